@@ -1,5 +1,5 @@
 use common::features;
 
-fn main() {
-    features::tunnel::main();
+fn main() -> tide::Result<()> {
+    features::tunnel::main()
 }
\ No newline at end of file
@@ -21,8 +21,13 @@ pub const CFG_TUNNEL_HOST: &str = "tunnel_host";
 pub const CFG_SHARE_KEY: &str = "share_key";
 pub const CFG_PASSWORD: &str = "password";
 pub const CFG_CLIENT_KEY: &str = "client_id";
-const SERVER_ALLOW_NAMES: [&str; 4] = [
+pub const CFG_SHARE_NAME: &str = "share_name";
+pub const CFG_READ_ONLY: &str = "read_only";
+pub const CFG_FILE_OPS: &str = "file_ops";
+pub const CFG_SYMLINKS: &str = "symlinks";
+const SERVER_ALLOW_NAMES: [&str; 8] = [
     CFG_PATH, CFG_TUNNEL_HOST, CFG_SHARE_KEY, CFG_PASSWORD,
+    CFG_SHARE_NAME, CFG_READ_ONLY, CFG_FILE_OPS, CFG_SYMLINKS,
 ];
 
 const CLIENT_ALLOW_NAMES: [&str; 5] = [
@@ -82,7 +87,7 @@ impl Config {
             eprintln!("warnning: config path error {}, error: {}", &config_path, err);
             fs::File::create(&config_path).expect("create config file failed");
         }
-        let conn = sqlite::open(&config_path.clone()).expect("config connectiont failed");
+        let conn = sqlite::open(&config_path).expect("config connectiont failed");
         Self { 
             work_dir: work_dir.to_owned(),
             config_path: config_path.to_owned(),
@@ -106,9 +111,7 @@ impl Config {
     }
     
     pub fn set(&mut self, key: String, value: String, auto_gen: Option<i64>) {
-        let auto_gen: i64 = if let Some(auto_gen) = auto_gen {
-            auto_gen
-        } else { 0 };
+        let auto_gen: i64 = auto_gen.unwrap_or_default();
         match self.config_dict.get_mut(&key) {
             Some(_v) => {
                 if *_v != value {
@@ -131,7 +134,7 @@ impl Config {
                 let query = "select value from config where name = ?";
                 let mut stat = self.conn.prepare(query).unwrap();
                 stat.bind((1, key.as_str())).unwrap();
-                while let Ok(State::Row) = stat.next() {
+                if let Ok(State::Row) = stat.next() {
                     let val = stat.read::<String, _>("value").unwrap();
                     self.config_dict.insert(key, val.clone());
                     return Some(val);
@@ -146,12 +149,12 @@ impl Config {
         let keys: Vec<String> = if let Some(keys) = keys {
             keys.iter()
                 .filter(|k|self.allowed_names.contains(k))
-                .map(|k| k.clone())
+                .cloned()
                 .collect()
         } else { 
             self.allowed_names.iter()
-                .filter(|k| **k != CFG_PASSWORD.to_string())
-                .map(|k| k.clone())
+                .filter(|k| *k != CFG_PASSWORD)
+                .cloned()
                 .collect()
         };
 
@@ -193,6 +196,15 @@ impl Config {
         map
     }
 
+    pub fn config_path(&self) -> &str {
+        &self.config_path
+    }
+
+    /// 配置所在的数据库连接, 同一数据库中的其他表共用该连接
+    pub fn connection(&self) -> &Connection {
+        &self.conn
+    }
+
     pub fn keys(&self) -> &Vec<String> {
         &self.allowed_names
     }
@@ -202,7 +214,7 @@ impl Config {
             let query = "select count(1) as name_count from config where name = ?";
             let mut stat = self.conn.prepare(query).unwrap();
             stat.bind((1, key.as_str())).unwrap();
-            while let Ok(State::Row) = stat.next() {
+            if let Ok(State::Row) = stat.next() {
                 let count = stat.read::<i64, _>("name_count").unwrap();
                 if count > 0 {
                     let update_query = format!("update config set value = '{}', auto_gen = {} where name = '{}'", value, _auto_gen, key);
@@ -211,7 +223,6 @@ impl Config {
                     let update_query = format!("insert into config (name, value, auto_gen) values ('{}', '{}', {});", key, value, _auto_gen);
                     self.conn.execute(update_query).unwrap();
                 }
-                break ;
             }
         }
     }
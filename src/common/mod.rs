@@ -8,7 +8,7 @@ const RANDOM_CHAR_POOL: &[u8] = b"0123456789\
                                 ABCDEFGHIJKLMNOPQRSTUVWXYZ\
                                 <>?,./;:[]{}~!@#$%^&*()_+-=";
 
-fn _gen_string(size: usize, pool: &Vec<u8>) -> String {
+fn _gen_string(size: usize, pool: &[u8]) -> String {
     let mut rng = rand::thread_rng();
 
     (0..size)
@@ -20,7 +20,7 @@ fn _gen_string(size: usize, pool: &Vec<u8>) -> String {
 }
 
 pub fn gen_password(size: usize) -> String {
-    _gen_string(size, &RANDOM_CHAR_POOL[..].to_vec())
+    _gen_string(size, RANDOM_CHAR_POOL)
 }
 
 pub fn gen_uuid() -> String {
@@ -35,14 +35,21 @@ pub fn gen_uuid() -> String {
     uuid_slices.join("-")
 }
 
+pub fn gen_request_id() -> u64 {
+    // note: 0 保留给 tunnel 自身产生的消息
+    rand::thread_rng().gen_range(1..u64::MAX)
+}
+
 pub type CommomResult<T> = Result<T, Box<dyn Error>>;
 
 pub mod config;
 pub mod utils;
+#[cfg(test)]
+pub mod test_util;
 
 #[cfg(test)]
 mod common_random_str {
-    use crate::common::gen_uuid;
+    use crate::common::{gen_request_id, gen_uuid};
 
     use super::gen_password;
 
@@ -57,6 +64,13 @@ mod common_random_str {
         });
     }
 
+    #[test]
+    fn test_gen_request_id() {
+        let ids: Vec<u64> = (0..100).map(|_| gen_request_id()).collect();
+        assert!(ids.iter().all(|id| *id != 0));
+        assert_ne!(ids[0], ids[1]);
+    }
+
     #[test]
     fn test_gen_uuid() {
         let uuid = gen_uuid();
@@ -0,0 +1,38 @@
+use std::{fs, ops::Deref, path::{Path, PathBuf}};
+
+/// 测试使用的临时目录, drop 时删除; 目录名包含进程 id, 同一进程中的测试使用不同的 name
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    pub fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("ft-{name}-{}", std::process::id()));
+        // note: 上次运行异常退出时可能残留
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        Self(path)
+    }
+
+    pub fn to_str(&self) -> &str {
+        self.0.to_str().unwrap()
+    }
+}
+
+impl Deref for TempDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl AsRef<Path> for TempDir {
+    fn as_ref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
@@ -1,4 +1,6 @@
-use std::{path::Path, time::{SystemTime, UNIX_EPOCH}};
+use std::{fs, io, path::Path, time::{SystemTime, UNIX_EPOCH}};
+
+use sha2::{Digest, Sha256};
 
 use crate::common::config;
 
@@ -7,14 +9,14 @@ pub struct PathUtil {
 }
 
 impl PathUtil {
-    pub fn new(prefix: &String) -> Self {
-        Self { prefix: prefix.clone() }
+    pub fn new(prefix: &str) -> Self {
+        Self { prefix: prefix.to_string() }
     }
 }
 
 impl PathUtil {
-    pub fn full_path(&self, path: &String) -> String {
-        let mut path = path.clone();
+    pub fn full_path(&self, path: &str) -> String {
+        let mut path = path.to_string();
         while path.contains("../") {
             path = path.replace("../", "");
         }
@@ -25,8 +27,8 @@ impl PathUtil {
         String::from(Path::new(&self.prefix).join(path.clone()).to_str().unwrap())
     }
 
-    pub fn mask_path(&self, path: &String) -> String {
-        (*path).replace(self.prefix.as_str(), "/")
+    pub fn mask_path(&self, path: &str) -> String {
+        path.replace(self.prefix.as_str(), "/")
     }
 }
 
@@ -39,6 +41,13 @@ pub fn config_dir() -> String {
     }
 }
 
+/// 文件内容的 sha256, hex 编码
+pub fn file_sha256<P: AsRef<Path>>(path: P) -> io::Result<String> {
+    let mut hasher = Sha256::new();
+    io::copy(&mut fs::File::open(path)?, &mut hasher)?;
+    Ok(hex::encode(hasher.finalize()))
+}
+
 pub fn now_secs() -> u64 {
     SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
 }
@@ -53,6 +62,9 @@ pub fn format_size(bytes: u64) -> String {
             break ;
         }
     }
+    if size.is_empty() {
+        size = format!("{}B", bytes);
+    }
 
     size
 }
@@ -1,51 +1,397 @@
-use std::io::Read as _;
+use once_cell::sync::OnceCell;
+use reqwest::{blocking::Response, header::CONTENT_TYPE};
+use std::{io::{self, Write}, sync::{atomic::{AtomicBool, Ordering}, Arc, Mutex}, time::Duration};
 use crate::{
-    common::{config::{self, Config}, CommomResult},
-    features::commands::{CommandMessage, ApiCommand}
+    common::{config::{self, Config}, gen_request_id, CommomResult},
+    features::{
+        codec::Codec,
+        commands::{ApiCommand, Command, CommandData, CommandMessage, ErrorCode, ShareInfo},
+        crypto::{self, KeyExchange, Role, Session},
+        encoding::{Encoding, SUPPORTED_ENCODINGS},
+        frame::{Frame, FrameType},
+        handshake::{Handshake, HandshakeResult},
+    }
 };
 
+/// 解压后 payload 的上限; Data frame 按 64KiB 拆分, Message 按批发送, 远小于该值
+const MAX_PAYLOAD_SIZE: usize = 16 << 20;
 
-pub fn do_http_request_data(cli_config: &mut Config, cmd: &ApiCommand) -> CommomResult<CommandMessage> {
-    let chars = do_http_request_raw(cli_config, cmd)?;
-    Ok(serde_json::from_slice(chars.as_slice())?)
+static PROTOCOL: OnceCell<Handshake> = OnceCell::new();
+/// server 重启或淘汰会话后重新建立, 因此不使用 OnceCell
+static SESSION: Mutex<Option<Arc<Session>>> = Mutex::new(None);
+static LOGIN: AtomicBool = AtomicBool::new(false);
+static ENCODINGS: OnceCell<Vec<Encoding>> = OnceCell::new();
+static SHARE_INFO: OnceCell<ShareInfo> = OnceCell::new();
+/// 进行中的请求, 提前退出时逐个取消
+static IN_FLIGHT: Mutex<Vec<(u64, TunnelKeys)>> = Mutex::new(Vec::new());
+
+/// 指定会话使用的编码, 例如调试时使用 json; 需在首次请求前调用
+pub fn prefer_encoding(encoding: Encoding) {
+    let _ = ENCODINGS.set(vec![encoding]);
 }
 
-pub fn do_http_request(cli_config: &mut Config, cmd: &ApiCommand) -> CommomResult<Vec<char>> {
-    let body = do_http_request_raw(cli_config, cmd)?;
-    let chars: Vec<char> = body.iter().map(|u| *u as char).collect();
-    Ok(chars)
+#[derive(Clone)]
+struct TunnelKeys {
+    share_key: String,
+    client_key: String,
+    tunnel_host: String,
 }
 
-pub fn do_http_request_raw(cli_config: &mut Config, cmd: &ApiCommand) -> CommomResult<Vec<u8>> {
+fn tunnel_keys(cli_config: &mut Config) -> TunnelKeys {
     let config_map = cli_config.get_keys_to_map(Some(vec![
         config::CFG_SHARE_KEY.to_string(),
         config::CFG_CLIENT_KEY.to_string(),
         config::CFG_TUNNEL_HOST.to_string(),
     ]));
-    let share_key = config_map.get(config::CFG_SHARE_KEY).unwrap();
-    let client_key = config_map.get(config::CFG_CLIENT_KEY).unwrap();
-    let tunnel_host = config_map.get(config::CFG_TUNNEL_HOST).unwrap();
+    TunnelKeys {
+        share_key: config_map.get(config::CFG_SHARE_KEY).unwrap().clone(),
+        client_key: config_map.get(config::CFG_CLIENT_KEY).unwrap().clone(),
+        tunnel_host: config_map.get(config::CFG_TUNNEL_HOST).unwrap().clone(),
+    }
+}
 
+pub fn handshake(cli_config: &mut Config) -> CommomResult<&'static Handshake> {
+    if let Some(protocol) = PROTOCOL.get() {
+        return Ok(protocol);
+    }
+    let keys = tunnel_keys(cli_config);
     let http_cli = reqwest::blocking::Client::new();
-    let body = serde_json::to_string(&cmd).unwrap();
-    // println!("req: {}", &body[..100]);
-    let url_endpoint = format!("http://{}/{}", tunnel_host, "tunnel/v1/client/data");
+    let url_endpoint = format!("http://{}/{}", keys.tunnel_host, "tunnel/v1/client/handshake");
+    let res = http_cli.post(url_endpoint)
+                .header("X-Server-Key", &keys.share_key)
+                .header("X-Client-Key", &keys.client_key)
+                .header("Content-Type", "application/json")
+                .body(serde_json::to_string(&Handshake::local())?)
+                .send()?;
+
+    match serde_json::from_slice::<HandshakeResult>(&res.bytes()?)? {
+        HandshakeResult::Accepted { version, features } => {
+            Ok(PROTOCOL.get_or_init(|| Handshake {
+                min_version: version,
+                max_version: version,
+                features,
+            }))
+        },
+        HandshakeResult::Rejected { message } => Err(format!("handshake failed, {message}").into()),
+    }
+}
+
+/// 通知 tunnel 取消请求, tunnel 释放对应的 channel 并通知 server 停止处理
+fn cancel(keys: &TunnelKeys, request_id: u64) -> CommomResult<()> {
+    let http_cli = reqwest::blocking::Client::builder().timeout(Duration::from_secs(5)).build()?;
+    let url_endpoint = format!("http://{}/{}", keys.tunnel_host, "tunnel/v1/client/cancel");
+    http_cli.post(url_endpoint)
+        .header("X-Server-Key", &keys.share_key)
+        .header("X-Client-Key", &keys.client_key)
+        .header("X-Request-Id", request_id.to_string())
+        .send()?;
+    Ok(())
+}
+
+/// 取消所有进行中的请求并退出, 用于 Ctrl-C 等提前退出的场景;
+/// 持有锁直到退出, 丢弃响应的线程会在此等待, 不会继续处理已取消的请求
+pub fn cancel_in_flight_and_exit(code: i32) -> ! {
+    let in_flight = IN_FLIGHT.lock().unwrap_or_else(|e| e.into_inner());
+    for (request_id, keys) in in_flight.iter() {
+        if let Err(e) = cancel(keys, *request_id) {
+            eprintln!("cancel request {request_id} failed, err: {e}");
+        }
+    }
+    std::process::exit(code)
+}
+
+pub fn do_http_request_data(cli_config: &mut Config, cmd: &ApiCommand) -> CommomResult<CommandMessage> {
+    let mut stream = do_http_request_stream(cli_config, cmd)?;
+    let message = stream.message()?;
+    stream.copy_data(&mut io::sink())?;
+    Ok(message)
+}
+
+pub fn do_http_request_raw(cli_config: &mut Config, cmd: &ApiCommand) -> CommomResult<(CommandMessage, Vec<u8>)> {
+    let mut stream = do_http_request_stream(cli_config, cmd)?;
+    let message = stream.message()?;
+    let mut data: Vec<u8> = vec![];
+    stream.copy_data(&mut data)?;
+    Ok((message, data))
+}
+
+/// 读取 server 的共享信息; with_stats 为 false 时首次读取后缓存, 用于判断 server 支持的功能
+pub fn share_info(cli_config: &mut Config, with_stats: bool) -> CommomResult<ShareInfo> {
+    if let (Some(share), false) = (SHARE_INFO.get(), with_stats) {
+        return Ok(share.clone());
+    }
+    let message = do_http_request_data(cli_config, &ApiCommand::new(Command::ReadConfig { with_stats }))?;
+    match message.data {
+        CommandData::ReadConfig { share } => {
+            let _ = SHARE_INFO.set(share.clone());
+            Ok(share)
+        },
+        CommandData::Error { message, .. } => Err(format!("read server config failed, {message}").into()),
+        data => Err(format!("read server config failed, unexpected message {data:?}").into()),
+    }
+}
+
+/// 使用共享密码与 server 建立端到端加密会话, tunnel 只能看到密文;
+/// client 先发送确认值, server 校验通过后才回复自己的确认值
+pub fn session(cli_config: &mut Config) -> CommomResult<Arc<Session>> {
+    if let Some(session) = SESSION.lock().unwrap().as_ref() {
+        return Ok(session.clone());
+    }
+    let password = cli_config.get_key(config::CFG_PASSWORD.to_string())
+        .ok_or("password required, set it by set-local-config")?;
+    let key_exchange = KeyExchange::new(&password);
+    let cmd = ApiCommand::new(Command::OpenSession {
+        public_key: key_exchange.public_key(),
+        encodings: ENCODINGS.get().cloned().unwrap_or(SUPPORTED_ENCODINGS.to_vec()),
+    });
+    let (session_id, session) = match plain_message(cli_config, &cmd)?.data {
+        CommandData::OpenSession { session_id, public_key, encoding } => {
+            let session = key_exchange.establish(Role::Client, &public_key, &session_id)?
+                .with_encoding(encoding);
+            (session_id, session)
+        },
+        CommandData::Error { message, .. } => return Err(format!("open session failed, {message}").into()),
+        data => return Err(format!("open session failed, unexpected message {data:?}").into()),
+    };
+    let cmd = ApiCommand::new(Command::ConfirmSession { session_id, proof: session.proof(Role::Client) });
+    match plain_message(cli_config, &cmd)?.data {
+        CommandData::ConfirmSession { proof } if session.verify_proof(Role::Server, &proof) => {
+            let session = Arc::new(session);
+            *SESSION.lock().unwrap() = Some(session.clone());
+            Ok(session)
+        },
+        CommandData::ConfirmSession { .. } => Err("open session failed, password mismatch".into()),
+        CommandData::Error { message, .. } => Err(format!("open session failed, {message}").into()),
+        data => Err(format!("open session failed, unexpected message {data:?}").into()),
+    }
+}
+
+fn plain_message(cli_config: &mut Config, cmd: &ApiCommand) -> CommomResult<CommandMessage> {
+    let mut stream = send_request(cli_config, cmd, None)?;
+    let message = stream.message()?;
+    stream.copy_data(&mut io::sink())?;
+    Ok(message)
+}
+
+/// 响应由多个 frame 组成: Message frame 为 `CommandMessage`, Data frame 为原始数据
+pub fn do_http_request_stream(cli_config: &mut Config, cmd: &ApiCommand) -> CommomResult<ResponseStream> {
+    let mut stream = send_session_request(cli_config, cmd)?;
+    if !stream.unauthorized()? {
+        return Ok(stream);
+    }
+    // note: server 重启或淘汰了会话, 丢弃缓存的会话与登录结果, 重新建立后以新的 request_id 重发一次
+    drop(stream);
+    *SESSION.lock().unwrap() = None;
+    LOGIN.store(false, Ordering::Relaxed);
+    send_session_request(cli_config, &ApiCommand { request_id: gen_request_id(), ..cmd.clone() })
+}
+
+fn send_session_request(cli_config: &mut Config, cmd: &ApiCommand) -> CommomResult<ResponseStream> {
+    let session = session(cli_config)?;
+    login(cli_config, &session)?;
+    send_request(cli_config, cmd, Some(session))
+}
+
+fn request_message(cli_config: &mut Config, cmd: &ApiCommand, session: &Arc<Session>) -> CommomResult<CommandMessage> {
+    let mut stream = send_request(cli_config, cmd, Some(session.clone()))?;
+    let message = stream.message()?;
+    stream.copy_data(&mut io::sink())?;
+    Ok(message)
+}
+
+/// 挑战应答登录, server 记录 client_key 的登录结果, 未登录的 client 无法执行其他命令
+fn login(cli_config: &mut Config, session: &Arc<Session>) -> CommomResult<()> {
+    if LOGIN.load(Ordering::Relaxed) {
+        return Ok(());
+    }
+    let password = cli_config.get_key(config::CFG_PASSWORD.to_string())
+        .ok_or("password required, set it by set-local-config")?;
+    let client_key = tunnel_keys(cli_config).client_key;
+    let challenge = match request_message(cli_config, &ApiCommand::new(Command::AuthChallenge {}), session)?.data {
+        CommandData::AuthChallenge { challenge } => challenge,
+        CommandData::Error { message, .. } => return Err(format!("login failed, {message}").into()),
+        data => return Err(format!("login failed, unexpected message {data:?}").into()),
+    };
+    let response = crypto::auth_response(&password, &challenge, &client_key);
+    let cmd = ApiCommand::new(Command::AuthResponse { challenge, response });
+    match request_message(cli_config, &cmd, session)?.data {
+        CommandData::AuthResponse { authenticated: true } => {
+            LOGIN.store(true, Ordering::Relaxed);
+            Ok(())
+        },
+        CommandData::AuthResponse { authenticated: false } => Err("login failed, password mismatch".into()),
+        CommandData::Error { message, .. } => Err(format!("login failed, {message}").into()),
+        data => Err(format!("login failed, unexpected message {data:?}").into()),
+    }
+}
+
+fn send_request(cli_config: &mut Config, cmd: &ApiCommand, session: Option<Arc<Session>>) -> CommomResult<ResponseStream> {
+    let version = handshake(cli_config)?.version();
+    let keys = tunnel_keys(cli_config);
+
+    let http_cli = reqwest::blocking::Client::builder().timeout(None).build()?;
+    let mut cmd = cmd.clone();
+    cmd.version = version;
+    let encoding = session.as_ref().map(|session| session.encoding()).unwrap_or_default();
+    let mut body = encoding.encode(&cmd)?;
+    if let Some(session) = &session {
+        // note: tunnel 以 flags = 0 的 Command frame 转发请求体
+        body = session.seal(&crypto::aad(cmd.request_id, FrameType::Command, 0), &body)?;
+    }
+    let url_endpoint = format!("http://{}/{}", keys.tunnel_host, "tunnel/v1/client/data");
     
-    let mut res = http_cli.post(url_endpoint.clone())
-                .header("X-Server-Key", share_key)
-                .header("X-Client-Key", client_key)
+    let res = http_cli.post(url_endpoint.clone())
+                .header("X-Server-Key", &keys.share_key)
+                .header("X-Client-Key", &keys.client_key)
+                .header("X-Request-Id", cmd.request_id.to_string())
+                .header("X-Protocol-Version", version.to_string())
                 .body(body).send()?;
-    
-    let mut body: Vec<u8> = vec![];
-    loop {
-        let mut buffer: Vec<u8> = vec![0u8; 32];
-        let _usize = res.read(&mut  buffer)?;
-        if _usize == 0 {
-            break ;
-        }
-        
-        body.extend(buffer[.._usize].iter());
-    }
-    // println!("res: {}", &String::from_utf8(body.clone()).unwrap()[..100]);
-    Ok(body)
-}
\ No newline at end of file
+
+    let is_frame = res.headers().get(CONTENT_TYPE)
+        .is_some_and(|content_type| content_type.as_bytes().starts_with(b"application/octet-stream"));
+    if !is_frame {
+        return Err(format!("request failed, status: {}, body: {}", res.status(), res.text()?).into());
+    }
+    IN_FLIGHT.lock().unwrap().push((cmd.request_id, keys.clone()));
+    Ok(ResponseStream { res, request_id: cmd.request_id, keys, session, last_counter: 0, pending: None, expired: false, finished: false })
+}
+
+/// 按 frame 逐个读取响应, 不缓存整个响应体
+pub struct ResponseStream {
+    res: Response,
+    request_id: u64,
+    keys: TunnelKeys,
+    session: Option<Arc<Session>>,
+    last_counter: u64,
+    /// 已读取但还未返回给调用方的 frame
+    pending: Option<Frame>,
+    /// server 以明文错误回复会话过期
+    expired: bool,
+    finished: bool,
+}
+
+impl ResponseStream {
+    pub fn next_frame(&mut self) -> CommomResult<Option<Frame>> {
+        if let Some(frame) = self.pending.take() {
+            return Ok(Some(frame));
+        }
+        if self.finished {
+            return Ok(None);
+        }
+        let frame = match Frame::read_from(&mut self.res)? {
+            Some(frame) => frame,
+            None => {
+                self.finished = true;
+                return Err("response closed before end of stream".into());
+            }
+        };
+        if frame.request_id != self.request_id {
+            self.finished = true;
+            return Err(format!(
+                "response mismatch, request id: {}, response id: {}",
+                self.request_id, frame.request_id
+            ).into());
+        }
+        self.finished = frame.end_of_stream;
+        let frame = self.open(frame)?;
+        if frame.codec != Codec::Identity {
+            let payload = frame.codec.decompress_limited(&frame.payload, MAX_PAYLOAD_SIZE)
+                .map_err(|e| format!("{} decompress failed, {}", frame.codec, e))?;
+            return Ok(Some(Frame { payload, codec: Codec::Identity, ..frame }));
+        }
+        Ok(Some(frame))
+    }
+
+    fn encoding(&self) -> Encoding {
+        self.session.as_ref().map(|session| session.encoding()).unwrap_or_default()
+    }
+
+    /// 响应以 Unauthorized 错误开始时返回 true(明文的会话过期或加密的未登录), 否则读取的 frame 留给之后返回
+    fn unauthorized(&mut self) -> CommomResult<bool> {
+        let frame = match self.next_frame() {
+            Ok(Some(frame)) => frame,
+            Ok(None) => return Ok(false),
+            Err(_) if self.expired => return Ok(true),
+            Err(e) => return Err(e),
+        };
+        if frame.frame_type == FrameType::Message && !frame.payload.is_empty() {
+            let message = self.encoding().decode::<CommandMessage>(&frame.payload)?;
+            if let CommandData::Error { code: ErrorCode::Unauthorized, .. } = message.data {
+                return Ok(true);
+            }
+        }
+        self.pending = Some(frame);
+        Ok(false)
+    }
+
+    /// 解密会话中的 frame; 未加密的 frame 无法确认来源(可能由 tunnel 产生), 按错误结束本次请求, 但保留会话;
+    /// 只有 server 回复的会话过期(`Unauthorized`)会触发重新建立会话
+    fn open(&mut self, frame: Frame) -> CommomResult<Frame> {
+        let session = match &self.session {
+            Some(session) => session,
+            None => return Ok(frame),
+        };
+        if crypto::is_sealed(&frame.payload) {
+            let aad = crypto::aad(frame.request_id, frame.frame_type, frame.flags());
+            let payload = session.open_stream(&aad, &frame.payload, &mut self.last_counter)?;
+            return Ok(Frame { payload, ..frame });
+        }
+        let plain_message = frame.frame_type == FrameType::Message && frame.codec == Codec::Identity;
+        match serde_json::from_slice::<CommandMessage>(&frame.payload) {
+            Ok(CommandMessage { data: CommandData::Error { code, message }, .. }) if plain_message => {
+                self.expired = code == ErrorCode::Unauthorized;
+                Err(format!("unsealed frame in encrypted session, unverified error: {message} ({code})").into())
+            },
+            _ => Err("unsealed frame in encrypted session".into()),
+        }
+    }
+
+    /// 读取下一个 `CommandMessage`, 跳过期间的 Data frame
+    pub fn message(&mut self) -> CommomResult<CommandMessage> {
+        self.next_message()?.ok_or_else(|| "response message missing".into())
+    }
+
+    /// 流式响应包含多个 `CommandMessage`, 响应结束时返回 None
+    pub fn next_message(&mut self) -> CommomResult<Option<CommandMessage>> {
+        while let Some(frame) = self.next_frame()? {
+            if frame.frame_type == FrameType::Message && !frame.payload.is_empty() {
+                return Ok(Some(self.encoding().decode(&frame.payload)?));
+            }
+        }
+        Ok(None)
+    }
+
+    /// 将 Data frame 写入 writer 直到响应结束, 返回写入的字节数;
+    /// server 在发送数据期间出错时以 `CommandData::Error` 结束响应, 此时返回错误
+    pub fn copy_data<W: Write>(&mut self, writer: &mut W) -> CommomResult<usize> {
+        let mut size = 0;
+        while let Some(frame) = self.next_frame()? {
+            if frame.frame_type == FrameType::Data {
+                writer.write_all(&frame.payload)?;
+                size += frame.payload.len();
+            } else if !frame.payload.is_empty() {
+                if let CommandData::Error { code, message } = self.encoding().decode::<CommandMessage>(&frame.payload)?.data {
+                    return Err(format!("{message} ({code})").into());
+                }
+            }
+        }
+        Ok(size)
+    }
+}
+
+/// 未读完就丢弃的响应视为 client 放弃了请求
+impl Drop for ResponseStream {
+    fn drop(&mut self) {
+        let tracked = {
+            let mut in_flight = IN_FLIGHT.lock().unwrap();
+            let size = in_flight.len();
+            in_flight.retain(|(request_id, _)| *request_id != self.request_id);
+            in_flight.len() != size
+        };
+        if tracked && !self.finished {
+            if let Err(e) = cancel(&self.keys, self.request_id) {
+                eprintln!("cancel request {} failed, err: {e}", self.request_id);
+            }
+        }
+    }
+}
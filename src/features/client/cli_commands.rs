@@ -1,8 +1,14 @@
 use clap::{Parser, Subcommand};
 
+use crate::features::{commands::{ItemType, SortKey}, encoding::Encoding};
+
 #[derive(Parser, Debug)]
 #[command(author, version, about)]
 pub struct Cli {
+    /// 与 server 通信使用的编码, 默认由 server 选择(msgpack 优先), 调试时可指定 json
+    #[arg(long, global = true, env = "FT_ENCODING")]
+    pub encoding: Option<Encoding>,
+
     #[command(subcommand)]
     pub command: Option<Command>
 }
@@ -50,12 +56,50 @@ pub enum Command {
 
         #[arg(long, default_value_t=false)]
         download: bool,
+
+        /// 排序方式, 在 server 上排序后分页
+        #[arg(long, default_value="name")]
+        sort: SortKey,
+
+        /// 降序
+        #[arg(long, default_value_t=false)]
+        desc: bool,
+
+        /// 只列出这些扩展名的文件, 多个以 `,` 分隔, 例如 `--ext log,txt`
+        #[arg(long, value_delimiter=',')]
+        ext: Vec<String>,
+
+        #[arg(long = "type")]
+        item_type: Option<ItemType>,
+
+        /// 名称 glob, 例如 `*.log`
+        #[arg(long)]
+        name: Option<String>,
+
+        /// 不列出 `.` 开头的隐藏条目
+        #[arg(long, default_value_t=false)]
+        skip_hidden: bool,
+
+        /// 上一页输出的 `cursor`, 读取下一页; 排序与过滤沿用首页
+        #[arg(long)]
+        cursor: Option<String>,
+
+        /// 读取全部页
+        #[arg(long, default_value_t=false)]
+        all: bool,
+
+        /// 同时读取扩展属性, 下载时恢复其中的 user.* 属性
+        #[arg(long, default_value_t=false)]
+        xattrs: bool,
     },
 
     // 5. 获取 文件 信息
     ReadFileInfo {
         #[arg(long)]
         file_path: String,
+
+        #[arg(long, default_value_t=false)]
+        xattrs: bool,
     },
 
     // 6. 下载文件
@@ -68,5 +112,176 @@ pub enum Command {
 
         #[arg(long, default_value_t=0)]
         block_idx: usize,
+    },
+
+    // 7. 计算文件 sha256, server 会缓存结果
+    Checksum {
+        #[arg(long)]
+        file_path: String,
+    },
+
+    // 8. 上传文件, 需要 server 允许写入
+    Upload {
+        #[arg(long)]
+        local_path: String,
+
+        /// 共享目录中的路径, 以 `/` 结尾时使用本地文件名
+        #[arg(long)]
+        remote_path: String,
+
+        #[arg(long, default_value_t=false)]
+        overwrite: bool,
+    },
+
+    // 9. 创建目录
+    Mkdir {
+        #[arg(long)]
+        dir_path: String,
+
+        /// 同时创建不存在的上级目录
+        #[arg(long, default_value_t=false)]
+        parents: bool,
+    },
+
+    // 10. 重命名, to 为完整的目标路径
+    Rename {
+        #[arg(long)]
+        from: String,
+
+        #[arg(long)]
+        to: String,
+
+        #[arg(long, default_value_t=false)]
+        overwrite: bool,
+    },
+
+    // 11. 移动到目录下, 保持原名称
+    Move {
+        #[arg(long)]
+        from: String,
+
+        #[arg(long)]
+        to_dir: String,
+
+        #[arg(long, default_value_t=false)]
+        overwrite: bool,
+    },
+
+    // 12. 在 server 上复制文件或目录
+    Copy {
+        #[arg(long)]
+        from: String,
+
+        #[arg(long)]
+        to: String,
+
+        #[arg(long, default_value_t=false)]
+        overwrite: bool,
+    },
+
+    // 13. 删除文件或目录
+    Delete {
+        #[arg(long)]
+        path: String,
+
+        /// 移动到共享目录的回收站而不是直接删除
+        #[arg(long, default_value_t=false)]
+        trash: bool,
+    },
+
+    // 14. 读取目录树
+    Tree {
+        #[arg(long, default_value="/")]
+        path: String,
+
+        /// 最多读取的层数, 默认读取整个子树
+        #[arg(long)]
+        depth: Option<usize>,
+
+        /// 0 使用 server 的默认上限
+        #[arg(long, default_value_t=0)]
+        max_items: usize,
+    },
+
+    // 15. 按名称查找文件
+    Find {
+        /// 查找的目录
+        #[arg(long, default_value="/")]
+        path: String,
+
+        /// glob, 例如 `*.log`; 包含 `/` 时匹配相对路径
+        #[arg(long)]
+        name: String,
+
+        /// name 为正则表达式, 匹配相对路径
+        #[arg(long, default_value_t=false)]
+        regex: bool,
+
+        #[arg(long = "type")]
+        item_type: Option<ItemType>,
+
+        /// 最小文件大小(字节)
+        #[arg(long)]
+        min_size: Option<u64>,
+
+        /// 最大文件大小(字节)
+        #[arg(long)]
+        max_size: Option<u64>,
+
+        /// 修改时间晚于该 unix 时间戳(秒)
+        #[arg(long)]
+        modified_after: Option<u64>,
+
+        /// 修改时间早于该 unix 时间戳(秒)
+        #[arg(long)]
+        modified_before: Option<u64>,
+
+        /// 0 使用 server 的默认上限
+        #[arg(long, default_value_t=0)]
+        max_results: usize,
+    },
+
+    // 16. 查找文件内容
+    Grep {
+        /// 查找的文件或目录
+        #[arg(long, default_value="/")]
+        path: String,
+
+        /// 默认按字面查找
+        #[arg(long)]
+        pattern: String,
+
+        /// pattern 为正则表达式
+        #[arg(long, default_value_t=false)]
+        regex: bool,
+
+        #[arg(long, short = 'i', default_value_t=false)]
+        ignore_case: bool,
+
+        /// 匹配行前后各输出的行数
+        #[arg(long, short = 'C', default_value_t=0)]
+        context: usize,
+
+        /// 只查找名称匹配该 glob 的文件, 例如 `*.log`
+        #[arg(long)]
+        include: Option<String>,
+
+        /// 跳过大于该字节数的文件, 0 使用 server 的默认值
+        #[arg(long, default_value_t=0)]
+        max_file_size: u64,
+
+        /// 0 使用 server 的默认上限
+        #[arg(long, default_value_t=0)]
+        max_matches: usize,
+    },
+
+    // 17. 持续输出文件与目录的变化, Ctrl-C 停止
+    Watch {
+        #[arg(long, default_value="/")]
+        path: String,
+
+        /// 包含所有子目录中的变化, 默认只包含直接子条目
+        #[arg(long, default_value_t=false)]
+        recursive: bool,
     }
 }
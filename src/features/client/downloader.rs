@@ -1,125 +1,330 @@
-use sha256::try_digest;
-use std::{fs, io::{Seek, SeekFrom, Write}, path::PathBuf};
+use std::{collections::VecDeque, fs, io::{Seek, SeekFrom}, os::unix::fs::{symlink, PermissionsExt as _}, path::{Component, Path, PathBuf}, time::{Duration, UNIX_EPOCH}};
 
 use crate::{
     common::{config::{Config, CFG_PATH}, utils, CommomResult},
-    features::commands::{Command, CommandData, DirItem, DirItemInfo, FtPath, ApiCommand}
+    features::{commands::{Command, CommandData, DirItem, DirItemInfo, DirSort, FtPath, ApiCommand, ItemFilter, SymlinkPolicy}, xattr}
 };
 
 use super::api;
 
+/// 每次请求下载的块大小, 块越大请求往返越少, 下载速度越接近链路带宽
+const DOWNLOAD_BLOCK_SIZE: u64 = 4 << 20;
+/// 同时请求的块数, 写入当前块时后续的块已在传输, 隐藏请求往返的延迟
+const DOWNLOAD_PIPELINE_DEPTH: usize = 4;
 
-pub fn download(cli_config: &mut Config, path: PathBuf, take_size: usize, skip_size: usize, download: bool) -> CommomResult<()> {
+/// 列目录的分页与过滤参数
+pub struct ListOptions {
+    pub take_size: usize,
+    pub skip_size: usize,
+    pub sort: DirSort,
+    pub filter: ItemFilter,
+    /// 上一页返回的游标, 此时忽略 skip_size 与过滤条件
+    pub cursor: Option<String>,
+    /// 按游标读取后续所有页
+    pub all: bool,
+    /// 同时读取扩展属性, 下载时恢复
+    pub with_xattrs: bool,
+}
+
+pub fn download(cli_config: &mut Config, path: PathBuf, options: ListOptions, download: bool) -> CommomResult<()> {
     let root_path = cli_config.get_key(CFG_PATH.to_string()).unwrap();
-    let cmd = ApiCommand {
-        version: 1,
-        command: Command::ReadPathInfo {
-            path: FtPath::new_absolute(root_path, path.to_str().unwrap().to_string()),
-            take_size, skip_size,
-        }
-    };
-    let message = api::do_http_request_data(cli_config, &cmd)?;
-    match message.data {
-        CommandData::ReadDirItem { items, total, taked_size } => {
-            let _: Vec<_> = items.iter().map(|dir| {
-                let stat = if dir.path.exists() { "L" } else { "R" };
-                let mut item_type = "";
-                let mut size = "".to_string();
-                match &dir.info {
-                    DirItemInfo::File { modified_at, created_at, file_size, chksum} => {
-                        item_type = "-";
-                        size = utils::format_size(*file_size);
-                    },
-                    DirItemInfo::Dir { modified_at, created_at, item_count } => {
-                        item_type = "d";
-                        size = format!("{}", item_count);
+    let path = FtPath::new_absolute(root_path, path.to_str().unwrap().to_string());
+    // note: 下载的条目会改变所在目录的修改时间, 全部完成后恢复为下载前的值(该目录下载时已恢复为 server 上的时间)
+    let local_dir = PathBuf::from(path.full_path());
+    let local_dir = if local_dir.is_dir() { local_dir } else { local_dir.parent().map(Path::to_path_buf).unwrap_or_default() };
+    let local_dir_modified = fs::metadata(&local_dir).and_then(|meta| meta.modified()).ok();
+    // note: 新建目录的元数据在其中的条目之后恢复, 先设置的修改时间会因写入条目而改变
+    let mut created_dirs = vec![];
+    let mut cursor = options.cursor.clone();
+    loop {
+        let cmd = ApiCommand::new(Command::ReadPathInfo {
+            path: path.clone(),
+            take_size: options.take_size,
+            skip_size: options.skip_size,
+            sort: options.sort,
+            filter: options.filter.clone(),
+            cursor: cursor.take(),
+            with_xattrs: options.with_xattrs,
+        });
+        let message = api::do_http_request_data(cli_config, &cmd)?;
+        match message.data {
+            CommandData::ReadDirItem { items, total, taked_size, cursor: next } => {
+                let _: Vec<_> = items.iter().map(|dir| {
+                    let stat = if dir.path.exists() { "L" } else { "R" };
+                    let (size, link) = match &dir.info {
+                        DirItemInfo::File { file_size, .. } => (utils::format_size(*file_size), String::new()),
+                        DirItemInfo::Dir { item_count, .. } => (format!("{}", item_count), String::new()),
+                        DirItemInfo::Symlink { target, .. } => ("0".to_string(), format!(" -> {target}")),
+                        DirItemInfo::Other { .. } => ("0".to_string(), String::new()),
+                    };
+                    if download && !dir.path.exists() {
+                        match downloader(cli_config, dir) {
+                            Ok(()) if matches!(dir.info, DirItemInfo::Dir { .. }) => created_dirs.push(dir.clone()),
+                            Ok(()) => {},
+                            Err(e) => eprintln!("download {} failed, {}", dir.path.path(), e),
+                        }
                     }
+                    println!("{}--------- {} {}{} {}", dir.info.type_flag(), size, dir.path.full_path(), link, stat);
+                }).collect();
+                println!("info: {}/{}", taked_size, total);
+                match next {
+                    Some(next) if options.all => cursor = Some(next),
+                    Some(next) => println!("cursor: {next}"),
+                    None => {},
                 }
-                if download && !dir.path.exists() {
-                    downloader(cli_config, dir).unwrap();
-                }
-                println!("{}--------- {} {} {}", item_type, size, dir.path.full_path(), stat);
-            }).collect();
-            println!("info: {}/{}", taked_size, total);
-        },
-        CommandData::ReadFileInfo { item } => {
-            downloader(cli_config, &item)?
-        },
-        _data => eprintln!("response error")
+            },
+            CommandData::ReadFileInfo { item } => {
+                downloader(cli_config, &item)?
+            },
+            CommandData::Error { code, message } => return Err(format!("{message} ({code})").into()),
+            data => return Err(format!("unexpected message {data:?}").into()),
+        }
+        if cursor.is_none() {
+            break ;
+        }
+    }
+    for dir in created_dirs.iter().rev() {
+        if let Err(e) = restore_meta(Path::new(&dir.path.full_path()), &dir.info) {
+            eprintln!("restore meta of {} failed, {e}", dir.path.path());
+        }
+    }
+    if let Some(modified) = local_dir_modified {
+        if let Err(e) = fs::File::open(&local_dir).and_then(|dir| dir.set_modified(modified)) {
+            eprintln!("restore modified time of {} failed, {e}", local_dir.display());
+        }
     }
     Ok(())
 }
 
+/// 请求 server 计算文件 sha256, 返回 (chksum, cached)
+pub fn checksum(cli_config: &mut Config, path: &FtPath) -> CommomResult<(String, bool)> {
+    if !api::share_info(cli_config, false)?.supports("Checksum") {
+        return Err("server does not support checksum".into());
+    }
+    let cmd = ApiCommand::new(Command::Checksum { file_path: path.clone() });
+    let mut stream = api::do_http_request_stream(cli_config, &cmd)?;
+    while let Some(message) = stream.next_message()? {
+        match message.data {
+            CommandData::ChecksumProgress { hashed_size, file_size } => {
+                print!("{:<50}: hashing [{}/{}]\r", path.path(), utils::format_size(hashed_size), utils::format_size(file_size));
+            },
+            CommandData::Checksum { chksum, cached } => return Ok((chksum, cached)),
+            CommandData::Error { code, message } => return Err(format!("{message} ({code})").into()),
+            data => return Err(format!("unexpected message {data:?}").into()),
+        }
+    }
+    Err("response message missing".into())
+}
+
 fn downloader(cli_config: &mut Config, item: &DirItem) -> CommomResult<()> {
+    if !api::share_info(cli_config, false)?.supports("DownloadFile") {
+        return Err("server does not support downloading files".into());
+    }
     match &item.info {
-        DirItemInfo::File { modified_at, created_at, file_size, chksum } => {
-            let block_size = 1 << 16;
-            let mut block_count = file_size / block_size;
-            if block_count * block_size < *file_size {
-                block_count += 1;
-            }
-            let mut file = if !item.path().exists() {
-                fs::File::create(item.path().full_path())?
-            } else {
-                fs::File::open(item.path().full_path())?
-            };
-            file.rewind()?;
-
-            let mut downloaded_size: u64 = 0;
-            let mut block_idx = 0;
+        DirItemInfo::File { file_size, chksum, .. } => {
+            // note: 列目录时不返回校验和, 下载前单独请求
+            let mut file = fs::OpenOptions::new()
+                .create(true)
+                .truncate(false)
+                .write(true)
+                .open(item.path().full_path())?;
+            let mut chksum = chksum.clone();
             let max_err_retry_times = 3;
             let mut err_times = max_err_retry_times;
-            while block_idx < block_count {
-                let cmd = ApiCommand {
-                    version: 1,
-                    command: Command::DownloadFile {
-                        file_path: item.path.clone(),
-                        block_idx: block_idx as usize,
-                        block_size: block_size as usize,
-                    }
+            loop {
+                download_blocks(cli_config, item, &mut file, *file_size)?;
+                // note: 每个文件下载完成后都校验一次; 列目录时不返回校验和, 此时才单独请求, 重试时不再请求
+                let expect = match &chksum {
+                    Some(chksum) => chksum.clone(),
+                    None => chksum.insert(checksum(cli_config, &item.path)?.0).clone(),
                 };
-                let message = api::do_http_request_data(cli_config, &cmd)?;
-
-                match message.data {
-                    CommandData::DownloadFile { data, data_size } => {
-                        if data_size > 0 {
-                            file.seek(SeekFrom::Start(downloaded_size))?;
-                            let wsize = file.write(&data)?;
-                            if wsize != data_size {
-                                panic!("write file error");
-                            }
-                            downloaded_size += data_size as u64;
-                            block_idx += 1;
-                            let percent: f64 = (downloaded_size as f64 / *file_size as f64) * (100 as f64);
-                            let downloaded_size_er = utils::format_size(downloaded_size);
-                            let total_size_er = utils::format_size(*file_size);
-                            print!("{:<50}: [{}/{},{:>6}]\r", item.path().full_path(), downloaded_size_er, total_size_er, format!("{:.2}%", percent));
-                        }
-                        if data_size < block_size as usize {
-                            let local_chksum = try_digest(item.path().full_path())?;
-                            if local_chksum == *chksum {
-                                break ;
-                            } else {
-                                err_times -= 0;
-                                if err_times > 0 {
-                                    eprintln!("sha256sum valid faild, and will retry it {:?} <=>{:?}", local_chksum, &chksum);
-                                    block_idx = 0;
-                                    downloaded_size = 0;
-                                } else {
-                                    panic!("sha256sum valid faild too many times");
-                                }
-                            }
-                        }
-                    },
-                    _ => panic!("unexpected message")
+                let local_chksum = utils::file_sha256(item.path().full_path())?;
+                if local_chksum.eq_ignore_ascii_case(&expect) {
+                    break ;
                 }
+                err_times -= 1;
+                if err_times == 0 {
+                    return Err("sha256sum valid faild too many times".into());
+                }
+                eprintln!("sha256sum valid faild, and will retry it {:?} <=>{:?}", local_chksum, &expect);
             }
-            
-            Ok(())
+            drop(file);
+            restore_meta(Path::new(&item.path.full_path()), &item.info)
         },
-        DirItemInfo::Dir { modified_at, created_at, item_count } => {
+        DirItemInfo::Dir { .. } => {
+            // note: 元数据由调用方在写入其中的条目之后恢复
             fs::create_dir(item.path.full_path())?;
             Ok(())
+        },
+        DirItemInfo::Symlink { target, .. } => {
+            // note: 跟随链接的共享只在目标不存在时返回链接, 不重建
+            if api::share_info(cli_config, false)?.symlinks != SymlinkPolicy::Link {
+                return Err("symlink target not found on server".into());
+            }
+            if !link_stays_inside(&item.path.path(), target) {
+                return Err(format!("symlink target {target} is outside of the save path, skipped").into());
+            }
+            symlink(target, item.path.full_path())?;
+            Ok(())
+        },
+        DirItemInfo::Other { file_type, .. } => Err(format!("{file_type} can not be downloaded").into()),
+    }
+}
+
+/// 按块下载整个文件, 读到不足一块时视为文件结束, 并截断本地文件中多余的数据
+fn download_blocks(cli_config: &mut Config, item: &DirItem, file: &mut fs::File, file_size: u64) -> CommomResult<()> {
+    let block_size = DOWNLOAD_BLOCK_SIZE;
+    let block_count = file_size.div_ceil(block_size);
+    let mut downloaded_size: u64 = 0;
+    let mut in_flight = VecDeque::new();
+    let mut next_block_idx = 0;
+    for block_idx in 0..block_count {
+        // note: 按顺序发出后续块的请求, 再按顺序读取响应, 文件仍然顺序写入
+        while next_block_idx < block_count && in_flight.len() < DOWNLOAD_PIPELINE_DEPTH {
+            let cmd = ApiCommand::new(Command::DownloadFile {
+                file_path: item.path.clone(),
+                block_idx: next_block_idx as usize,
+                block_size: block_size as usize,
+            });
+            in_flight.push_back(api::do_http_request_stream(cli_config, &cmd)?);
+            next_block_idx += 1;
+        }
+        let mut stream = in_flight.pop_front().unwrap();
+        let data_size = match stream.message()?.data {
+            CommandData::DownloadFile { data_size } => data_size,
+            CommandData::Error { code, message } => return Err(format!("{message} ({code})").into()),
+            data => return Err(format!("unexpected message {data:?}").into()),
+        };
+        file.seek(SeekFrom::Start(downloaded_size))?;
+        let wsize = stream.copy_data(file)?;
+        if wsize != data_size {
+            return Err(format!("block {} size mismatch, expect {}, got {}", block_idx, data_size, wsize).into());
+        }
+        downloaded_size += data_size as u64;
+        let percent: f64 = (downloaded_size as f64 / file_size as f64) * 100_f64;
+        let downloaded_size_er = utils::format_size(downloaded_size);
+        let total_size_er = utils::format_size(file_size);
+        print!("{:<50}: [{}/{},{:>6}]\r", item.path().full_path(), downloaded_size_er, total_size_er, format!("{:.2}%", percent));
+        if (data_size as u64) < block_size {
+            break ;
+        }
+    }
+    file.set_len(downloaded_size)?;
+    Ok(())
+}
+
+/// 依次恢复扩展属性、修改时间与权限(先设置权限可能导致无法写入扩展属性);
+/// 不恢复 setuid/setgid/sticky, 目录保留所有者的读写执行权限, 以便之后继续下载其中的条目
+fn restore_meta(path: &Path, info: &DirItemInfo) -> CommomResult<()> {
+    let (modified_at, mode, xattrs) = match info {
+        DirItemInfo::File { modified_at, mode, xattrs, .. } => (*modified_at, *mode, xattrs),
+        DirItemInfo::Dir { modified_at, mode, xattrs, .. } => (*modified_at, *mode, xattrs),
+        _ => return Ok(()),
+    };
+    for xattr in xattrs.iter().flatten() {
+        if !xattr::is_restorable(&xattr.name) {
+            eprintln!("skip xattr {} of {}, only user.* is restored", xattr.name, path.display());
+            continue ;
+        }
+        if let Err(e) = xattr::write_xattr(path, xattr) {
+            eprintln!("restore xattr {} of {} failed, {e}", xattr.name, path.display());
         }
     }
+    fs::File::open(path)?.set_modified(UNIX_EPOCH + Duration::from_secs(modified_at))?;
+    // note: 旧版本 server 不返回 mode
+    if mode != 0 {
+        let mode = if matches!(info, DirItemInfo::Dir { .. }) { mode & 0o777 | 0o700 } else { mode & 0o777 };
+        fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+    }
+    Ok(())
+}
+
+/// 只重建指向保存目录以内的相对链接, 避免之后的下载经由链接写到保存目录以外;
+/// path 为链接相对于保存目录的路径
+fn link_stays_inside(path: &str, target: &str) -> bool {
+    let mut depth = Path::new(path.trim_start_matches('/')).components().count() as isize - 1;
+    for component in Path::new(target).components() {
+        match component {
+            Component::Normal(_) => depth += 1,
+            Component::CurDir => {},
+            Component::ParentDir => depth -= 1,
+            Component::RootDir | Component::Prefix(_) => return false,
+        }
+        if depth < 0 {
+            return false;
+        }
+    }
+    true
+}
+
+#[cfg(test)]
+mod test_downloader {
+    use std::{fs, os::unix::fs::PermissionsExt as _, time::{Duration, UNIX_EPOCH}};
+
+    use crate::{common::test_util::TempDir, features::{commands::{DirItemInfo, Xattr}, xattr::read_xattrs}};
+
+    use super::{link_stays_inside, restore_meta};
+
+    #[test]
+    fn test_restore_meta() {
+        let dir = TempDir::new("restore");
+        let file = dir.join("run.sh");
+        fs::write(&file, "#!/bin/sh").unwrap();
+        let info = DirItemInfo::File {
+            modified_at: 1_000_000,
+            created_at: 1_000_000,
+            file_size: 9,
+            chksum: None,
+            mode: 0o4755,
+            executable: true,
+            xattrs: None,
+        };
+        restore_meta(&file, &info).unwrap();
+        let meta = fs::metadata(&file).unwrap();
+        // setuid 不恢复
+        assert_eq!(meta.permissions().mode() & 0o7777, 0o755);
+        assert_eq!(meta.modified().unwrap(), UNIX_EPOCH + Duration::from_secs(1_000_000));
+
+        let info = DirItemInfo::Dir { modified_at: 2_000_000, created_at: 0, item_count: 1, mode: 0o555, xattrs: None };
+        restore_meta(&dir, &info).unwrap();
+        let meta = fs::metadata(&dir).unwrap();
+        assert_eq!(meta.permissions().mode() & 0o777, 0o755);
+        assert_eq!(meta.modified().unwrap(), UNIX_EPOCH + Duration::from_secs(2_000_000));
+    }
+
+    #[test]
+    fn test_restore_xattrs() {
+        let dir = TempDir::new("restore-xattrs");
+        let file = dir.join("a.txt");
+        fs::write(&file, "a").unwrap();
+        let user = Xattr { name: "user.ft.comment".to_string(), value: b"hello".to_vec() };
+        // note: 以 root 运行测试时 security 命名空间也可以写入, 跳过时才不会出现
+        let security = Xattr { name: "security.ft.test".to_string(), value: b"evil".to_vec() };
+        let info = DirItemInfo::File {
+            modified_at: 1_000_000,
+            created_at: 1_000_000,
+            file_size: 1,
+            chksum: None,
+            mode: 0o644,
+            executable: false,
+            xattrs: Some(vec![security.clone(), user.clone()]),
+        };
+        restore_meta(&file, &info).unwrap();
+        let xattrs = read_xattrs(&file).unwrap();
+        assert!(!xattrs.iter().any(|xattr| xattr.name == security.name));
+        // note: 部分文件系统不支持 user 命名空间
+        if !xattrs.is_empty() {
+            assert!(xattrs.contains(&user));
+        }
+    }
+
+    #[test]
+    fn test_link_stays_inside() {
+        assert!(link_stays_inside("/a/link", "b.txt"));
+        assert!(link_stays_inside("/a/link", "../b/c.txt"));
+        assert!(!link_stays_inside("/a/link", "../../b.txt"));
+        assert!(!link_stays_inside("/link", "../b.txt"));
+        assert!(!link_stays_inside("/a/link", "/etc/passwd"));
+        assert!(link_stays_inside("/a/link", "./x/../../b.txt"));
+    }
 }
@@ -0,0 +1,61 @@
+use crate::{
+    common::{config::{Config, CFG_PATH}, CommomResult},
+    features::commands::{ApiCommand, Command, CommandData, DirItem, FtPath},
+};
+
+use super::api;
+
+/// 共享目录中的路径
+fn remote_path(cli_config: &mut Config, path: &str) -> FtPath {
+    let root_path = cli_config.get_key(CFG_PATH.to_string()).unwrap_or("/".to_string());
+    FtPath::new_relative(root_path, path.to_string())
+}
+
+fn request(cli_config: &mut Config, command: Command) -> CommomResult<CommandData> {
+    let name = command.name();
+    if !api::share_info(cli_config, false)?.allows_write(name) {
+        return Err(format!("server does not allow {name}").into());
+    }
+    match api::do_http_request_data(cli_config, &ApiCommand::new(command))?.data {
+        CommandData::Error { code, message } => Err(format!("{message} ({code})").into()),
+        data => Ok(data),
+    }
+}
+
+fn operation_item(data: CommandData) -> CommomResult<DirItem> {
+    match data {
+        CommandData::FileOperation { item } => Ok(item),
+        data => Err(format!("unexpected message {data:?}").into()),
+    }
+}
+
+pub fn make_dir(cli_config: &mut Config, dir_path: &str, parents: bool) -> CommomResult<DirItem> {
+    let dir_path = remote_path(cli_config, dir_path);
+    operation_item(request(cli_config, Command::MakeDir { dir_path, parents })?)
+}
+
+pub fn rename(cli_config: &mut Config, from: &str, to: &str, overwrite: bool) -> CommomResult<DirItem> {
+    let (from, to) = (remote_path(cli_config, from), remote_path(cli_config, to));
+    operation_item(request(cli_config, Command::Rename { from, to, overwrite })?)
+}
+
+/// 移动到目录 to_dir 下, 保持原名称
+pub fn move_to(cli_config: &mut Config, from: &str, to_dir: &str, overwrite: bool) -> CommomResult<DirItem> {
+    let name = from.trim_end_matches('/').rsplit('/').next().unwrap_or_default();
+    let to = format!("{}/{}", to_dir.trim_end_matches('/'), name);
+    rename(cli_config, from, &to, overwrite)
+}
+
+pub fn copy(cli_config: &mut Config, from: &str, to: &str, overwrite: bool) -> CommomResult<DirItem> {
+    let (from, to) = (remote_path(cli_config, from), remote_path(cli_config, to));
+    operation_item(request(cli_config, Command::Copy { from, to, overwrite })?)
+}
+
+/// 返回回收站中的路径, 直接删除时为 None
+pub fn delete(cli_config: &mut Config, path: &str, trash: bool) -> CommomResult<Option<FtPath>> {
+    let path = remote_path(cli_config, path);
+    match request(cli_config, Command::Delete { path, trash })? {
+        CommandData::Delete { trash_path } => Ok(trash_path),
+        data => Err(format!("unexpected message {data:?}").into()),
+    }
+}
@@ -3,13 +3,18 @@ use std::path::{Path, PathBuf};
 use clap::Parser;
 
 use crate::{
-    common::{config::{self, Config, CFG_PATH}, gen_uuid, utils}, 
-    features::commands::{self, CommandData, FtPath, ApiCommand},
+    common::{config::{self, Config, CFG_PATH}, gen_uuid, utils, CommomResult}, 
+    features::commands::{self, CommandData, DirItem, FtPath, ApiCommand, ShareInfo},
 };
 
 mod api;
 mod cli_commands;
 mod downloader;
+mod file_ops;
+mod search;
+mod tree;
+mod uploader;
+mod watch;
 
 pub fn main() {
     use cli_commands::Command as cli_enum;
@@ -20,6 +25,13 @@ pub fn main() {
         Some(config::FILE_TUNNEL_ENDPOINT_CLIENT.to_string()),
     );
     cli_config.init();
+    // note: Ctrl-C 退出前取消进行中的请求, server 不再继续读取和发送数据
+    if let Err(e) = ctrlc::set_handler(|| api::cancel_in_flight_and_exit(130)) {
+        eprintln!("set Ctrl-C handler failed, err: {e}");
+    }
+    if let Some(encoding) = cli.encoding {
+        api::prefer_encoding(encoding);
+    }
     if let Some(cmd) = &cli.command {
         match cmd {    
             cli_enum::SetLocalConfig { 
@@ -28,7 +40,7 @@ pub fn main() {
                 tunnel_host, 
                 password 
             } => {
-                assert_eq!(Path::new(save_path).exists(), true, "please make sure it exists");
+                assert!(Path::new(save_path).exists(), "please make sure it exists");
                 cli_config.set(config::CFG_PATH.to_string(), save_path.clone(), None);
                 cli_config.set(config::CFG_TUNNEL_HOST.to_string(), tunnel_host.clone(), None);
                 cli_config.set(config::CFG_SHARE_KEY.to_string(), share_key.clone(), None);
@@ -40,9 +52,9 @@ pub fn main() {
             cli_enum::ShowLocalConfig { names } => {
                 let names: Vec<String> = match names {
                     Some(names) => {
-                        let new_names: Vec<String> = names.into_iter()
+                        let new_names: Vec<String> = names.iter()
                                 .filter(|name| cli_config.keys().contains(*name))
-                                .map(|f| f.clone())
+                                .cloned()
                                 .collect();
                         if new_names.len() != names.len() {
                             eprintln!("some cfg name be ignored");
@@ -51,8 +63,8 @@ pub fn main() {
                     },
                     None => {
                         let new_names: Vec<String> = cli_config.keys().iter()
-                                        .filter(|f| (**f) != config::CFG_PASSWORD.to_string())
-                                        .map(|f| f.clone())
+                                        .filter(|f| *f != config::CFG_PASSWORD)
+                                        .cloned()
                                         .collect();
                         new_names
                     }
@@ -62,61 +74,174 @@ pub fn main() {
                 }).collect();
             }
             cli_enum::ReadServerConfig {  } => {
-                let cmd = ApiCommand {
-                    version: 1,
-                    command: commands::Command::ReadConfig {  }
-                };
-                match api::do_http_request(&mut cli_config, &cmd) {
-                    Ok(result) => println!("server config: {}", String::from_iter(result.iter())),
-                    Err(err) => eprintln!("error: {}", err.to_string()),
+                match api::share_info(&mut cli_config, true) {
+                    Ok(share) => print_share_info(&share),
+                    Err(err) => eprintln!("error: {}", err),
                 }
             },
-            cli_enum::ReadDirItem { dir_path, take_size, skip_size, format, download } => {
-                match downloader::download(
-                    &mut cli_config,
-                    PathBuf::from(dir_path),
-                    *take_size,
-                    *skip_size,
-                    *download,
-                ) {
+            cli_enum::ReadDirItem {
+                dir_path,
+                take_size,
+                skip_size,
+                format: _,
+                download,
+                sort,
+                desc,
+                ext,
+                item_type,
+                name,
+                skip_hidden,
+                cursor,
+                all,
+                xattrs,
+            } => {
+                let sort = commands::DirSort { key: *sort, descending: *desc };
+                let filter = commands::ItemFilter {
+                    item_type: *item_type,
+                    extensions: ext.clone(),
+                    name: name.clone(),
+                    skip_hidden: *skip_hidden,
+                    ..Default::default()
+                };
+                let options = downloader::ListOptions {
+                    take_size: *take_size,
+                    skip_size: *skip_size,
+                    sort,
+                    filter,
+                    cursor: cursor.clone(),
+                    all: *all,
+                    with_xattrs: *xattrs,
+                };
+                match downloader::download(&mut cli_config, PathBuf::from(dir_path), options, *download) {
                     Ok(()) => {},
-                    Err(e) => panic!("read dir item failed, {}", e)
+                    Err(e) => eprintln!("read dir item failed, {}", e)
                 }
             },
-            cli_enum::ReadFileInfo { file_path } => {
+            cli_enum::ReadFileInfo { file_path, xattrs } => {
                 let root_path = cli_config.get_key(CFG_PATH.to_string()).unwrap_or("/".to_string());
-                let cmd = ApiCommand {
-                    version: 1,
-                    command: commands::Command::ReadFileInfo { 
-                        file_path: FtPath::new_relative(root_path, file_path.clone()),
-                    }
-                };
+                let cmd = ApiCommand::new(commands::Command::ReadFileInfo { 
+                    file_path: FtPath::new_relative(root_path, file_path.clone()),
+                    with_xattrs: *xattrs,
+                });
 
                 match api::do_http_request_data(&mut cli_config, &cmd) {
                     Ok(message) => match message.data {
-                        CommandData::DownloadFile { data, data_size } => {
-
-                        },
-                        _ => {},
+                        CommandData::ReadFileInfo { item } => println!("{:#?}", item),
+                        CommandData::Error { code, message } => eprintln!("request error: {message} ({code})"),
+                        data => eprintln!("request error: unexpected message {data:?}"),
                     },
                     Err(e) => eprintln!("request error: {}", e)
                 }
             },
+            cli_enum::Checksum { file_path } => {
+                let root_path = cli_config.get_key(CFG_PATH.to_string()).unwrap_or("/".to_string());
+                let path = FtPath::new_relative(root_path, file_path.clone());
+                match downloader::checksum(&mut cli_config, &path) {
+                    Ok((chksum, cached)) => println!("{chksum}  {}{}", path.path(), if cached { " (cached)" } else { "" }),
+                    Err(e) => eprintln!("checksum failed, {}", e),
+                }
+            },
             cli_enum::DownloadFile { file_path, block_size, block_idx } => {
                 let root_path = cli_config.get_key(CFG_PATH.to_string()).unwrap_or("/".to_string());
-                let cmd = ApiCommand {
-                    version: 1,
-                    command: commands::Command::DownloadFile { 
-                        file_path: FtPath::new_relative(root_path, file_path.clone()),
-                        block_idx: *block_idx,
-                        block_size: *block_size,
-                    }
-                };
+                let cmd = ApiCommand::new(commands::Command::DownloadFile { 
+                    file_path: FtPath::new_relative(root_path, file_path.clone()),
+                    block_idx: *block_idx,
+                    block_size: *block_size,
+                });
 
-                if let Ok(result) = api::do_http_request(&mut cli_config, &cmd) {
-                    println!("result: {}", String::from_iter(result.iter()));
+                match api::do_http_request_raw(&mut cli_config, &cmd) {
+                    Ok((message, data)) => println!("result: {:?}, {}", message.data, String::from_utf8_lossy(&data)),
+                    Err(e) => eprintln!("request error: {}", e),
+                }
+            },
+            cli_enum::Upload { local_path, remote_path, overwrite } => {
+                if let Err(e) = uploader::upload(&mut cli_config, PathBuf::from(local_path), remote_path.clone(), *overwrite) {
+                    eprintln!("upload {} failed, {}", local_path, e);
+                }
+            },
+            cli_enum::Mkdir { dir_path, parents } => {
+                print_operation("mkdir", file_ops::make_dir(&mut cli_config, dir_path, *parents));
+            },
+            cli_enum::Rename { from, to, overwrite } => {
+                print_operation("rename", file_ops::rename(&mut cli_config, from, to, *overwrite));
+            },
+            cli_enum::Move { from, to_dir, overwrite } => {
+                print_operation("move", file_ops::move_to(&mut cli_config, from, to_dir, *overwrite));
+            },
+            cli_enum::Copy { from, to, overwrite } => {
+                print_operation("copy", file_ops::copy(&mut cli_config, from, to, *overwrite));
+            },
+            cli_enum::Delete { path, trash } => {
+                match file_ops::delete(&mut cli_config, path, *trash) {
+                    Ok(Some(trash_path)) => println!("moved to trash: /{}", trash_path.path()),
+                    Ok(None) => println!("deleted: {path}"),
+                    Err(e) => eprintln!("delete failed, {}", e),
+                }
+            },
+            cli_enum::Tree { path, depth, max_items } => {
+                if let Err(e) = tree::tree(&mut cli_config, path, *depth, *max_items) {
+                    eprintln!("tree failed, {}", e);
+                }
+            },
+            cli_enum::Find {
+                path,
+                name,
+                regex,
+                item_type,
+                min_size,
+                max_size,
+                modified_after,
+                modified_before,
+                max_results,
+            } => {
+                let filter = commands::ItemFilter {
+                    item_type: *item_type,
+                    min_size: *min_size,
+                    max_size: *max_size,
+                    modified_after: *modified_after,
+                    modified_before: *modified_before,
+                    ..Default::default()
+                };
+                if let Err(e) = search::find(&mut cli_config, path, name, *regex, filter, *max_results) {
+                    eprintln!("find failed, {}", e);
+                }
+            },
+            cli_enum::Grep { path, pattern, regex, ignore_case, context, include, max_file_size, max_matches } => {
+                let options = search::GrepOptions {
+                    regex: *regex,
+                    ignore_case: *ignore_case,
+                    context: *context,
+                    include: include.clone(),
+                    max_file_size: *max_file_size,
+                    max_matches: *max_matches,
+                };
+                if let Err(e) = search::grep(&mut cli_config, path, pattern, options) {
+                    eprintln!("grep failed, {}", e);
+                }
+            },
+            cli_enum::Watch { path, recursive } => {
+                if let Err(e) = watch::watch(&mut cli_config, path, *recursive) {
+                    eprintln!("watch failed, {}", e);
                 }
             }
         }
     }
 }
+
+fn print_operation(operation: &str, result: CommomResult<DirItem>) {
+    match result {
+        Ok(item) => println!("{operation}: /{}", item.path().path()),
+        Err(e) => eprintln!("{operation} failed, {}", e),
+    }
+}
+
+fn print_share_info(share: &ShareInfo) {
+    println!("name: {}", share.name);
+    println!("server: {}, protocol: {}", share.server, share.protocol_version);
+    println!("access: {}", if share.read_only { "read-only" } else { "read-write" });
+    println!("files: {}, size: {}", share.file_count, utils::format_size(share.total_size));
+    println!("free space: {}", utils::format_size(share.free_space));
+    println!("commands: {}", share.commands.join(", "));
+    let codecs: Vec<String> = share.codecs.iter().map(|codec| codec.to_string()).collect();
+    println!("codecs: {}", codecs.join(", "));
+}
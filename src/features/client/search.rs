@@ -0,0 +1,99 @@
+use crate::{
+    common::{config::{Config, CFG_PATH}, utils, CommomResult},
+    features::commands::{ApiCommand, Command, CommandData, DirItem, DirItemInfo, FtPath, GrepMatch, ItemFilter},
+};
+
+use super::api;
+
+/// 查找共享目录中的文件, 边接收边输出
+pub fn find(cli_config: &mut Config, path: &str, pattern: &str, regex: bool, filter: ItemFilter, max_results: usize) -> CommomResult<()> {
+    if !api::share_info(cli_config, false)?.supports("Search") {
+        return Err("server does not support searching files".into());
+    }
+    let root_path = cli_config.get_key(CFG_PATH.to_string()).unwrap_or("/".to_string());
+    let cmd = ApiCommand::new(Command::Search {
+        path: FtPath::new_relative(root_path, path.to_string()),
+        pattern: pattern.to_string(),
+        regex,
+        filter,
+        max_results,
+    });
+    let mut stream = api::do_http_request_stream(cli_config, &cmd)?;
+    while let Some(message) = stream.next_message()? {
+        match message.data {
+            CommandData::SearchItems { items } => items.iter().for_each(print_item),
+            CommandData::Search { matched, truncated } => {
+                let more = if truncated { ", more results omitted" } else { "" };
+                println!("info: {matched} matched{more}");
+            },
+            CommandData::Error { code, message } => return Err(format!("{message} ({code})").into()),
+            data => return Err(format!("unexpected message {data:?}").into()),
+        }
+    }
+    Ok(())
+}
+
+pub struct GrepOptions {
+    pub regex: bool,
+    pub ignore_case: bool,
+    pub context: usize,
+    pub include: Option<String>,
+    pub max_file_size: u64,
+    pub max_matches: usize,
+}
+
+/// 查找共享目录中文件的内容, 按 `path:line: text` 输出, 上下文行使用 `-` 分隔
+pub fn grep(cli_config: &mut Config, path: &str, pattern: &str, options: GrepOptions) -> CommomResult<()> {
+    if !api::share_info(cli_config, false)?.supports("Grep") {
+        return Err("server does not support searching file content".into());
+    }
+    let root_path = cli_config.get_key(CFG_PATH.to_string()).unwrap_or("/".to_string());
+    let cmd = ApiCommand::new(Command::Grep {
+        path: FtPath::new_relative(root_path, path.to_string()),
+        pattern: pattern.to_string(),
+        regex: options.regex,
+        ignore_case: options.ignore_case,
+        context: options.context,
+        include: options.include,
+        max_file_size: options.max_file_size,
+        max_matches: options.max_matches,
+    });
+    let mut stream = api::do_http_request_stream(cli_config, &cmd)?;
+    while let Some(message) = stream.next_message()? {
+        match message.data {
+            CommandData::GrepMatches { matches } => matches.iter().for_each(|m| print_match(m, options.context > 0)),
+            CommandData::Grep { scanned, skipped, matched, truncated } => {
+                let more = if truncated { ", more matches omitted" } else { "" };
+                println!("info: {matched} matched in {scanned} files, {skipped} skipped{more}");
+            },
+            CommandData::Error { code, message } => return Err(format!("{message} ({code})").into()),
+            data => return Err(format!("unexpected message {data:?}").into()),
+        }
+    }
+    Ok(())
+}
+
+fn print_match(grep_match: &GrepMatch, with_context: bool) {
+    let path = grep_match.path.path();
+    let first_line = grep_match.line_number - grep_match.before.len() as u64;
+    for (idx, line) in grep_match.before.iter().enumerate() {
+        println!("/{}-{}- {}", path, first_line + idx as u64, line);
+    }
+    println!("/{}:{}: {}", path, grep_match.line_number, grep_match.line);
+    for (idx, line) in grep_match.after.iter().enumerate() {
+        println!("/{}-{}- {}", path, grep_match.line_number + 1 + idx as u64, line);
+    }
+    if with_context {
+        println!("--");
+    }
+}
+
+fn print_item(item: &DirItem) {
+    let (size, link) = match &item.info {
+        DirItemInfo::File { file_size, .. } => (utils::format_size(*file_size), String::new()),
+        DirItemInfo::Dir { item_count, .. } => (format!("{}", item_count), String::new()),
+        DirItemInfo::Symlink { target, .. } => ("0".to_string(), format!(" -> {target}")),
+        DirItemInfo::Other { .. } => ("0".to_string(), String::new()),
+    };
+    println!("{} {:>8} /{}{}", item.info.type_flag(), size, item.path().path(), link);
+}
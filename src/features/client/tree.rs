@@ -0,0 +1,67 @@
+use std::collections::HashMap;
+
+use crate::{
+    common::{config::{Config, CFG_PATH}, utils, CommomResult},
+    features::commands::{ApiCommand, Command, CommandData, DirItemInfo, FtPath, TreeItem},
+};
+
+use super::api;
+
+/// 读取目录树, 接收完成后按层级输出
+pub fn tree(cli_config: &mut Config, path: &str, max_depth: Option<usize>, max_items: usize) -> CommomResult<()> {
+    if !api::share_info(cli_config, false)?.supports("ReadTree") {
+        return Err("server does not support reading directory trees".into());
+    }
+    let root_path = cli_config.get_key(CFG_PATH.to_string()).unwrap_or("/".to_string());
+    let cmd = ApiCommand::new(Command::ReadTree {
+        path: FtPath::new_relative(root_path, path.to_string()),
+        max_depth,
+        max_items,
+    });
+    let mut stream = api::do_http_request_stream(cli_config, &cmd)?;
+    let mut items: Vec<TreeItem> = vec![];
+    let mut summary = None;
+    while let Some(message) = stream.next_message()? {
+        match message.data {
+            CommandData::TreeItems { items: batch } => items.extend(batch),
+            CommandData::ReadTree { total, truncated } => summary = Some((total, truncated)),
+            CommandData::Error { code, message } => return Err(format!("{message} ({code})").into()),
+            data => return Err(format!("unexpected message {data:?}").into()),
+        }
+    }
+
+    let mut children: HashMap<usize, Vec<&TreeItem>> = HashMap::new();
+    for item in items.iter() {
+        if let Some(parent) = item.parent {
+            children.entry(parent).or_default().push(item);
+        }
+    }
+    if let Some(root) = items.iter().find(|item| item.parent.is_none()) {
+        println!("/{}", root.item.path().path());
+        print_children(&children, root.id, "");
+    }
+    if let Some((total, truncated)) = summary {
+        let more = if truncated { ", more items omitted" } else { "" };
+        println!("info: {total} items{more}");
+    }
+    Ok(())
+}
+
+fn print_children(children: &HashMap<usize, Vec<&TreeItem>>, parent: usize, prefix: &str) {
+    let Some(items) = children.get(&parent) else {
+        return ;
+    };
+    for (idx, item) in items.iter().enumerate() {
+        let last = idx + 1 == items.len();
+        let path = item.item.path().path();
+        let name = path.rsplit('/').next().unwrap_or_default();
+        let detail = match &item.item.info {
+            DirItemInfo::File { file_size, .. } => format!("{name} ({})", utils::format_size(*file_size)),
+            DirItemInfo::Dir { item_count, .. } => format!("{name}/ ({item_count})"),
+            DirItemInfo::Symlink { target, .. } => format!("{name} -> {target}"),
+            DirItemInfo::Other { file_type, .. } => format!("{name} ({file_type})"),
+        };
+        println!("{prefix}{}{detail}", if last { "└── " } else { "├── " });
+        print_children(children, item.id, &format!("{prefix}{}", if last { "    " } else { "│   " }));
+    }
+}
@@ -0,0 +1,110 @@
+use std::{fs, io::{Read as _, Seek, SeekFrom}, path::PathBuf};
+
+use crate::{
+    common::{config::{Config, CFG_PATH}, utils, CommomResult},
+    features::{
+        codec::{is_compressed_file, Codec},
+        commands::{ApiCommand, Command, CommandData, FtPath},
+    },
+};
+
+use super::api;
+
+/// 上传块大小, server 限制单块不超过 16MiB
+const UPLOAD_BLOCK_SIZE: usize = 1 << 20;
+
+/// 上传本地文件到共享目录, remote_path 以 `/` 结尾时使用本地文件名
+pub fn upload(cli_config: &mut Config, local_path: PathBuf, remote_path: String, overwrite: bool) -> CommomResult<()> {
+    let share = api::share_info(cli_config, false)?;
+    if !share.allows_write("UploadFile") {
+        return Err("server does not allow uploading files".into());
+    }
+    let meta = fs::metadata(&local_path)?;
+    if !meta.is_file() {
+        return Err(format!("{} is not a file", local_path.display()).into());
+    }
+    let remote_path = if remote_path.ends_with('/') {
+        let file_name = local_path.file_name().and_then(|name| name.to_str()).unwrap_or_default();
+        format!("{remote_path}{file_name}")
+    } else {
+        remote_path
+    };
+    let root_path = cli_config.get_key(CFG_PATH.to_string()).unwrap_or("/".to_string());
+    let file_path = FtPath::new_relative(root_path, remote_path);
+    let file_size = meta.len();
+    let chksum = utils::file_sha256(&local_path)?;
+
+    let cmd = ApiCommand::new(Command::UploadStatus {
+        file_path: file_path.clone(),
+        file_size,
+        chksum: chksum.clone(),
+        overwrite,
+    });
+    let missing_blocks = match api::do_http_request_data(cli_config, &cmd)?.data {
+        CommandData::UploadStatus { missing_blocks, .. } => missing_blocks,
+        CommandData::Error { code, message } => return Err(format!("{message} ({code})").into()),
+        data => return Err(format!("unexpected message {data:?}").into()),
+    };
+
+    // note: 只续传 server 记录中缺少的块, 没有记录时上传所有块; 已全部接收但未完成校验时重新发送最后一块, 触发 server 再次校验
+    let block_count = file_size.div_ceil(UPLOAD_BLOCK_SIZE as u64).max(1) as usize;
+    let blocks = match missing_blocks {
+        None => (0..block_count).collect(),
+        Some(missing_blocks) if missing_blocks.is_empty() => vec![block_count - 1],
+        Some(missing_blocks) => missing_blocks,
+    };
+    let codec = if is_compressed_file(&local_path.to_string_lossy()) {
+        Codec::Identity
+    } else {
+        Codec::select(&share.codecs)
+    };
+    let mut file = fs::File::open(&local_path)?;
+    let mut buf = vec![0; UPLOAD_BLOCK_SIZE];
+    for block_idx in blocks {
+        let offset = block_idx as u64 * UPLOAD_BLOCK_SIZE as u64;
+        file.seek(SeekFrom::Start(offset))?;
+        let size = read_block(&mut file, &mut buf)?;
+        let (block_codec, data) = match codec.try_compress(&buf[..size]) {
+            Some(compressed) => (codec, compressed),
+            None => (Codec::Identity, buf[..size].to_vec()),
+        };
+        let cmd = ApiCommand::new(Command::UploadFile {
+            file_path: file_path.clone(),
+            file_size,
+            chksum: chksum.clone(),
+            block_idx,
+            block_size: UPLOAD_BLOCK_SIZE,
+            overwrite,
+            codec: block_codec,
+            data,
+        });
+        match api::do_http_request_data(cli_config, &cmd)?.data {
+            CommandData::UploadFile { received_size, completed } => {
+                let percent: f64 = if file_size > 0 { (received_size as f64 / file_size as f64) * 100_f64 } else { 100_f64 };
+                let uploaded_size_er = utils::format_size(received_size);
+                let total_size_er = utils::format_size(file_size);
+                print!("{:<50}: [{}/{},{:>6}]\r", file_path.path(), uploaded_size_er, total_size_er, format!("{:.2}%", percent));
+                if completed {
+                    println!();
+                    return Ok(());
+                }
+            },
+            CommandData::Error { code, message } => return Err(format!("{message} ({code})").into()),
+            data => return Err(format!("unexpected message {data:?}").into()),
+        }
+    }
+    println!();
+    Err(format!("upload {} not completed, run upload again to send the missing blocks", file_path.path()).into())
+}
+
+/// 读满一个块或读到文件末尾
+fn read_block(file: &mut fs::File, buf: &mut [u8]) -> CommomResult<usize> {
+    let mut size = 0;
+    while size < buf.len() {
+        match file.read(&mut buf[size..])? {
+            0 => break,
+            n => size += n,
+        }
+    }
+    Ok(size)
+}
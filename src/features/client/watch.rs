@@ -0,0 +1,38 @@
+use crate::{
+    common::{config::{Config, CFG_PATH}, CommomResult},
+    features::commands::{ApiCommand, Command, CommandData, FtPath, ModfiedType},
+};
+
+use super::api;
+
+/// 订阅共享目录中的变化并逐条输出, 直到 Ctrl-C 或连接断开
+pub fn watch(cli_config: &mut Config, path: &str, recursive: bool) -> CommomResult<()> {
+    if !api::share_info(cli_config, false)?.supports("Watch") {
+        return Err("server does not support watching changes".into());
+    }
+    let root_path = cli_config.get_key(CFG_PATH.to_string()).unwrap_or("/".to_string());
+    let cmd = ApiCommand::new(Command::Watch {
+        path: FtPath::new_relative(root_path, path.to_string()),
+        recursive,
+    });
+    let mut stream = api::do_http_request_stream(cli_config, &cmd)?;
+    let mut started = false;
+    while let Some(message) = stream.next_message()? {
+        match message.data {
+            CommandData::Watching { path } => {
+                if !started {
+                    println!("watching /{}, press Ctrl-C to stop", path.path());
+                    started = true;
+                }
+            },
+            CommandData::ModifiedFile { path, m_type, from } => match (m_type, from) {
+                (ModfiedType::Rename, Some(from)) => println!("{:<8} /{} -> /{}", "rename", from.path(), path.path()),
+                (ModfiedType::Overflow, _) => println!("{:<8} /{}, some changes were dropped, read it again", "overflow", path.path()),
+                (m_type, _) => println!("{:<8} /{}", format!("{m_type:?}").to_lowercase(), path.path()),
+            },
+            CommandData::Error { code, message } => return Err(format!("{message} ({code})").into()),
+            data => return Err(format!("unexpected message {data:?}").into()),
+        }
+    }
+    Err("watch stopped by server".into())
+}
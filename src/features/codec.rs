@@ -0,0 +1,137 @@
+use std::{fmt::Display, io::{self, Read as _, Write as _}, path::Path};
+
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use serde::{Deserialize, Serialize};
+
+/// 小于该字节数的 payload 不压缩
+pub const COMPRESS_MIN_SIZE: usize = 512;
+
+/// 按优先级排列, server 选择双方都支持的第一个
+pub const SUPPORTED_CODECS: [Codec; 2] = [Codec::Zstd, Codec::Gzip];
+
+// 已压缩的文件格式，再压缩基本没有收益
+const COMPRESSED_EXTENSIONS: [&str; 24] = [
+    "gz", "tgz", "zst", "zip", "7z", "rar", "xz", "bz2", "lz4", "br",
+    "jpg", "jpeg", "png", "gif", "webp", "heic",
+    "mp3", "aac", "ogg", "flac", "mp4", "mkv", "mov", "webm",
+];
+
+#[derive(Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Codec {
+    #[default]
+    Identity = 0,
+    Gzip = 1,
+    Zstd = 2,
+}
+
+impl TryFrom<u8> for Codec {
+    type Error = u8;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(Self::Identity),
+            1 => Ok(Self::Gzip),
+            2 => Ok(Self::Zstd),
+            _ => Err(value),
+        }
+    }
+}
+
+impl Display for Codec {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Identity => write!(f, "identity"),
+            Self::Gzip => write!(f, "gzip"),
+            Self::Zstd => write!(f, "zstd"),
+        }
+    }
+}
+
+impl Codec {
+    /// 从对方声明的 codec 中选出本地支持且优先级最高的
+    pub fn select(accepted: &[Codec]) -> Codec {
+        SUPPORTED_CODECS.iter()
+            .find(|codec| accepted.contains(codec))
+            .copied()
+            .unwrap_or(Codec::Identity)
+    }
+
+    pub fn compress(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            Self::Identity => Ok(data.to_vec()),
+            Self::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::with_capacity(data.len() / 2), Compression::fast());
+                encoder.write_all(data)?;
+                encoder.finish()
+            },
+            Self::Zstd => zstd::encode_all(data, 3),
+        }
+    }
+
+    /// 解压后超过 limit 字节时返回错误, 避免少量压缩数据解压出大量内容耗尽内存
+    pub fn decompress_limited(&self, data: &[u8], limit: usize) -> io::Result<Vec<u8>> {
+        let decoder: Box<dyn io::Read + '_> = match self {
+            Self::Identity => Box::new(data),
+            Self::Gzip => Box::new(GzDecoder::new(data)),
+            Self::Zstd => Box::new(zstd::Decoder::new(data)?),
+        };
+        let mut buf = Vec::with_capacity(data.len().min(limit));
+        decoder.take(limit as u64 + 1).read_to_end(&mut buf)?;
+        if buf.len() > limit {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("decompressed size exceeds {limit} bytes")));
+        }
+        Ok(buf)
+    }
+
+    /// 压缩后更小时返回压缩结果, 否则返回 None
+    pub fn try_compress(&self, data: &[u8]) -> Option<Vec<u8>> {
+        if *self == Self::Identity || data.len() < COMPRESS_MIN_SIZE {
+            return None;
+        }
+        match self.compress(data) {
+            Ok(compressed) if compressed.len() < data.len() => Some(compressed),
+            Ok(_) => None,
+            Err(e) => {
+                eprintln!("{self} compress failed, err: {e}");
+                None
+            }
+        }
+    }
+}
+
+pub fn is_compressed_file(path: &str) -> bool {
+    Path::new(path).extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| COMPRESSED_EXTENSIONS.contains(&ext.to_lowercase().as_str()))
+}
+
+#[cfg(test)]
+mod test_codec {
+    use super::{is_compressed_file, Codec};
+
+    #[test]
+    fn test_codec_roundtrip() {
+        let data = "file-tunnel 文件 ".repeat(200).into_bytes();
+        for codec in [Codec::Gzip, Codec::Zstd] {
+            let compressed = codec.try_compress(&data).unwrap();
+            assert!(compressed.len() < data.len());
+            assert_eq!(codec.decompress_limited(&compressed, data.len()).unwrap(), data);
+        }
+        let compressed = Codec::Zstd.compress(&data).unwrap();
+        assert!(Codec::Zstd.decompress_limited(&compressed, data.len() - 1).is_err());
+        assert!(Codec::Identity.decompress_limited(&data, 16).is_err());
+        assert!(Codec::Identity.try_compress(&data).is_none());
+        assert!(Codec::Zstd.try_compress(b"tiny").is_none());
+    }
+
+    #[test]
+    fn test_codec_select() {
+        assert_eq!(Codec::select(&[Codec::Gzip, Codec::Zstd]), Codec::Zstd);
+        assert_eq!(Codec::select(&[Codec::Gzip]), Codec::Gzip);
+        assert_eq!(Codec::select(&[]), Codec::Identity);
+        assert!(is_compressed_file("/a/b/movie.MP4"));
+        assert!(!is_compressed_file("/a/b/app.log"));
+    }
+}
@@ -1,15 +1,32 @@
 use std::{
-    fmt::Display, fs::{self, DirEntry, Metadata}, path::{Path, PathBuf}, str::FromStr, time::UNIX_EPOCH
+    fmt::Display, fs::{self, DirEntry, Metadata}, io, os::unix::fs::MetadataExt, path::{Path, PathBuf}, time::UNIX_EPOCH
 };
 use serde::{Deserialize, Serialize};
 
+use crate::{common::gen_request_id, features::{codec::{Codec, SUPPORTED_CODECS}, encoding::Encoding, handshake::PROTOCOL_VERSION_MAX, xattr}};
+
 #[derive(Serialize, Deserialize)]
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct ApiCommand {
     pub version: u16,
+    pub request_id: u64,
+    /// client 可以解压的 codec, server 据此选择是否压缩响应
+    #[serde(default)]
+    pub codecs: Vec<Codec>,
     pub command: Command
 }
 
+impl ApiCommand {
+    pub fn new(command: Command) -> Self {
+        Self {
+            version: PROTOCOL_VERSION_MAX,
+            request_id: gen_request_id(),
+            codecs: SUPPORTED_CODECS.to_vec(),
+            command,
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize)]
 #[derive(Debug, Clone)]
 pub struct FtPath {
@@ -61,7 +78,7 @@ impl FtPath {
     }
 
     pub fn replace_root_path(&mut self, new_root: &str) {
-        self.relative_path = self.relative_path.trim_start_matches(&new_root).to_string();
+        self.relative_path = self.relative_path.trim_start_matches(new_root).to_string();
     }
 
     pub fn root_path(&self) -> &String {
@@ -74,42 +91,479 @@ impl FtPath {
 }
 
 #[derive(Serialize, Deserialize)]
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum Command {
-    ReadConfig {},
+    ReadConfig {
+        /// 是否统计文件数与总大小, 需要遍历整个共享目录
+        #[serde(default)]
+        with_stats: bool,
+    },
+    /// 先过滤、排序, 再按 skip_size, take_size 分页;
+    /// 还有后续页时返回游标, 使用游标读取后续页时忽略其他参数(take_size 除外), 各页来自同一个目录快照
     ReadDirItem {
         dir_path: FtPath,
         take_size: usize,
         skip_size: usize,
+        #[serde(default)]
+        sort: DirSort,
+        #[serde(default)]
+        filter: ItemFilter,
+        #[serde(default)]
+        cursor: Option<String>,
+        /// 同时返回文件与目录的扩展属性
+        #[serde(default)]
+        with_xattrs: bool,
     },
     ReadFileInfo {
         file_path: FtPath,
+        #[serde(default)]
+        with_xattrs: bool,
     },
+    /// path 为目录时同 `ReadDirItem`
     ReadPathInfo {
         path: FtPath,
         take_size: usize,
         skip_size: usize,
+        #[serde(default)]
+        sort: DirSort,
+        #[serde(default)]
+        filter: ItemFilter,
+        #[serde(default)]
+        cursor: Option<String>,
+        #[serde(default)]
+        with_xattrs: bool,
     },
     DownloadFile {
         file_path: FtPath,
         block_idx: usize,
         block_size: usize,
     },
+    /// 计算文件的 sha256, 结果按路径、大小与修改时间缓存在 server 上;
+    /// 计算期间定期返回 `ChecksumProgress`
+    Checksum {
+        file_path: FtPath,
+    },
     ModifiedFile {
         path: FtPath,
         m_type: ModfiedType,
+    },
+    /// 订阅 path(文件或目录)下的变化, 以多个 `ModifiedFile` 推送, 直到 client 取消请求;
+    /// recursive 为 false 时只包含 path 本身与其直接子条目
+    Watch {
+        path: FtPath,
+        #[serde(default)]
+        recursive: bool,
+    },
+    /// 查询未完成的上传, 返回已接收的字节数, 用于断点续传
+    UploadStatus {
+        file_path: FtPath,
+        file_size: u64,
+        chksum: String,
+        /// 目标文件已存在时是否覆盖
+        #[serde(default)]
+        overwrite: bool,
+    },
+    /// 上传文件块, 块可以乱序发送, 收齐所有块后校验 chksum(sha256) 并移动到 file_path
+    UploadFile {
+        file_path: FtPath,
+        file_size: u64,
+        chksum: String,
+        block_idx: usize,
+        block_size: usize,
+        #[serde(default)]
+        overwrite: bool,
+        /// data 的压缩方式
+        #[serde(default)]
+        codec: Codec,
+        #[serde(with = "serde_bytes")]
+        data: Vec<u8>,
+    },
+    /// 创建目录, parents 为 true 时同时创建不存在的上级目录
+    MakeDir {
+        dir_path: FtPath,
+        #[serde(default)]
+        parents: bool,
+    },
+    /// 重命名或移动文件、目录, to 为完整的目标路径
+    Rename {
+        from: FtPath,
+        to: FtPath,
+        #[serde(default)]
+        overwrite: bool,
+    },
+    /// 在 server 上复制文件或目录, 不经过 client
+    Copy {
+        from: FtPath,
+        to: FtPath,
+        #[serde(default)]
+        overwrite: bool,
+    },
+    /// 删除文件或目录(包括目录下的所有内容), trash 为 true 时移动到共享目录的回收站
+    Delete {
+        path: FtPath,
+        #[serde(default)]
+        trash: bool,
+    },
+    /// 在 path 下递归查找名称匹配 pattern 的文件与目录, 以多个 `SearchItems` 返回结果
+    Search {
+        path: FtPath,
+        /// 默认为 glob, 不含 `/` 时只匹配名称, 否则匹配相对于 path 的路径
+        pattern: String,
+        /// pattern 为正则表达式, 匹配相对于 path 的路径
+        #[serde(default)]
+        regex: bool,
+        #[serde(default)]
+        filter: ItemFilter,
+        /// 最多返回的条目数, 0 使用 server 的默认上限
+        #[serde(default)]
+        max_results: usize,
+    },
+    /// 递归读取 path 下的目录树, 以多个 `TreeItems` 返回, 父目录总在子条目之前
+    ReadTree {
+        path: FtPath,
+        /// 最多读取的层数, 1 为只读取 path 的直接子条目; None 读取整个子树
+        #[serde(default)]
+        max_depth: Option<usize>,
+        /// 最多返回的条目数, 0 使用 server 的默认上限
+        #[serde(default)]
+        max_items: usize,
+    },
+    /// 在 path(文件或目录)下的文本文件中查找内容, 以多个 `GrepMatches` 返回匹配的行
+    Grep {
+        path: FtPath,
+        /// 默认按字面查找, regex 为 true 时为正则表达式
+        pattern: String,
+        #[serde(default)]
+        regex: bool,
+        #[serde(default)]
+        ignore_case: bool,
+        /// 匹配行前后各返回的行数
+        #[serde(default)]
+        context: usize,
+        /// 只查找名称匹配该 glob 的文件, 例如 `*.log`
+        #[serde(default)]
+        include: Option<String>,
+        /// 跳过大于该字节数的文件, 0 使用 server 的默认值
+        #[serde(default)]
+        max_file_size: u64,
+        /// 最多返回的匹配行数, 0 使用 server 的默认上限
+        #[serde(default)]
+        max_matches: usize,
+    },
+    /// 建立端到端加密会话, 与 `ConfirmSession` 是仅有的允许明文发送的命令
+    OpenSession {
+        public_key: String,
+        /// client 支持的编码, 会话中的命令与响应使用 server 选择的编码
+        #[serde(default)]
+        encodings: Vec<Encoding>,
+    },
+    /// client 先证明持有相同的密码, server 校验通过后才回复自己的确认值
+    ConfirmSession {
+        session_id: String,
+        proof: String,
+    },
+    /// 请求登录挑战
+    AuthChallenge {},
+    /// 回复登录挑战, response 为 HMAC(password, challenge + client_key)
+    AuthResponse {
+        challenge: String,
+        response: String,
+    },
+}
+
+impl Command {
+    /// 命令名称, 与 `ShareInfo.commands` 对应
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::ReadConfig { .. } => "ReadConfig",
+            Self::ReadDirItem { .. } => "ReadDirItem",
+            Self::ReadFileInfo { .. } => "ReadFileInfo",
+            Self::ReadPathInfo { .. } => "ReadPathInfo",
+            Self::DownloadFile { .. } => "DownloadFile",
+            Self::Checksum { .. } => "Checksum",
+            Self::ModifiedFile { .. } => "ModifiedFile",
+            Self::Watch { .. } => "Watch",
+            Self::UploadStatus { .. } => "UploadStatus",
+            Self::UploadFile { .. } => "UploadFile",
+            Self::MakeDir { .. } => "MakeDir",
+            Self::Rename { .. } => "Rename",
+            Self::Copy { .. } => "Copy",
+            Self::Delete { .. } => "Delete",
+            Self::ReadTree { .. } => "ReadTree",
+            Self::Search { .. } => "Search",
+            Self::Grep { .. } => "Grep",
+            Self::OpenSession { .. } => "OpenSession",
+            Self::ConfirmSession { .. } => "ConfirmSession",
+            Self::AuthChallenge {} => "AuthChallenge",
+            Self::AuthResponse { .. } => "AuthResponse",
+        }
+    }
+}
+
+/// `ReadConfig` 返回的共享信息, client 据此判断 server 支持哪些功能
+#[derive(Serialize, Deserialize)]
+#[derive(Debug, Clone, Default)]
+pub struct ShareInfo {
+    /// 共享名称, 默认为共享目录名
+    pub name: String,
+    /// server 软件与版本, 例如 `file-tunnel/0.1.0`
+    pub server: String,
+    pub protocol_version: u16,
+    /// server 可以处理的命令名称, 见 `Command::name`
+    pub commands: Vec<String>,
+    pub codecs: Vec<Codec>,
+    /// 只读共享不接受任何修改文件的命令
+    pub read_only: bool,
+    /// 仅在 `ReadConfig { with_stats: true }` 时统计, 否则为 0
+    pub file_count: u64,
+    pub total_size: u64,
+    /// 共享目录所在磁盘的可用空间, 无法获取时为 0
+    pub free_space: u64,
+    #[serde(default)]
+    pub symlinks: SymlinkPolicy,
+}
+
+/// 共享目录中符号链接的处理方式
+#[derive(Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum SymlinkPolicy {
+    /// 按链接目标返回, 目标不存在时返回 `Symlink`
+    #[default]
+    Follow,
+    /// 返回 `Symlink`, 不读取链接目标, client 在本地重建链接
+    Link,
+    /// 不返回符号链接
+    Hide,
+}
+
+impl ShareInfo {
+    pub fn supports(&self, command: &str) -> bool {
+        self.commands.iter().any(|c| c == command)
     }
+
+    /// 可写且支持该命令
+    pub fn allows_write(&self, command: &str) -> bool {
+        !self.read_only && self.supports(command)
+    }
+}
+
+/// 目录树中的一个条目, id 在一次 ReadTree 中唯一; path 本身 id 为 0, parent 为 None
+#[derive(Serialize, Deserialize)]
+#[derive(Debug, Clone)]
+pub struct TreeItem {
+    pub id: usize,
+    pub parent: Option<usize>,
+    /// path 本身为 0
+    pub depth: usize,
+    pub item: DirItem,
+}
+
+/// Grep 匹配的一行, 过长的行会被截断
+#[derive(Serialize, Deserialize)]
+#[derive(Debug, Clone)]
+pub struct GrepMatch {
+    pub path: FtPath,
+    /// 从 1 开始
+    pub line_number: u64,
+    pub line: String,
+    pub before: Vec<String>,
+    pub after: Vec<String>,
 }
 
+/// 条目过滤条件, 未设置的条件不过滤
+#[derive(Serialize, Deserialize)]
+#[derive(Debug, Clone, Default)]
+pub struct ItemFilter {
+    #[serde(default)]
+    pub item_type: Option<ItemType>,
+    /// 设置大小条件时只匹配文件
+    #[serde(default)]
+    pub min_size: Option<u64>,
+    #[serde(default)]
+    pub max_size: Option<u64>,
+    /// 修改时间, unix 时间戳(秒)
+    #[serde(default)]
+    pub modified_after: Option<u64>,
+    #[serde(default)]
+    pub modified_before: Option<u64>,
+    /// 扩展名(不含 `.`, 不区分大小写), 设置后只匹配文件
+    #[serde(default)]
+    pub extensions: Vec<String>,
+    /// 名称 glob, 例如 `*.log`
+    #[serde(default)]
+    pub name: Option<String>,
+    /// 跳过 `.` 开头的隐藏条目
+    #[serde(default)]
+    pub skip_hidden: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum ItemType {
+    File,
+    Dir,
+}
+
+/// 目录列表的排序方式, 默认按名称升序; 其他排序键相同时按名称排序, 保证分页稳定
+#[derive(Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DirSort {
+    #[serde(default)]
+    pub key: SortKey,
+    #[serde(default)]
+    pub descending: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum SortKey {
+    #[default]
+    Name,
+    /// 目录按 0 处理
+    Size,
+    Modified,
+    /// 目录在前, 文件在后
+    Type,
+}
+
+impl ItemFilter {
+    /// 不包含 name glob, 由 server 编译后单独匹配
+    pub fn matches(&self, name: &str, meta: &Metadata) -> bool {
+        let item_type = if meta.is_dir() { ItemType::Dir } else { ItemType::File };
+        if self.item_type.is_some_and(|expect| expect != item_type) {
+            return false;
+        }
+        if self.skip_hidden && name.starts_with('.') {
+            return false;
+        }
+        if !self.extensions.is_empty() {
+            let extension = Path::new(name).extension().map(|ext| ext.to_string_lossy().to_lowercase());
+            let matched = extension.is_some_and(|extension| {
+                self.extensions.iter().any(|expect| expect.trim_start_matches('.').to_lowercase() == extension)
+            });
+            if item_type != ItemType::File || !matched {
+                return false;
+            }
+        }
+        if (self.min_size.is_some() || self.max_size.is_some())
+            && (item_type != ItemType::File
+                || self.min_size.is_some_and(|min_size| meta.len() < min_size)
+                || self.max_size.is_some_and(|max_size| meta.len() > max_size)) {
+            return false;
+        }
+        let modified_at = || meta.modified().ok()
+            .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+            .map_or(0, |d| d.as_secs());
+        !(self.modified_after.is_some_and(|after| modified_at() < after)
+            || self.modified_before.is_some_and(|before| modified_at() > before))
+    }
+}
 
 #[derive(Serialize, Deserialize)]
 #[derive(Debug)]
 pub struct CommandMessage {
     pub version: u16,
+    pub request_id: u64,
     pub status: u16,
     pub data: CommandData,
 }
 
+impl CommandMessage {
+    /// 失败响应, status 由 code 决定
+    pub fn error(version: u16, request_id: u64, code: ErrorCode, message: String) -> Self {
+        Self {
+            version,
+            request_id,
+            status: code.status(),
+            data: CommandData::Error { code, message },
+        }
+    }
+}
+
+/// `CommandData::Error` 的错误码, 供 client 按类型处理, 不依赖 message 的内容
+#[derive(Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    NotFound,
+    PermissionDenied,
+    NotADirectory,
+    TooLarge,
+    /// 共享为只读, server 需 `set-config --writable` 开启写入
+    ReadOnly,
+    AlreadyExists,
+    ChecksumMismatch,
+    /// 分页游标已过期或无效, 需要重新列目录
+    CursorExpired,
+    BadRequest,
+    Unauthorized,
+    Unsupported,
+    UnsupportedVersion,
+    /// tunnel 或 server 暂时不可用
+    Unavailable,
+    #[default]
+    Internal,
+}
+
+impl ErrorCode {
+    /// 对应的 `CommandMessage.status`, 取值参考 http 状态码
+    pub fn status(&self) -> u16 {
+        match self {
+            Self::NotFound => 404,
+            Self::PermissionDenied => 403,
+            Self::NotADirectory | Self::BadRequest => 400,
+            Self::TooLarge => 413,
+            Self::ReadOnly => 403,
+            Self::AlreadyExists => 409,
+            Self::ChecksumMismatch => 422,
+            Self::CursorExpired => 410,
+            Self::Unauthorized => 401,
+            Self::Unsupported => 501,
+            Self::UnsupportedVersion => 426,
+            Self::Unavailable => 503,
+            Self::Internal => 500,
+        }
+    }
+}
+
+impl Display for ErrorCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::NotFound => write!(f, "not_found"),
+            Self::PermissionDenied => write!(f, "permission_denied"),
+            Self::NotADirectory => write!(f, "not_a_directory"),
+            Self::TooLarge => write!(f, "too_large"),
+            Self::ReadOnly => write!(f, "read_only"),
+            Self::AlreadyExists => write!(f, "already_exists"),
+            Self::ChecksumMismatch => write!(f, "checksum_mismatch"),
+            Self::CursorExpired => write!(f, "cursor_expired"),
+            Self::BadRequest => write!(f, "bad_request"),
+            Self::Unauthorized => write!(f, "unauthorized"),
+            Self::Unsupported => write!(f, "unsupported"),
+            Self::UnsupportedVersion => write!(f, "unsupported_version"),
+            Self::Unavailable => write!(f, "unavailable"),
+            Self::Internal => write!(f, "internal"),
+        }
+    }
+}
+
+impl From<&io::Error> for ErrorCode {
+    fn from(value: &io::Error) -> Self {
+        match value.kind() {
+            io::ErrorKind::NotFound => Self::NotFound,
+            io::ErrorKind::PermissionDenied => Self::PermissionDenied,
+            io::ErrorKind::NotADirectory => Self::NotADirectory,
+            io::ErrorKind::FileTooLarge => Self::TooLarge,
+            io::ErrorKind::AlreadyExists => Self::AlreadyExists,
+            io::ErrorKind::Unsupported => Self::Unsupported,
+            _ => Self::Internal,
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize)]
 #[derive(Debug, Clone)]
 pub struct DirItem {
@@ -123,43 +577,60 @@ impl DirItem {
     }
 }
 
-impl From <DirEntry> for DirItem {
-    fn from(value: DirEntry) -> Self {
-        let path = value.path().to_str().unwrap().to_string();
-        Self {
-            info: DirItemInfo::new(&path),
-            path: FtPath::new_absolute("".to_string(), path),
-        }
+impl DirItem {
+    /// follow_links 为 false 时符号链接返回 `DirItemInfo::Symlink`
+    pub fn new(path: FtPath, follow_links: bool) -> io::Result<Self> {
+        Ok(Self {
+            info: DirItemInfo::new(&path.full_path(), follow_links)?,
+            path,
+        })
     }
 }
 
-impl From<String> for DirItem {
-    fn from(value: String) -> Self {
-        Self {
-            info: DirItemInfo::new(&value),
+impl TryFrom<DirEntry> for DirItem {
+    type Error = io::Error;
+
+    fn try_from(value: DirEntry) -> Result<Self, Self::Error> {
+        Self::try_from(value.path().to_string_lossy().to_string())
+    }
+}
+
+impl TryFrom<String> for DirItem {
+    type Error = io::Error;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        Ok(Self {
+            info: DirItemInfo::new(&value, true)?,
             path: FtPath::new_absolute("".to_string(), value),
-        }
+        })
     }
 }
 
-impl From<&str> for DirItem {
-    fn from(value: &str) -> Self {
-        Self {
-            path: FtPath::new_absolute("".to_string(), value.to_string()),
-            info: DirItemInfo::new(&value.to_string())
-        }
+impl TryFrom<&str> for DirItem {
+    type Error = io::Error;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        Self::try_from(value.to_string())
     }
 }
 
-impl From<FtPath> for DirItem {
-    fn from(value: FtPath) -> Self {
-        Self {
-            info: DirItemInfo::new(&value.full_path()),
-            path: value,
-        }
+impl TryFrom<FtPath> for DirItem {
+    type Error = io::Error;
+
+    fn try_from(value: FtPath) -> Result<Self, Self::Error> {
+        Self::new(value, true)
     }
 }
 
+/// 扩展属性, 名称包含命名空间, 例如 `user.comment`
+#[derive(Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Xattr {
+    pub name: String,
+    #[serde(with = "serde_bytes")]
+    pub value: Vec<u8>,
+}
+
 #[derive(Serialize, Deserialize)]
 #[derive(Debug, Clone)]
 pub enum DirItemInfo {
@@ -167,54 +638,136 @@ pub enum DirItemInfo {
         modified_at: u64,
         created_at: u64,
         item_count: u64,
+        /// Unix 权限位(含 setuid/setgid/sticky), 旧版本 server 为 0
+        #[serde(default)]
+        mode: u32,
+        /// 仅在请求 `with_xattrs` 时读取, 否则为 None
+        #[serde(default)]
+        xattrs: Option<Vec<Xattr>>,
     },
     File {
         modified_at: u64,
         created_at: u64,
         file_size: u64,
-        chksum: String,
-    }
+        /// 列目录时不计算校验和, 需要时使用 `Checksum` 命令获取
+        #[serde(default)]
+        chksum: Option<String>,
+        #[serde(default)]
+        mode: u32,
+        /// 任一执行位被设置, 供不使用 mode 的 client 判断
+        #[serde(default)]
+        executable: bool,
+        #[serde(default)]
+        xattrs: Option<Vec<Xattr>>,
+    },
+    Symlink {
+        modified_at: u64,
+        created_at: u64,
+        /// 链接内容, 可能是相对路径, 也可能指向不存在的路径
+        target: String,
+    },
+    /// FIFO、socket、设备等特殊文件
+    Other {
+        modified_at: u64,
+        created_at: u64,
+        /// `fifo`, `socket`, `char_device`, `block_device` 或 `unknown`
+        file_type: String,
+    },
 }
 
 impl DirItemInfo {
-    pub fn new(path: &String) -> Self {
+    /// 路径不存在或无权限读取时返回错误; follow_links 为 true 时按链接目标返回, 目标不存在时仍返回 `Symlink`
+    pub fn new(path: &String, follow_links: bool) -> io::Result<Self> {
         let _path = Path::new(path);
-        let meta = _path.metadata().expect(format!("path {}", path).as_str());
-        let modified_at = meta.modified().unwrap().duration_since(UNIX_EPOCH).unwrap().as_secs() as u64;
-        let created_at = meta.created().unwrap().duration_since(UNIX_EPOCH).unwrap().as_secs() as u64;
-        if _path.is_dir() {
-            return Self::Dir {
-                modified_at: modified_at,
-                created_at: created_at,
+        let link_meta = _path.symlink_metadata()?;
+        let meta = match follow_links && link_meta.is_symlink() {
+            true => _path.metadata().unwrap_or(link_meta),
+            false => link_meta,
+        };
+        let modified_at = meta.modified()?.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+        // note: 部分文件系统不记录创建时间, 使用修改时间代替
+        let created_at = meta.created().ok()
+            .and_then(|created| created.duration_since(UNIX_EPOCH).ok())
+            .map_or(modified_at, |d| d.as_secs());
+        if meta.is_dir() {
+            return Ok(Self::Dir {
+                modified_at,
+                created_at,
                 item_count: Self::item_size(path, &meta),
-            };
+                mode: meta.mode() & 0o7777,
+                xattrs: None,
+            });
         }
-        if _path.is_file() {
-            use sha256::try_digest;
-            return Self::File {
-                modified_at: modified_at,
-                created_at: created_at,
+        if meta.is_file() {
+            return Ok(Self::File {
+                modified_at,
+                created_at,
                 file_size: Self::item_size(path, &meta),
-                chksum: try_digest(_path).unwrap(),
-            };
+                chksum: None,
+                mode: meta.mode() & 0o7777,
+                executable: meta.mode() & 0o111 != 0,
+                xattrs: None,
+            });
+        }
+        if meta.is_symlink() {
+            return Ok(Self::Symlink {
+                modified_at,
+                created_at,
+                target: fs::read_link(_path)?.to_string_lossy().to_string(),
+            });
+        }
+        Ok(Self::Other { modified_at, created_at, file_type: Self::special_type(&meta).to_string() })
+    }
+
+    /// 读取扩展属性, 只有文件与目录有扩展属性
+    pub fn load_xattrs(&mut self, path: &Path) -> io::Result<()> {
+        match self {
+            Self::File { xattrs, .. } | Self::Dir { xattrs, .. } => *xattrs = Some(xattr::read_xattrs(path)?),
+            _ => {},
+        }
+        Ok(())
+    }
+
+    /// 与 `ls -l` 相同的类型标记
+    pub fn type_flag(&self) -> &'static str {
+        match self {
+            Self::File { .. } => "-",
+            Self::Dir { .. } => "d",
+            Self::Symlink { .. } => "l",
+            Self::Other { file_type, .. } => match file_type.as_str() {
+                "fifo" => "p",
+                "socket" => "s",
+                "char_device" => "c",
+                "block_device" => "b",
+                _ => "?",
+            },
+        }
+    }
+
+    fn special_type(meta: &Metadata) -> &'static str {
+        use std::os::unix::fs::FileTypeExt;
+        let file_type = meta.file_type();
+        if file_type.is_fifo() {
+            "fifo"
+        } else if file_type.is_socket() {
+            "socket"
+        } else if file_type.is_char_device() {
+            "char_device"
+        } else if file_type.is_block_device() {
+            "block_device"
+        } else {
+            "unknown"
         }
-        panic!("{} unsupported type", path);
     }
 
     pub fn item_size(path: &String, meta: &Metadata) -> u64 {
         if meta.is_dir() {
-            let mut _size = 0;
-            let _: Vec<_> = fs::read_dir(path).unwrap().map(|f| {
-                if let Ok(_f) = f {
-                    _size += 1;
-                }
-            }).collect();
-
-            return _size;
+            // note: 无权限读取的子目录按空目录处理
+            fs::read_dir(path).map_or(0, |entries| entries.flatten().count() as u64)
         } else if meta.is_file() {
-            return meta.len() as u64;
+            meta.len()
         } else {
-            return 0;
+            0
         }
     }
 }
@@ -223,55 +776,166 @@ impl DirItemInfo {
 #[derive(Debug)]
 pub enum CommandData {
     ReadConfig {
-        path: String,
+        share: ShareInfo,
     },
     ReadDirItem {
         items: Vec<DirItem>,
         total: usize,
         taked_size: usize,
+        /// 读取下一页的游标, 已是最后一页时为 None
+        #[serde(default)]
+        cursor: Option<String>,
     },
     ReadFileInfo {
         item: DirItem,
     },
     DownloadFile {
-        data: Vec<u8>,
         data_size: usize,
     },
+    /// 计算期间返回的进度, 用于保持连接
+    ChecksumProgress {
+        hashed_size: u64,
+        file_size: u64,
+    },
+    /// cached 为 true 时结果来自缓存, 未读取文件
+    Checksum {
+        chksum: String,
+        cached: bool,
+    },
+    /// 订阅成功后首先返回, 之后定期返回用于保持连接
+    Watching {
+        path: FtPath,
+    },
+    /// 合并后的一次变化, Rename 时 from 为原路径
     ModifiedFile {
-        path: String,
+        path: FtPath,
         m_type: ModfiedType,
+        #[serde(default)]
+        from: Option<FtPath>,
+    },
+    UploadStatus {
+        received_size: u64,
+        /// 按上传时的 block_size 计算尚未接收的块, 没有未完成的上传时为 None;
+        /// 为空时所有块都已接收但还未完成校验
+        #[serde(default)]
+        missing_blocks: Option<Vec<usize>>,
+    },
+    UploadFile {
+        received_size: u64,
+        /// 已校验并移动到目标路径
+        completed: bool,
+    },
+    /// MakeDir, Rename, Copy 返回目标的信息
+    FileOperation {
+        item: DirItem,
+    },
+    Delete {
+        /// 移动到回收站时为回收站中的路径
+        trash_path: Option<FtPath>,
+    },
+    /// 流式返回的一批目录树条目
+    TreeItems {
+        items: Vec<TreeItem>,
+    },
+    /// ReadTree 结束, truncated 为 true 时达到了条目上限
+    ReadTree {
+        total: usize,
+        truncated: bool,
+    },
+    /// 流式返回的一批结果, 可能为空(用于保持连接)
+    SearchItems {
+        items: Vec<DirItem>,
+    },
+    /// Search 结束, truncated 为 true 时达到了结果上限
+    Search {
+        matched: usize,
+        truncated: bool,
+    },
+    /// 流式返回的一批匹配行, 可能为空(用于保持连接)
+    GrepMatches {
+        matches: Vec<GrepMatch>,
+    },
+    /// Grep 结束, skipped 为因过大、二进制或无法读取而跳过的文件数
+    Grep {
+        scanned: usize,
+        skipped: usize,
+        matched: usize,
+        truncated: bool,
+    },
+    OpenSession {
+        session_id: String,
+        public_key: String,
+        #[serde(default)]
+        encoding: Encoding,
+    },
+    ConfirmSession {
+        proof: String,
+    },
+    AuthChallenge {
+        challenge: String,
+    },
+    AuthResponse {
+        authenticated: bool,
     },
     Error {
+        #[serde(default)]
+        code: ErrorCode,
         message: String,
     }
 }
 
 #[derive(Serialize, Deserialize)]
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum ModfiedType {
     Meta,
     Content,
+    Create,
+    Delete,
+    Rename,
+    /// server 丢失了部分事件, client 需要重新读取订阅的路径
+    Overflow,
 }
 
 #[cfg(test)]
 mod test_commands {
+    use std::io;
+
     use crate::features::commands::{FtPath, ModfiedType};
 
-    use super::{Command, ApiCommand};
+    use super::{Command, ApiCommand, CommandData, CommandMessage, ErrorCode};
 
     #[test]
     fn se_commands() {
         let cmd = ApiCommand {
             version: 1,
+            request_id: 1,
+            codecs: vec![],
             command: Command::ModifiedFile { path: FtPath::new_absolute("".to_string(), "".to_string()), m_type: ModfiedType::Content }
         };
 
         println!("json: {}", serde_json::to_string(&cmd).unwrap());
     }
 
+    #[test]
+    fn test_error_code() {
+        let message = CommandMessage::error(1, 7, ErrorCode::NotFound, "/a: missing".to_string());
+        assert_eq!(message.status, 404);
+        let json = serde_json::to_string(&message).unwrap();
+        assert!(json.contains(r#""code":"not_found""#));
+
+        // 没有 code 的旧格式按 internal 处理
+        let s = r#"{"version":1,"request_id":7,"status":500,"data":{"Error":{"message":"boom"}}}"#;
+        let message: CommandMessage = serde_json::from_str(s).unwrap();
+        assert!(matches!(message.data, CommandData::Error { code: ErrorCode::Internal, .. }));
+
+        let e = io::Error::from(io::ErrorKind::PermissionDenied);
+        assert_eq!(ErrorCode::from(&e), ErrorCode::PermissionDenied);
+        assert_eq!(ErrorCode::from(&io::Error::other("boom")), ErrorCode::Internal);
+    }
+
     #[test]
     fn de_commands() {
-        let s = r#"{"version":1,"command":{"ModifiedFile":{"path":"","m_type":"Content"}}}"#;
+        let s = r#"{"version":1,"request_id":1,"command":{"ModifiedFile":{"path":{"root":"","relative_path":""},"m_type":"Content"}}}"#;
         
         let cmd: ApiCommand = serde_json::from_str(s).unwrap();
         println!("{cmd:#?}");
@@ -0,0 +1,527 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{atomic::{AtomicU64, Ordering}, Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use chacha20poly1305::{aead::{Aead, Payload}, ChaCha20Poly1305, KeyInit, Nonce};
+use curve25519_dalek::{ristretto::CompressedRistretto, traits::IsIdentity, RistrettoPoint, Scalar};
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use rand::Rng;
+use sha2::{Digest, Sha256, Sha512};
+
+use crate::common::CommomResult;
+
+use super::{encoding::Encoding, frame::FrameType};
+
+// 加密后的 payload 格式:
+// | magic(4) | session_id(16) | counter(8) | ciphertext + tag(16) |
+pub const SEALED_MAGIC: [u8; 4] = *b"FTE\x01";
+pub const SESSION_ID_SIZE: usize = 16;
+const SEALED_HEADER_SIZE: usize = SEALED_MAGIC.len() + SESSION_ID_SIZE + 8;
+const REPLAY_WINDOW_SIZE: u64 = 64;
+/// server 最多保留的会话数，超出后淘汰会话最多的 client 最早建立的会话
+pub const MAX_SESSIONS: usize = 256;
+/// 每个 client 最多保留的会话数，超出后淘汰该 client 最早建立的会话
+pub const MAX_CLIENT_SESSIONS: usize = 4;
+/// 每个 client 在 OPEN_SESSION_WINDOW 内最多建立的会话数
+const MAX_OPEN_SESSIONS: usize = 60;
+const OPEN_SESSION_WINDOW: Duration = Duration::from_secs(60);
+
+const HKDF_INFO: &[u8] = b"file-tunnel e2e v1";
+const CPACE_DSI: &[u8] = b"file-tunnel cpace v1";
+const AUTH_CONTEXT: &[u8] = b"file-tunnel auth v1";
+pub const CHALLENGE_SIZE: usize = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Client,
+    Server,
+}
+
+/// CPace 密钥交换的一方, 公钥以 hex 编码传输;
+/// 生成元由共享密码派生, 公钥本身不泄露密码, 替换公钥的一方(包括 tunnel)每个会话只能在线猜测一次密码
+pub struct KeyExchange {
+    secret: Scalar,
+    public_key: RistrettoPoint,
+}
+
+impl KeyExchange {
+    pub fn new(password: &str) -> Self {
+        let mut hasher = Sha512::new();
+        hasher.update(CPACE_DSI);
+        hasher.update((password.len() as u64).to_be_bytes());
+        hasher.update(password.as_bytes());
+        let generator = RistrettoPoint::from_uniform_bytes(&hasher.finalize().into());
+        let mut random = [0u8; 64];
+        rand::thread_rng().fill(&mut random[..]);
+        let secret = Scalar::from_bytes_mod_order_wide(&random);
+        Self { secret, public_key: secret * generator }
+    }
+
+    pub fn public_key(&self) -> String {
+        hex::encode(self.public_key.compress().as_bytes())
+    }
+
+    /// 由 CPace 共享密钥派生会话密钥与双方的确认值, 密码不同的双方得到不同的密钥
+    pub fn establish(self, role: Role, peer_public_key: &str, session_id: &str) -> CommomResult<Session> {
+        let peer_public_key = CompressedRistretto::from_slice(&hex::decode(peer_public_key)?)
+            .ok()
+            .and_then(|point| point.decompress())
+            .filter(|point| !point.is_identity())
+            .ok_or("invalid public key")?;
+        let id: [u8; SESSION_ID_SIZE] = hex::decode(session_id)?
+            .try_into()
+            .map_err(|_| "invalid session id")?;
+        let (client_public_key, server_public_key) = match role {
+            Role::Client => (self.public_key, peer_public_key),
+            Role::Server => (peer_public_key, self.public_key),
+        };
+        let shared = self.secret * peer_public_key;
+        if shared.is_identity() {
+            return Err("invalid public key".into());
+        }
+
+        let mut salt = client_public_key.compress().as_bytes().to_vec();
+        salt.extend_from_slice(server_public_key.compress().as_bytes());
+        salt.extend_from_slice(&id);
+        let mut okm = [0u8; 128];
+        Hkdf::<Sha256>::new(Some(&salt), shared.compress().as_bytes())
+            .expand(HKDF_INFO, &mut okm)
+            .map_err(|e| e.to_string())?;
+
+        let client_key = ChaCha20Poly1305::new_from_slice(&okm[..32]).map_err(|e| e.to_string())?;
+        let server_key = ChaCha20Poly1305::new_from_slice(&okm[32..64]).map_err(|e| e.to_string())?;
+        let (send_key, recv_key) = match role {
+            Role::Client => (client_key, server_key),
+            Role::Server => (server_key, client_key),
+        };
+        Ok(Session {
+            id,
+            send_key,
+            recv_key,
+            client_proof: okm[64..96].try_into().unwrap(),
+            server_proof: okm[96..].try_into().unwrap(),
+            send_counter: AtomicU64::new(0),
+            replay: Mutex::new(ReplayWindow::default()),
+            encoding: Encoding::Json,
+        })
+    }
+}
+
+pub fn gen_session_id() -> String {
+    hex::encode(rand::thread_rng().gen::<[u8; SESSION_ID_SIZE]>())
+}
+
+pub fn is_sealed(payload: &[u8]) -> bool {
+    payload.len() >= SEALED_HEADER_SIZE && payload[..SEALED_MAGIC.len()] == SEALED_MAGIC
+}
+
+/// 读取加密 payload 中的会话 id
+pub fn sealed_session_id(payload: &[u8]) -> Option<String> {
+    if is_sealed(payload) {
+        Some(hex::encode(&payload[SEALED_MAGIC.len()..SEALED_MAGIC.len() + SESSION_ID_SIZE]))
+    } else {
+        None
+    }
+}
+
+pub fn gen_challenge() -> String {
+    hex::encode(rand::thread_rng().gen::<[u8; CHALLENGE_SIZE]>())
+}
+
+fn auth_mac(password: &str, challenge: &str, client_key: &str) -> Hmac<Sha256> {
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(password.as_bytes()).expect("hmac accepts any key size");
+    mac.update(AUTH_CONTEXT);
+    mac.update(challenge.as_bytes());
+    mac.update(client_key.as_bytes());
+    mac
+}
+
+/// 登录应答: HMAC-SHA256(password, challenge + client_key), 密码本身不经过 tunnel
+pub fn auth_response(password: &str, challenge: &str, client_key: &str) -> String {
+    hex::encode(auth_mac(password, challenge, client_key).finalize().into_bytes())
+}
+
+pub fn verify_auth_response(password: &str, challenge: &str, client_key: &str, response: &str) -> bool {
+    match hex::decode(response) {
+        Ok(response) => auth_mac(password, challenge, client_key).verify_slice(&response).is_ok(),
+        Err(_) => false,
+    }
+}
+
+/// 认证附加数据: 将密文绑定到请求 id、frame 类型与 flags, tunnel 无法挪用或篡改
+pub fn aad(request_id: u64, frame_type: FrameType, flags: u8) -> [u8; 10] {
+    let mut aad = [0u8; 10];
+    aad[..8].copy_from_slice(&request_id.to_be_bytes());
+    aad[8] = frame_type as u8;
+    aad[9] = flags;
+    aad
+}
+
+pub struct Session {
+    id: [u8; SESSION_ID_SIZE],
+    send_key: ChaCha20Poly1305,
+    recv_key: ChaCha20Poly1305,
+    client_proof: [u8; 32],
+    server_proof: [u8; 32],
+    send_counter: AtomicU64,
+    replay: Mutex<ReplayWindow>,
+    encoding: Encoding,
+}
+
+impl Session {
+    /// 会话协商的 `ApiCommand` 与 `CommandMessage` 编码
+    pub fn with_encoding(mut self, encoding: Encoding) -> Self {
+        self.encoding = encoding;
+        self
+    }
+
+    pub fn encoding(&self) -> Encoding {
+        self.encoding
+    }
+
+    pub fn id(&self) -> String {
+        hex::encode(self.id)
+    }
+
+    /// 该方的密钥确认值: client 先发送, server 校验通过后才回复自己的确认值
+    pub fn proof(&self, role: Role) -> String {
+        match role {
+            Role::Client => hex::encode(self.client_proof),
+            Role::Server => hex::encode(self.server_proof),
+        }
+    }
+
+    /// 校验对方的确认值, 按字节比较全部内容, 耗时与不同之处无关
+    pub fn verify_proof(&self, role: Role, proof: &str) -> bool {
+        let expect = match role {
+            Role::Client => &self.client_proof,
+            Role::Server => &self.server_proof,
+        };
+        match hex::decode(proof) {
+            Ok(proof) if proof.len() == expect.len() => proof.iter().zip(expect).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0,
+            _ => false,
+        }
+    }
+
+    pub fn seal(&self, aad: &[u8], plaintext: &[u8]) -> CommomResult<Vec<u8>> {
+        let counter = self.send_counter.fetch_add(1, Ordering::SeqCst) + 1;
+        let ciphertext = self.send_key
+            .encrypt(&nonce(counter), Payload { msg: plaintext, aad })
+            .map_err(|_| "encrypt failed")?;
+        let mut sealed = Vec::with_capacity(SEALED_HEADER_SIZE + ciphertext.len());
+        sealed.extend_from_slice(&SEALED_MAGIC);
+        sealed.extend_from_slice(&self.id);
+        sealed.extend_from_slice(&counter.to_be_bytes());
+        sealed.extend(ciphertext);
+        Ok(sealed)
+    }
+
+    /// 解密独立的消息, 使用滑动窗口防重放
+    pub fn open(&self, aad: &[u8], sealed: &[u8]) -> CommomResult<Vec<u8>> {
+        let (counter, plaintext) = self.decrypt(aad, sealed)?;
+        // note: 认证通过后再记录 counter, 避免伪造的 counter 污染窗口
+        if !self.replay.lock().unwrap().accept(counter) {
+            return Err(format!("replayed payload, counter: {counter}").into());
+        }
+        Ok(plaintext)
+    }
+
+    /// 解密同一响应流中的 frame, counter 必须严格递增;
+    /// 多个响应流并发读取时顺序不定, 跨流的重放由 aad 中的 request_id 拒绝
+    pub fn open_stream(&self, aad: &[u8], sealed: &[u8], last_counter: &mut u64) -> CommomResult<Vec<u8>> {
+        let (counter, plaintext) = self.decrypt(aad, sealed)?;
+        if counter <= *last_counter {
+            return Err(format!("replayed payload, counter: {counter}").into());
+        }
+        *last_counter = counter;
+        Ok(plaintext)
+    }
+
+    fn decrypt(&self, aad: &[u8], sealed: &[u8]) -> CommomResult<(u64, Vec<u8>)> {
+        if !is_sealed(sealed) {
+            return Err("payload is not sealed".into());
+        }
+        if sealed[SEALED_MAGIC.len()..SEALED_MAGIC.len() + SESSION_ID_SIZE] != self.id {
+            return Err("session mismatch".into());
+        }
+        let counter = u64::from_be_bytes(sealed[SEALED_HEADER_SIZE - 8..SEALED_HEADER_SIZE].try_into().unwrap());
+        let plaintext = self.recv_key
+            .decrypt(&nonce(counter), Payload { msg: &sealed[SEALED_HEADER_SIZE..], aad })
+            .map_err(|_| "decrypt failed, password mismatch or payload tampered")?;
+        Ok((counter, plaintext))
+    }
+}
+
+fn nonce(counter: u64) -> Nonce {
+    let mut nonce = [0u8; 12];
+    nonce[4..].copy_from_slice(&counter.to_be_bytes());
+    Nonce::from(nonce)
+}
+
+/// 滑动窗口防重放: 接受比已见最大值更新的 counter, 或窗口内未出现过的 counter
+#[derive(Default)]
+struct ReplayWindow {
+    max: u64,
+    seen: u64,
+}
+
+impl ReplayWindow {
+    fn accept(&mut self, counter: u64) -> bool {
+        if counter == 0 {
+            return false;
+        }
+        if counter > self.max {
+            let shift = counter - self.max;
+            self.seen = if shift >= REPLAY_WINDOW_SIZE { 0 } else { self.seen << shift };
+            self.seen |= 1;
+            self.max = counter;
+            return true;
+        }
+        let offset = self.max - counter;
+        if offset >= REPLAY_WINDOW_SIZE || self.seen & (1 << offset) != 0 {
+            return false;
+        }
+        self.seen |= 1 << offset;
+        true
+    }
+}
+
+struct StoredSession {
+    /// 建立会话的 client_key, 其他 client 不能使用该会话
+    client_key: String,
+    session: Arc<Session>,
+    /// client 的确认值校验通过后才能用于加密命令
+    confirmed: bool,
+}
+
+/// server 端的会话表, 按 client_key 限制会话数与建立频率, 一个 client 不能挤掉其他 client 的会话
+#[derive(Default)]
+pub struct SessionStore {
+    sessions: HashMap<String, StoredSession>,
+    /// (client_key, session_id), 按建立顺序
+    order: VecDeque<(String, String)>,
+    /// 每个 client 最近建立会话的时间
+    opened: HashMap<String, VecDeque<Instant>>,
+}
+
+impl SessionStore {
+    /// 建立会话前调用, 超出频率限制时返回 false
+    pub fn allow_open(&mut self, client_key: &str) -> bool {
+        let now = Instant::now();
+        self.opened.retain(|_, times| {
+            while times.front().is_some_and(|time| now.duration_since(*time) >= OPEN_SESSION_WINDOW) {
+                times.pop_front();
+            }
+            !times.is_empty()
+        });
+        let times = self.opened.entry(client_key.to_string()).or_default();
+        if times.len() >= MAX_OPEN_SESSIONS {
+            return false;
+        }
+        times.push_back(now);
+        true
+    }
+
+    pub fn insert(&mut self, client_key: &str, session: Session) {
+        let id = session.id();
+        if self.order.iter().filter(|(key, _)| key == client_key).count() >= MAX_CLIENT_SESSIONS {
+            self.evict(client_key);
+        }
+        if self.order.len() >= MAX_SESSIONS {
+            let mut counts: HashMap<&str, usize> = HashMap::new();
+            for (key, _) in &self.order {
+                *counts.entry(key).or_default() += 1;
+            }
+            // note: 数量相同时选最早建立会话的 client, max_by_key 相同时返回最后一个, 因此逆序遍历
+            let busiest = self.order.iter()
+                .rev()
+                .map(|(key, _)| key)
+                .max_by_key(|key| counts[key.as_str()])
+                .cloned();
+            if let Some(busiest) = busiest {
+                self.evict(&busiest);
+            }
+        }
+        self.order.push_back((client_key.to_string(), id.clone()));
+        self.sessions.insert(id, StoredSession { client_key: client_key.to_string(), session: Arc::new(session), confirmed: false });
+    }
+
+    /// 校验 client 的确认值, 失败时删除会话, 每个会话只能猜测一次密码
+    pub fn confirm(&mut self, client_key: &str, id: &str, proof: &str) -> Option<Arc<Session>> {
+        let stored = self.sessions.get_mut(id).filter(|stored| stored.client_key == client_key && !stored.confirmed)?;
+        if stored.session.verify_proof(Role::Client, proof) {
+            stored.confirmed = true;
+            return Some(stored.session.clone());
+        }
+        self.sessions.remove(id);
+        self.order.retain(|(_, order_id)| order_id != id);
+        None
+    }
+
+    /// 淘汰该 client 最早建立的会话
+    fn evict(&mut self, client_key: &str) {
+        if let Some(pos) = self.order.iter().position(|(key, _)| key == client_key) {
+            if let Some((_, expired)) = self.order.remove(pos) {
+                self.sessions.remove(&expired);
+            }
+        }
+    }
+
+    /// 只返回该 client 建立并已确认的会话, 其他 client 不能使用泄露的 session_id
+    pub fn get(&self, client_key: &str, id: &str) -> Option<Arc<Session>> {
+        self.sessions.get(id)
+            .filter(|stored| stored.client_key == client_key && stored.confirmed)
+            .map(|stored| stored.session.clone())
+    }
+}
+
+#[cfg(test)]
+mod test_crypto {
+    use crate::features::frame::FrameType;
+
+    use super::{
+        aad, auth_response, gen_challenge, gen_session_id, is_sealed, sealed_session_id, verify_auth_response,
+        KeyExchange, ReplayWindow, Role, Session, SessionStore, MAX_CLIENT_SESSIONS, MAX_OPEN_SESSIONS, MAX_SESSIONS,
+    };
+
+    fn session_pair(client_password: &str, server_password: &str) -> (Session, Session) {
+        let session_id = gen_session_id();
+        let client = KeyExchange::new(client_password);
+        let server = KeyExchange::new(server_password);
+        let client_public_key = client.public_key();
+        let server_public_key = server.public_key();
+        (
+            client.establish(Role::Client, &server_public_key, &session_id).unwrap(),
+            server.establish(Role::Server, &client_public_key, &session_id).unwrap(),
+        )
+    }
+
+    #[test]
+    fn test_seal_open() {
+        let (client, server) = session_pair("secret", "secret");
+        assert!(server.verify_proof(Role::Client, &client.proof(Role::Client)));
+        assert!(client.verify_proof(Role::Server, &server.proof(Role::Server)));
+        assert_ne!(client.proof(Role::Client), client.proof(Role::Server));
+        let request_aad = aad(7, FrameType::Command, 0);
+        let sealed = client.seal(&request_aad, b"{\"command\":{}}").unwrap();
+        assert!(is_sealed(&sealed));
+        assert_eq!(sealed_session_id(&sealed), Some(client.id()));
+        assert_eq!(server.open(&request_aad, &sealed).unwrap(), b"{\"command\":{}}");
+        // 重放与篡改均被拒绝
+        assert!(server.open(&request_aad, &sealed).is_err());
+        let sealed = client.seal(&request_aad, b"data").unwrap();
+        assert!(server.open(&aad(8, FrameType::Command, 0), &sealed).is_err());
+        // 同一方向的密钥不能用于反方向
+        let response_aad = aad(7, FrameType::Message, 1);
+        let sealed = server.seal(&response_aad, b"reply").unwrap();
+        assert!(server.open(&response_aad, &sealed).is_err());
+        assert_eq!(client.open(&response_aad, &sealed).unwrap(), b"reply");
+    }
+
+    #[test]
+    fn test_open_stream() {
+        let (client, server) = session_pair("secret", "secret");
+        let first_aad = aad(1, FrameType::Data, 0);
+        let second_aad = aad(2, FrameType::Data, 0);
+        let first = server.seal(&first_aad, b"first").unwrap();
+        let second = server.seal(&second_aad, b"second").unwrap();
+        // 不同响应流的读取顺序互不影响
+        let (mut first_counter, mut second_counter) = (0, 0);
+        assert_eq!(client.open_stream(&second_aad, &second, &mut second_counter).unwrap(), b"second");
+        assert_eq!(client.open_stream(&first_aad, &first, &mut first_counter).unwrap(), b"first");
+        assert!(client.open_stream(&first_aad, &first, &mut first_counter).is_err());
+        assert!(client.open_stream(&second_aad, &first, &mut second_counter).is_err());
+    }
+
+    #[test]
+    fn test_password_mismatch() {
+        let (client, server) = session_pair("secret", "other");
+        assert!(!server.verify_proof(Role::Client, &client.proof(Role::Client)));
+        assert!(!client.verify_proof(Role::Server, &server.proof(Role::Server)));
+        let request_aad = aad(1, FrameType::Command, 0);
+        let sealed = client.seal(&request_aad, b"data").unwrap();
+        assert!(server.open(&request_aad, &sealed).is_err());
+    }
+
+    #[test]
+    fn test_invalid_public_key() {
+        let session_id = gen_session_id();
+        // 单位元与非法编码的公钥会让共享密钥与密码无关, 必须拒绝
+        let identity = hex::encode([0u8; 32]);
+        assert!(KeyExchange::new("secret").establish(Role::Server, &identity, &session_id).is_err());
+        let invalid = hex::encode([0xffu8; 32]);
+        assert!(KeyExchange::new("secret").establish(Role::Server, &invalid, &session_id).is_err());
+    }
+
+    #[test]
+    fn test_auth_response() {
+        let challenge = gen_challenge();
+        let response = auth_response("secret", &challenge, "client");
+        assert!(verify_auth_response("secret", &challenge, "client", &response));
+        assert!(!verify_auth_response("other", &challenge, "client", &response));
+        assert!(!verify_auth_response("secret", &challenge, "client-2", &response));
+        assert!(!verify_auth_response("secret", &gen_challenge(), "client", &response));
+        assert!(!verify_auth_response("secret", &challenge, "client", "not hex"));
+    }
+
+    #[test]
+    fn test_replay_window() {
+        let mut window = ReplayWindow::default();
+        assert!(!window.accept(0));
+        assert!(window.accept(3));
+        assert!(window.accept(1));
+        assert!(!window.accept(1));
+        assert!(window.accept(100));
+        assert!(!window.accept(3));
+        assert!(window.accept(99));
+        assert!(!window.accept(99));
+    }
+
+    #[test]
+    fn test_session_store() {
+        let mut store = SessionStore::default();
+        let (client, server) = session_pair("secret", "secret");
+        let first_id = server.id();
+        store.insert("victim", server);
+        // 确认前不能用于加密命令, 只能由建立会话的 client 确认
+        assert!(store.get("victim", &first_id).is_none());
+        assert!(store.confirm("flood", &first_id, &client.proof(Role::Client)).is_none());
+        assert!(store.confirm("victim", &first_id, &client.proof(Role::Client)).is_some());
+        assert!(store.get("victim", &first_id).is_some());
+        // 已确认的会话不能再次确认, 其他 client 不能使用该会话
+        assert!(store.confirm("victim", &first_id, &client.proof(Role::Client)).is_none());
+        assert!(store.get("flood", &first_id).is_none());
+
+        // 确认值错误时删除会话, 不能再次猜测
+        let (client, server) = session_pair("guess", "secret");
+        let id = server.id();
+        store.insert("victim", server);
+        assert!(store.confirm("victim", &id, &client.proof(Role::Client)).is_none());
+        assert!(store.confirm("victim", &id, &session_pair("secret", "secret").0.proof(Role::Client)).is_none());
+
+        // 同一 client 只保留最近的会话
+        let ids: Vec<_> = (0..MAX_CLIENT_SESSIONS + 2).map(|_| {
+            let (client, server) = session_pair("secret", "secret");
+            let id = server.id();
+            store.insert("flood", server);
+            store.confirm("flood", &id, &client.proof(Role::Client));
+            id
+        }).collect();
+        assert!(store.get("flood", &ids[0]).is_none() && store.get("flood", &ids[1]).is_none());
+        assert!(ids[2..].iter().all(|id| store.get("flood", id).is_some()));
+        assert!(store.get("victim", &first_id).is_some());
+
+        // 会话表满时先淘汰会话最多的 client
+        for idx in 0..MAX_SESSIONS {
+            store.insert(&format!("client-{}", idx / MAX_CLIENT_SESSIONS), session_pair("secret", "secret").1);
+        }
+        assert!(store.get("victim", &first_id).is_some());
+
+        assert!((0..MAX_OPEN_SESSIONS).all(|_| store.allow_open("flood")));
+        assert!(!store.allow_open("flood"));
+        assert!(store.allow_open("victim"));
+    }
+}
@@ -0,0 +1,108 @@
+use std::fmt::Display;
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::common::CommomResult;
+
+/// 按优先级排列, server 选择双方都支持的第一个; json 保留用于调试
+pub const SUPPORTED_ENCODINGS: [Encoding; 3] = [Encoding::MessagePack, Encoding::Cbor, Encoding::Json];
+
+/// `ApiCommand` 与 `CommandMessage` 的编码方式, 在建立会话时协商, tunnel 不关心具体编码
+#[derive(Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Encoding {
+    #[default]
+    Json,
+    #[serde(rename = "msgpack")]
+    #[value(name = "msgpack")]
+    MessagePack,
+    Cbor,
+}
+
+impl Display for Encoding {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Json => write!(f, "json"),
+            Self::MessagePack => write!(f, "msgpack"),
+            Self::Cbor => write!(f, "cbor"),
+        }
+    }
+}
+
+impl Encoding {
+    /// 从对方声明的编码中选出本地支持且优先级最高的, 没有交集时使用 json
+    pub fn select(accepted: &[Encoding]) -> Encoding {
+        SUPPORTED_ENCODINGS.iter()
+            .find(|encoding| accepted.contains(encoding))
+            .copied()
+            .unwrap_or_default()
+    }
+
+    pub fn encode<T: Serialize>(&self, value: &T) -> CommomResult<Vec<u8>> {
+        let buf = match self {
+            Self::Json => serde_json::to_vec(value)?,
+            // note: 使用带字段名的格式, 新增带默认值的字段时保持兼容
+            Self::MessagePack => rmp_serde::to_vec_named(value)?,
+            Self::Cbor => {
+                let mut buf = vec![];
+                ciborium::into_writer(value, &mut buf)?;
+                buf
+            },
+        };
+        Ok(buf)
+    }
+
+    pub fn decode<T: DeserializeOwned>(&self, buf: &[u8]) -> CommomResult<T> {
+        let value = match self {
+            Self::Json => serde_json::from_slice(buf)?,
+            Self::MessagePack => rmp_serde::from_slice(buf)?,
+            Self::Cbor => ciborium::from_reader(buf)?,
+        };
+        Ok(value)
+    }
+}
+
+#[cfg(test)]
+mod test_encoding {
+    use crate::features::commands::{ApiCommand, Command, CommandData, CommandMessage, DirSort, FtPath, ItemFilter, ShareInfo, SortKey};
+
+    use super::{Encoding, SUPPORTED_ENCODINGS};
+
+    #[test]
+    fn test_encoding_roundtrip() {
+        let cmd = ApiCommand::new(Command::ReadDirItem {
+            dir_path: FtPath::new_relative("/".to_string(), "中文/dir".to_string()),
+            take_size: 10,
+            skip_size: 0,
+            sort: DirSort { key: SortKey::Modified, descending: true },
+            filter: ItemFilter { extensions: vec!["log".to_string()], ..Default::default() },
+            cursor: None,
+            with_xattrs: false,
+        });
+        let message = CommandMessage {
+            version: 1,
+            request_id: cmd.request_id,
+            status: 0,
+            data: CommandData::ReadConfig { share: ShareInfo { name: "共享".to_string(), ..Default::default() } },
+        };
+        for encoding in SUPPORTED_ENCODINGS {
+            let buf = encoding.encode(&cmd).unwrap();
+            let decoded: ApiCommand = encoding.decode(&buf).unwrap();
+            assert_eq!(decoded.request_id, cmd.request_id);
+            assert_eq!(format!("{:?}", decoded.command), format!("{:?}", cmd.command));
+
+            let buf = encoding.encode(&message).unwrap();
+            let decoded: CommandMessage = encoding.decode(&buf).unwrap();
+            assert!(matches!(decoded.data, CommandData::ReadConfig { share } if share.name == "共享"));
+        }
+        assert!(Encoding::MessagePack.encode(&cmd).unwrap().len() < Encoding::Json.encode(&cmd).unwrap().len());
+    }
+
+    #[test]
+    fn test_encoding_select() {
+        assert_eq!(Encoding::select(&[Encoding::Json, Encoding::Cbor]), Encoding::Cbor);
+        assert_eq!(Encoding::select(&[Encoding::Json]), Encoding::Json);
+        assert_eq!(Encoding::select(&[]), Encoding::Json);
+    }
+}
@@ -0,0 +1,324 @@
+use std::{error::Error, fmt::Display, io::Read};
+
+use super::codec::Codec;
+
+// frame 头格式(大端序), 详见 `features/tunnel/readme.md`:
+// | magic(2) | version(1) | frame_type(1) | flags(1) | reserved(3) | request_id(8) | client_key_len(2) | payload_len(4) |
+// 紧跟 client_key(utf-8) 与 payload
+pub const FRAME_MAGIC: [u8; 2] = *b"FT";
+pub const FRAME_VERSION: u8 = 2;
+pub const FRAME_HEADER_SIZE: usize = 22;
+/// 单个 frame 的最大字节数(含头部), 按头部声明的长度分配内存前先检查
+pub const MAX_FRAME_SIZE: usize = 64 << 20;
+
+pub const FLAG_END_OF_STREAM: u8 = 0b0000_0001;
+// bit1-2: payload 的压缩方式, 见 `Codec`
+pub const FLAG_CODEC_MASK: u8 = 0b0000_0110;
+const FLAG_CODEC_SHIFT: u8 = 1;
+
+/// 协商 flow_control 后, 每个请求在收到 WindowUpdate 前最多发送的 frame 数
+pub const FLOW_CONTROL_WINDOW: u32 = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameType {
+    Command = 1,
+    Message = 2,
+    Handshake = 3,
+    Data = 4,
+    // tunnel -> server, 取消进行中的请求, payload 为空
+    Cancel = 5,
+    // tunnel -> server, 增加请求可发送的 frame 数, payload 为 u32(大端序)
+    WindowUpdate = 6,
+}
+
+impl TryFrom<u8> for FrameType {
+    type Error = FrameError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            1 => Ok(Self::Command),
+            2 => Ok(Self::Message),
+            3 => Ok(Self::Handshake),
+            4 => Ok(Self::Data),
+            5 => Ok(Self::Cancel),
+            6 => Ok(Self::WindowUpdate),
+            _ => Err(FrameError::UnknownType(value)),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum FrameError {
+    TooShort(usize),
+    BadMagic,
+    UnsupportedVersion(u8),
+    UnknownType(u8),
+    UnknownCodec(u8),
+    KeyTooLong(usize),
+    TooLarge(usize),
+    LengthMismatch { expected: usize, actual: usize },
+    InvalidKey,
+    Io(String),
+}
+
+impl Display for FrameError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::TooShort(size) => write!(f, "frame too short: {size} bytes"),
+            Self::BadMagic => write!(f, "frame magic mismatch"),
+            Self::UnsupportedVersion(version) => write!(f, "unsupported frame version: {version}"),
+            Self::UnknownType(frame_type) => write!(f, "unknown frame type: {frame_type}"),
+            Self::UnknownCodec(codec) => write!(f, "unknown payload codec: {codec}"),
+            Self::KeyTooLong(size) => write!(f, "client key too long: {size} bytes"),
+            Self::TooLarge(size) => write!(f, "frame too large: {size} bytes, limit {MAX_FRAME_SIZE}"),
+            Self::LengthMismatch { expected, actual } => {
+                write!(f, "frame length mismatch, expected {expected} bytes, got {actual}")
+            }
+            Self::InvalidKey => write!(f, "client key is not valid utf-8"),
+            Self::Io(message) => write!(f, "frame read failed: {message}"),
+        }
+    }
+}
+
+impl Error for FrameError {}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    pub frame_type: FrameType,
+    pub end_of_stream: bool,
+    pub codec: Codec,
+    pub request_id: u64,
+    pub client_key: String,
+    pub payload: Vec<u8>,
+}
+
+impl Frame {
+    pub fn new(frame_type: FrameType, client_key: &str, request_id: u64, payload: Vec<u8>) -> Self {
+        Self {
+            frame_type,
+            end_of_stream: false,
+            codec: Codec::Identity,
+            request_id,
+            client_key: client_key.to_string(),
+            payload,
+        }
+    }
+
+    pub fn end_of_stream(mut self) -> Self {
+        self.end_of_stream = true;
+        self
+    }
+
+    pub fn window_update(client_key: &str, request_id: u64, credits: u32) -> Self {
+        Self::new(FrameType::WindowUpdate, client_key, request_id, credits.to_be_bytes().to_vec())
+    }
+
+    /// WindowUpdate frame 携带的 frame 数, 其他 frame 或格式错误时返回 None
+    pub fn window_credits(&self) -> Option<u32> {
+        if self.frame_type != FrameType::WindowUpdate {
+            return None;
+        }
+        self.payload.as_slice().try_into().ok().map(u32::from_be_bytes)
+    }
+
+    /// 标记 payload 已使用 codec 压缩
+    pub fn with_codec(mut self, codec: Codec) -> Self {
+        self.codec = codec;
+        self
+    }
+}
+
+impl Frame {
+    pub fn encode(&self) -> Result<Vec<u8>, FrameError> {
+        let client_key = self.client_key.as_bytes();
+        if client_key.len() > u16::MAX as usize {
+            return Err(FrameError::KeyTooLong(client_key.len()));
+        }
+        let size = FRAME_HEADER_SIZE + client_key.len() + self.payload.len();
+        if size > MAX_FRAME_SIZE {
+            return Err(FrameError::TooLarge(size));
+        }
+        let mut buf = Vec::with_capacity(size);
+        buf.extend_from_slice(&FRAME_MAGIC);
+        buf.push(FRAME_VERSION);
+        buf.push(self.frame_type as u8);
+        buf.push(self.flags());
+        buf.extend_from_slice(&[0u8; 3]);
+        buf.extend_from_slice(&self.request_id.to_be_bytes());
+        buf.extend_from_slice(&(client_key.len() as u16).to_be_bytes());
+        buf.extend_from_slice(&(self.payload.len() as u32).to_be_bytes());
+        buf.extend_from_slice(client_key);
+        buf.extend_from_slice(&self.payload);
+        Ok(buf)
+    }
+
+    pub fn flags(&self) -> u8 {
+        let mut flags = (self.codec as u8) << FLAG_CODEC_SHIFT;
+        if self.end_of_stream {
+            flags |= FLAG_END_OF_STREAM;
+        }
+        flags
+    }
+
+    /// 校验 frame 头，返回整个 frame 的字节数; 超过 `MAX_FRAME_SIZE` 时返回错误, 调用方不会按其分配内存
+    pub fn frame_size(header: &[u8]) -> Result<usize, FrameError> {
+        if header.len() < FRAME_HEADER_SIZE {
+            return Err(FrameError::TooShort(header.len()));
+        }
+        if header[..2] != FRAME_MAGIC {
+            return Err(FrameError::BadMagic);
+        }
+        if header[2] != FRAME_VERSION {
+            return Err(FrameError::UnsupportedVersion(header[2]));
+        }
+        FrameType::try_from(header[3])?;
+        let key_len = u16::from_be_bytes([header[16], header[17]]) as usize;
+        let payload_len = u32::from_be_bytes(header[18..22].try_into().unwrap()) as usize;
+        let size = FRAME_HEADER_SIZE + key_len + payload_len;
+        if size > MAX_FRAME_SIZE {
+            return Err(FrameError::TooLarge(size));
+        }
+        Ok(size)
+    }
+
+    /// 从连续的 frame 流中读取一个 frame, 流正常结束时返回 None
+    pub fn read_from<R: Read>(reader: &mut R) -> Result<Option<Self>, FrameError> {
+        let mut header = [0u8; FRAME_HEADER_SIZE];
+        let mut read_size = 0;
+        while read_size < FRAME_HEADER_SIZE {
+            match reader.read(&mut header[read_size..]) {
+                Ok(0) if read_size == 0 => return Ok(None),
+                Ok(0) => return Err(FrameError::TooShort(read_size)),
+                Ok(size) => read_size += size,
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {},
+                Err(e) => return Err(FrameError::Io(e.to_string())),
+            }
+        }
+        let mut buf = vec![0u8; Self::frame_size(&header)?];
+        buf[..FRAME_HEADER_SIZE].copy_from_slice(&header);
+        reader.read_exact(&mut buf[FRAME_HEADER_SIZE..]).map_err(|e| FrameError::Io(e.to_string()))?;
+        Self::decode(&buf).map(Some)
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<Self, FrameError> {
+        let expected = Self::frame_size(buf)?;
+        let frame_type = FrameType::try_from(buf[3])?;
+        let end_of_stream = buf[4] & FLAG_END_OF_STREAM != 0;
+        let codec = (buf[4] & FLAG_CODEC_MASK) >> FLAG_CODEC_SHIFT;
+        let codec = Codec::try_from(codec).map_err(FrameError::UnknownCodec)?;
+        let request_id = u64::from_be_bytes(buf[8..16].try_into().unwrap());
+        let key_len = u16::from_be_bytes([buf[16], buf[17]]) as usize;
+        if buf.len() != expected {
+            return Err(FrameError::LengthMismatch { expected, actual: buf.len() });
+        }
+        let key_end = FRAME_HEADER_SIZE + key_len;
+        let client_key = String::from_utf8(buf[FRAME_HEADER_SIZE..key_end].to_vec())
+            .map_err(|_| FrameError::InvalidKey)?;
+        Ok(Self {
+            frame_type,
+            end_of_stream,
+            codec,
+            request_id,
+            client_key,
+            payload: buf[key_end..].to_vec(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test_frame {
+    use crate::features::codec::Codec;
+
+    use super::{Frame, FrameError, FrameType, FRAME_HEADER_SIZE, MAX_FRAME_SIZE};
+
+    #[test]
+    fn test_frame_roundtrip() {
+        let cases: Vec<Frame> = vec![
+            Frame::new(FrameType::Command, "client-key", 1, b"{}".to_vec()),
+            Frame::new(FrameType::Message, "", 0, vec![]).end_of_stream(),
+            Frame::new(FrameType::Message, "客户端", u64::MAX, vec![0u8; 4]).end_of_stream(),
+            Frame::new(FrameType::Message, &"k".repeat(300), 42, vec![1, 0, 0, 0, 0]),
+            Frame::new(FrameType::Data, "key", 3, vec![1, 2, 3]).with_codec(Codec::Zstd).end_of_stream(),
+            Frame::new(FrameType::Cancel, "key", 3, vec![]).end_of_stream(),
+            Frame::window_update("key", 3, 8),
+        ];
+        for frame in cases {
+            let buf = frame.encode().unwrap();
+            assert_eq!(buf.len(), FRAME_HEADER_SIZE + frame.client_key.len() + frame.payload.len());
+            assert_eq!(Frame::decode(&buf).unwrap(), frame);
+        }
+    }
+
+    #[test]
+    fn test_frame_window_credits() {
+        assert_eq!(Frame::window_update("key", 3, 8).window_credits(), Some(8));
+        assert_eq!(Frame::new(FrameType::WindowUpdate, "key", 3, vec![1]).window_credits(), None);
+        assert_eq!(Frame::new(FrameType::Data, "key", 3, vec![0, 0, 0, 8]).window_credits(), None);
+    }
+
+    #[test]
+    fn test_frame_decode_error() {
+        let buf = Frame::new(FrameType::Message, "key", 7, b"data".to_vec()).encode().unwrap();
+        assert_eq!(Frame::decode(&buf[..4]), Err(FrameError::TooShort(4)));
+        assert!(matches!(Frame::decode(&buf[..buf.len() - 1]), Err(FrameError::LengthMismatch { .. })));
+
+        let mut bad = buf.clone();
+        bad[0] = b'X';
+        assert_eq!(Frame::decode(&bad), Err(FrameError::BadMagic));
+
+        let mut bad = buf.clone();
+        bad[2] = 0xff;
+        assert_eq!(Frame::decode(&bad), Err(FrameError::UnsupportedVersion(0xff)));
+
+        let mut bad = buf.clone();
+        bad[3] = 0xff;
+        assert_eq!(Frame::decode(&bad), Err(FrameError::UnknownType(0xff)));
+
+        let mut bad = buf;
+        bad[4] = 0b0000_0110;
+        assert_eq!(Frame::decode(&bad), Err(FrameError::UnknownCodec(3)));
+    }
+
+    #[test]
+    fn test_frame_read_from() {
+        let frames = [
+            Frame::new(FrameType::Message, "key", 7, b"{}".to_vec()),
+            Frame::new(FrameType::Data, "key", 7, vec![0u8; 1 << 16]),
+            Frame::new(FrameType::Message, "key", 7, vec![]).end_of_stream(),
+        ];
+        let mut buf: Vec<u8> = vec![];
+        for frame in frames.iter() {
+            buf.extend(frame.encode().unwrap());
+        }
+        let mut reader = buf.as_slice();
+        for frame in frames.iter() {
+            assert_eq!(Frame::read_from(&mut reader).unwrap().as_ref(), Some(frame));
+        }
+        assert_eq!(Frame::read_from(&mut reader), Ok(None));
+
+        let mut reader = &buf[..10];
+        assert_eq!(Frame::read_from(&mut reader), Err(FrameError::TooShort(10)));
+        let first_size = frames[0].encode().unwrap().len();
+        let mut reader = &buf[..(first_size + FRAME_HEADER_SIZE + 10)];
+        assert_eq!(Frame::read_from(&mut reader).unwrap().as_ref(), Some(&frames[0]));
+        assert!(matches!(Frame::read_from(&mut reader), Err(FrameError::Io(_))));
+    }
+
+    #[test]
+    fn test_frame_key_too_long() {
+        let frame = Frame::new(FrameType::Command, &"k".repeat(u16::MAX as usize + 1), 1, vec![]);
+        assert!(matches!(frame.encode(), Err(FrameError::KeyTooLong(_))));
+    }
+
+    #[test]
+    fn test_frame_too_large() {
+        // 头部声明的长度超出上限时直接拒绝, 不等待读取 payload
+        let mut header = Frame::new(FrameType::Message, "key", 1, vec![]).encode().unwrap();
+        header[18..22].copy_from_slice(&u32::MAX.to_be_bytes());
+        assert!(matches!(Frame::decode(&header), Err(FrameError::TooLarge(_))));
+        assert!(matches!(Frame::read_from(&mut &header[..]), Err(FrameError::TooLarge(_))));
+
+        let frame = Frame::new(FrameType::Message, "key", 1, vec![0u8; MAX_FRAME_SIZE]);
+        assert!(matches!(frame.encode(), Err(FrameError::TooLarge(_))));
+    }
+}
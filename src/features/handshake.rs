@@ -0,0 +1,135 @@
+use serde::{Deserialize, Serialize};
+
+pub const PROTOCOL_VERSION_MIN: u16 = 1;
+pub const PROTOCOL_VERSION_MAX: u16 = 1;
+
+pub const FEATURE_REQUEST_ID: &str = "request_id";
+pub const FEATURE_E2E_ENCRYPTION: &str = "e2e_encryption";
+pub const FEATURE_FLOW_CONTROL: &str = "flow_control";
+
+pub const SUPPORTED_FEATURES: [&str; 3] = [
+    FEATURE_REQUEST_ID,
+    FEATURE_E2E_ENCRYPTION,
+    FEATURE_FLOW_CONTROL,
+];
+
+/// 必需的功能, 本端支持而对端未声明时拒绝握手
+pub const REQUIRED_FEATURES: [&str; 2] = [
+    FEATURE_REQUEST_ID,
+    FEATURE_E2E_ENCRYPTION,
+];
+
+#[derive(Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Handshake {
+    pub min_version: u16,
+    pub max_version: u16,
+    pub features: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[derive(Debug, Clone)]
+pub enum HandshakeResult {
+    Accepted {
+        version: u16,
+        features: Vec<String>,
+    },
+    Rejected {
+        message: String,
+    },
+}
+
+impl Handshake {
+    pub fn local() -> Self {
+        Self {
+            min_version: PROTOCOL_VERSION_MIN,
+            max_version: PROTOCOL_VERSION_MAX,
+            features: SUPPORTED_FEATURES.iter().map(|f| f.to_string()).collect(),
+        }
+    }
+
+    /// 取双方都支持的版本区间与功能，无交集或对端缺少必需的功能时返回错误信息
+    pub fn negotiate(&self, peer: &Handshake) -> Result<Handshake, String> {
+        let min_version = self.min_version.max(peer.min_version);
+        let max_version = self.max_version.min(peer.max_version);
+        if min_version > max_version {
+            return Err(format!(
+                "protocol version mismatch, local: {}-{}, peer: {}-{}",
+                self.min_version, self.max_version, peer.min_version, peer.max_version
+            ));
+        }
+        let features: Vec<String> = self.features.iter()
+            .filter(|f| peer.features.contains(f))
+            .cloned()
+            .collect();
+        let missing = REQUIRED_FEATURES.iter()
+            .find(|required| self.has_feature(required) && !features.iter().any(|f| f == *required));
+        if let Some(missing) = missing {
+            return Err(format!("peer does not support required feature: {missing}"));
+        }
+        Ok(Self { min_version, max_version, features })
+    }
+
+    /// 协商后使用双方都支持的最高版本
+    pub fn version(&self) -> u16 {
+        self.max_version
+    }
+
+    pub fn has_feature(&self, feature: &str) -> bool {
+        self.features.iter().any(|f| f == feature)
+    }
+
+    pub fn supports(&self, version: u16) -> bool {
+        (self.min_version..=self.max_version).contains(&version)
+    }
+}
+
+impl From<&Handshake> for HandshakeResult {
+    fn from(value: &Handshake) -> Self {
+        Self::Accepted {
+            version: value.version(),
+            features: value.features.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_handshake {
+    use super::{Handshake, FEATURE_E2E_ENCRYPTION, FEATURE_REQUEST_ID};
+
+    fn handshake(min_version: u16, max_version: u16, features: &[&str]) -> Handshake {
+        Handshake {
+            min_version,
+            max_version,
+            features: features.iter().map(|f| f.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn test_negotiate() {
+        let local = handshake(1, 3, &["a", "b"]);
+        let agreed = local.negotiate(&handshake(2, 5, &["b", "c"])).unwrap();
+        assert_eq!(agreed, handshake(2, 3, &["b"]));
+        assert_eq!(agreed.version(), 3);
+        assert!(agreed.has_feature("b"));
+        assert!(!agreed.has_feature("a"));
+        assert!(agreed.supports(2));
+        assert!(!agreed.supports(1));
+    }
+
+    #[test]
+    fn test_negotiate_mismatch() {
+        let local = handshake(1, 1, &[]);
+        assert!(local.negotiate(&handshake(2, 3, &[])).is_err());
+        assert!(local.negotiate(&local.clone()).is_ok());
+    }
+
+    #[test]
+    fn test_negotiate_required_feature() {
+        let local = Handshake::local();
+        assert!(local.negotiate(&handshake(1, 1, &[])).is_err());
+        assert!(local.negotiate(&handshake(1, 1, &[FEATURE_REQUEST_ID])).is_err());
+        let agreed = local.negotiate(&handshake(1, 1, &[FEATURE_REQUEST_ID, FEATURE_E2E_ENCRYPTION])).unwrap();
+        assert!(agreed.has_feature(FEATURE_REQUEST_ID) && agreed.has_feature(FEATURE_E2E_ENCRYPTION));
+    }
+}
@@ -1,4 +1,10 @@
 pub mod server;
 pub mod tunnel;
 pub mod client;
-pub mod commands;
\ No newline at end of file
+pub mod commands;
+pub mod frame;
+pub mod handshake;
+pub mod codec;
+pub mod crypto;
+pub mod encoding;
+pub mod xattr;
\ No newline at end of file
@@ -0,0 +1,79 @@
+use std::{collections::HashMap, time::{Duration, Instant}};
+
+use crate::features::crypto;
+
+/// 登录挑战的有效期, 且只能使用一次
+const CHALLENGE_EXPIRE: Duration = Duration::from_secs(60);
+/// 登录结果的有效期, 过期后 client 需要重新登录
+const AUTH_EXPIRE: Duration = Duration::from_secs(12 * 60 * 60);
+
+/// 记录登录挑战与按 client_key 的登录结果;
+/// 挑战以自身为 key, 同一 client 可以并发登录
+#[derive(Default)]
+pub struct Auth {
+    challenges: HashMap<String, (String, Instant)>,
+    clients: HashMap<String, Instant>,
+}
+
+impl Auth {
+    pub fn challenge(&mut self, client_key: &str) -> String {
+        let now = Instant::now();
+        self.challenges.retain(|_, (_, created_at)| now.duration_since(*created_at) < CHALLENGE_EXPIRE);
+        let challenge = crypto::gen_challenge();
+        self.challenges.insert(challenge.clone(), (client_key.to_string(), now));
+        challenge
+    }
+
+    pub fn verify(&mut self, client_key: &str, password: &str, challenge: &str, response: &str) -> bool {
+        let authenticated = match self.challenges.remove(challenge) {
+            Some((challenge_client_key, created_at)) => {
+                challenge_client_key == client_key
+                    && created_at.elapsed() < CHALLENGE_EXPIRE
+                    && crypto::verify_auth_response(password, challenge, client_key, response)
+            },
+            None => false,
+        };
+        if authenticated {
+            self.clients.insert(client_key.to_string(), Instant::now());
+        }
+        authenticated
+    }
+
+    pub fn is_authenticated(&self, client_key: &str) -> bool {
+        self.clients.get(client_key)
+            .is_some_and(|authenticated_at| authenticated_at.elapsed() < AUTH_EXPIRE)
+    }
+}
+
+#[cfg(test)]
+mod test_auth {
+    use crate::features::crypto::auth_response;
+
+    use super::Auth;
+
+    #[test]
+    fn test_auth() {
+        let mut auth = Auth::default();
+        assert!(!auth.is_authenticated("client"));
+
+        let challenge = auth.challenge("client");
+        let response = auth_response("secret", &challenge, "client");
+        assert!(auth.verify("client", "secret", &challenge, &response));
+        assert!(auth.is_authenticated("client"));
+        assert!(!auth.is_authenticated("other"));
+
+        // 挑战只能使用一次, 且只属于请求它的 client
+        assert!(!auth.verify("client", "secret", &challenge, &response));
+        let challenge = auth.challenge("client");
+        let response = auth_response("secret", &challenge, "other");
+        assert!(!auth.verify("other", "secret", &challenge, &response));
+        assert!(!auth.is_authenticated("other"));
+
+        // 同一 client 并发登录
+        let first = auth.challenge("other");
+        let second = auth.challenge("other");
+        assert!(!auth.verify("other", "secret", &second, &auth_response("wrong", &second, "other")));
+        assert!(auth.verify("other", "secret", &first, &auth_response("secret", &first, "other")));
+        assert!(auth.is_authenticated("other"));
+    }
+}
@@ -0,0 +1,257 @@
+use std::{collections::HashMap, fs::{self, Metadata}, io::{self, Read as _}, path::Path, sync::{Arc, Mutex}, thread, time::{Duration, Instant, UNIX_EPOCH}};
+
+use sha2::{Digest, Sha256};
+use sqlite::State;
+
+use crate::{common::config::{Config, CFG_PATH}, features::commands::{ApiCommand, CommandData, CommandMessage, FtPath}};
+
+use super::{command_handler::{io_error, regular_file, HandlerResult}, responder::Responder, search::walk, ShareConfig};
+
+/// 预热完成后, 间隔该时间再次扫描共享目录
+const WARM_INTERVAL: Duration = Duration::from_secs(60 * 60);
+/// 预热时每秒最多读取的字节数, 避免占满 server 的磁盘 IO
+const WARM_READ_RATE: u64 = 32 << 20;
+/// 预热遍历时每访问该数量的条目暂停一次
+const WARM_WALK_BATCH: usize = 1000;
+const WARM_WALK_PAUSE: Duration = Duration::from_millis(20);
+const READ_BUFFER_SIZE: usize = 1 << 20;
+
+/// 文件 sha256 缓存, 保存在 server.db 中; 路径、大小或修改时间变化后缓存失效
+pub struct ChksumCache {
+    /// 与 server 共用同一个数据库连接
+    config: Arc<Mutex<Config>>,
+}
+
+impl ChksumCache {
+    pub fn new(config: Arc<Mutex<Config>>) -> sqlite::Result<Self> {
+        config.lock().unwrap().connection().execute(r#"
+            create table if not exists chksum_cache (
+                path TEXT NOT NULL PRIMARY KEY,
+                size INT NOT NULL,
+                modified INT NOT NULL,
+                chksum char(64) NOT NULL
+            );
+        "#)?;
+        Ok(Self { config })
+    }
+
+    /// 测试使用的缓存, 数据库位于 dir 下
+    #[cfg(test)]
+    pub fn open_in(dir: &Path) -> Self {
+        use crate::common::config::{FILE_TUNNEL_CFG_SERVER, FILE_TUNNEL_ENDPOINT_SERVER};
+        let mut config = Config::new(
+            Some(dir.to_string_lossy().to_string()),
+            Some(FILE_TUNNEL_CFG_SERVER.to_string()),
+            Some(FILE_TUNNEL_ENDPOINT_SERVER.to_string()),
+        );
+        config.init();
+        Self::new(Arc::new(Mutex::new(config))).unwrap()
+    }
+
+    pub fn get(&self, path: &Path, meta: &Metadata) -> Option<String> {
+        let config = self.config.lock().unwrap();
+        let query = "select chksum from chksum_cache where path = ? and size = ? and modified = ?";
+        let mut stat = config.connection().prepare(query).ok()?;
+        stat.bind((1, path.to_string_lossy().as_ref())).ok()?;
+        stat.bind((2, meta.len() as i64)).ok()?;
+        stat.bind((3, modified_nanos(meta))).ok()?;
+        match stat.next() {
+            Ok(State::Row) => stat.read::<String, _>("chksum").ok(),
+            _ => None,
+        }
+    }
+
+    pub fn put(&self, path: &Path, meta: &Metadata, chksum: &str) {
+        let config = self.config.lock().unwrap();
+        let query = "insert or replace into chksum_cache (path, size, modified, chksum) values (?, ?, ?, ?)";
+        let result = config.connection().prepare(query).and_then(|mut stat| {
+            stat.bind((1, path.to_string_lossy().as_ref()))?;
+            stat.bind((2, meta.len() as i64))?;
+            stat.bind((3, modified_nanos(meta)))?;
+            stat.bind((4, chksum))?;
+            stat.next().map(|_| ())
+        });
+        if let Err(e) = result {
+            eprintln!("cache chksum of {} failed, err: {e}", path.display());
+        }
+    }
+
+    /// 所有缓存的 path -> (size, modified)
+    fn entries(&self) -> HashMap<String, (i64, i64)> {
+        let config = self.config.lock().unwrap();
+        let mut entries = HashMap::new();
+        if let Ok(mut stat) = config.connection().prepare("select path, size, modified from chksum_cache") {
+            while let Ok(State::Row) = stat.next() {
+                if let (Ok(path), Ok(size), Ok(modified)) = (stat.read::<String, _>("path"), stat.read::<i64, _>("size"), stat.read::<i64, _>("modified")) {
+                    entries.insert(path, (size, modified));
+                }
+            }
+        }
+        entries
+    }
+
+    /// 删除文件已不存在或不在共享目录下的缓存
+    fn prune<'a>(&self, root_path: &str, paths: impl Iterator<Item = &'a String>) {
+        let stale: Vec<&String> = paths
+            .filter(|path| !Path::new(path).starts_with(root_path) || !Path::new(path).is_file())
+            .collect();
+        let config = self.config.lock().unwrap();
+        for path in stale {
+            let _ = config.connection().prepare("delete from chksum_cache where path = ?")
+                .and_then(|mut stat| stat.bind((1, path.as_str())).and_then(|_| stat.next()));
+        }
+    }
+
+    /// 返回 (chksum, cached), 未命中时计算并缓存; progress 返回 false 时停止计算
+    pub fn chksum(&self, path: &Path, progress: impl FnMut(u64) -> bool) -> io::Result<(String, bool)> {
+        let meta = fs::metadata(path)?;
+        if let Some(chksum) = self.get(path, &meta) {
+            return Ok((chksum, true));
+        }
+        let chksum = digest(path, progress)?;
+        // note: 计算期间文件被修改时结果不可靠, 不缓存
+        if fs::metadata(path).is_ok_and(|after| after.len() == meta.len() && modified_nanos(&after) == modified_nanos(&meta)) {
+            self.put(path, &meta, &chksum);
+        }
+        Ok((chksum, false))
+    }
+
+    /// 为共享目录下未缓存的文件计算校验和, 并清理失效的缓存; 读取速度与遍历都有限制, 不影响正常的请求
+    pub fn warm(&self, root_path: &str) {
+        // note: 一次读出所有缓存, 大小与修改时间未变化的文件不再查询数据库
+        let cached = self.entries();
+        let (mut hashed, mut visited, started_at) = (0, 0, Instant::now());
+        let mut read_size = 0;
+        walk(root_path, Path::new(root_path), |path, meta| {
+            visited += 1;
+            if visited % WARM_WALK_BATCH == 0 {
+                thread::sleep(WARM_WALK_PAUSE);
+            }
+            let unchanged = cached.get(path.to_string_lossy().as_ref()) == Some(&(meta.len() as i64, modified_nanos(meta)));
+            if !meta.is_file() || unchanged {
+                return true;
+            }
+            let file_start = read_size;
+            let throttle = |hashed_size| {
+                let expect = Duration::from_secs_f64((file_start + hashed_size) as f64 / WARM_READ_RATE as f64);
+                if let Some(ahead) = expect.checked_sub(started_at.elapsed()) {
+                    thread::sleep(ahead);
+                }
+                true
+            };
+            match self.chksum(path, throttle) {
+                Ok(_) => hashed += 1,
+                Err(e) => eprintln!("warm chksum of {} failed, err: {e}", path.display()),
+            }
+            read_size += meta.len();
+            true
+        });
+        self.prune(root_path, cached.keys());
+        if hashed > 0 {
+            println!("chksum cache warmed, {hashed} files hashed in {:?}", started_at.elapsed());
+        }
+    }
+}
+
+fn modified_nanos(meta: &Metadata) -> i64 {
+    meta.modified().ok()
+        .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |d| d.as_nanos() as i64)
+}
+
+/// 计算文件 sha256, 每读取一块调用一次 progress(已读取的字节数)
+fn digest(path: &Path, mut progress: impl FnMut(u64) -> bool) -> io::Result<String> {
+    let mut file = fs::File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0u8; READ_BUFFER_SIZE];
+    let mut hashed_size = 0;
+    loop {
+        let size = file.read(&mut buffer)?;
+        if size == 0 {
+            break ;
+        }
+        hasher.update(&buffer[..size]);
+        hashed_size += size as u64;
+        if !progress(hashed_size) {
+            return Err(io::Error::new(io::ErrorKind::Interrupted, "cancelled"));
+        }
+    }
+    Ok(hex::encode(hasher.finalize()))
+}
+
+/// 在后台定期预热缓存, 每轮按 server 当前的配置读取共享目录
+pub fn spawn_warm(cache: Arc<ChksumCache>) {
+    thread::spawn(move || loop {
+        let root_path = cache.config.lock().unwrap().get_key(CFG_PATH.to_string());
+        if let Some(root_path) = root_path {
+            cache.warm(&root_path);
+        }
+        thread::sleep(WARM_INTERVAL);
+    });
+}
+
+pub fn checksum(share: &ShareConfig, cmd: &ApiCommand, file_path: &FtPath, cache: &ChksumCache, responder: &Responder) -> HandlerResult<CommandData> {
+    let (path, meta) = regular_file(share, file_path)?;
+    let full_path = path.full_path();
+    // note: 计算期间定期发送进度, 避免 tunnel 等待超时
+    let progress = |hashed_size| {
+        responder.message_or_keepalive(false, || CommandMessage {
+            version: cmd.version,
+            request_id: cmd.request_id,
+            status: 0,
+            data: CommandData::ChecksumProgress { hashed_size, file_size: meta.len() },
+        });
+        !responder.is_cancelled()
+    };
+    let (chksum, cached) = cache.chksum(Path::new(&full_path), progress).map_err(|e| io_error(e, &path))?;
+    Ok(CommandData::Checksum { chksum, cached })
+}
+
+#[cfg(test)]
+mod test_chksum {
+    use std::{fs, time::{Duration, SystemTime}};
+
+    use sha2::{Digest, Sha256};
+
+    use crate::common::test_util::TempDir;
+
+    use super::ChksumCache;
+
+    #[test]
+    fn test_chksum_cache() {
+        let root = TempDir::new("chksum");
+        let db_dir = TempDir::new("chksum-db");
+        let file = root.join("a.txt");
+        fs::write(&file, "hello").unwrap();
+        let cache = ChksumCache::open_in(&db_dir);
+        let hello = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+        let set_modified = |path, modified| fs::File::options().write(true).open(path).unwrap().set_modified(modified).unwrap();
+        let modified = SystemTime::now() - Duration::from_secs(60);
+        set_modified(&file, modified);
+
+        assert_eq!(cache.chksum(&file, |_| true).unwrap(), (hello.to_string(), false));
+        assert_eq!(cache.chksum(&file, |_| true).unwrap(), (hello.to_string(), true));
+        // 修改时间变化后缓存失效
+        set_modified(&file, modified + Duration::from_secs(1));
+        assert_eq!(cache.chksum(&file, |_| true).unwrap(), (hello.to_string(), false));
+        assert!(cache.chksum(&root.join("missing"), |_| true).is_err());
+
+        fs::write(root.join("b.txt"), "b").unwrap();
+        cache.warm(root.to_str());
+        let b_meta = fs::metadata(root.join("b.txt")).unwrap();
+        assert!(cache.get(&root.join("b.txt"), &b_meta).is_some());
+        // 大小与修改时间都未变化的文件不再计算
+        fs::write(root.join("b.txt"), "c").unwrap();
+        set_modified(&root.join("b.txt"), b_meta.modified().unwrap());
+        cache.warm(root.to_str());
+        let (chksum, cached) = cache.chksum(&root.join("b.txt"), |_| true).unwrap();
+        assert!(cached);
+        assert_eq!(chksum, hex::encode(Sha256::digest("b")));
+
+        // 目录删除后清理缓存
+        let root_path = root.to_str().to_string();
+        drop(root);
+        cache.warm(&root_path);
+        assert!(cache.entries().is_empty());
+    }
+}
@@ -1,5 +1,9 @@
 use clap::{Parser, Subcommand};
 
+use crate::features::commands::SymlinkPolicy;
+
+use super::file_ops::FILE_OPS;
+
 #[derive(Parser, Debug)]
 #[command(author, version, about)]
 pub struct Cli {
@@ -18,6 +22,22 @@ pub enum Commands {
 
         #[arg(long)]
         password: Option<String>,
+
+        /// 共享名称, 默认为共享目录名
+        #[arg(long)]
+        name: Option<String>,
+
+        /// 允许 client 修改共享目录, 默认只读
+        #[arg(long, default_value_t=false)]
+        writable: bool,
+
+        /// 可写时允许的文件操作, 多个以 `,` 分隔, 例如 `--allow mkdir,rename`
+        #[arg(long, value_delimiter=',', value_parser=FILE_OPS.map(|(op, _)| op))]
+        allow: Vec<String>,
+
+        /// 符号链接的处理方式: 按目标返回、作为链接返回(client 在本地重建)或不返回
+        #[arg(long, default_value="follow")]
+        symlinks: SymlinkPolicy,
     },
     ShowConfig {
         #[arg(long)]
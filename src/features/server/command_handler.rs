@@ -1,177 +1,708 @@
-use std::{cmp::min, fs, io::{Read as _, Seek as _, SeekFrom}};
+use std::{cmp::{min, Ordering}, fs::{self, Metadata}, io::{self, Read as _, Seek as _, SeekFrom}, path::{Component, Path, PathBuf}, sync::{Arc, Mutex}, time::UNIX_EPOCH};
 
-use crate::features::commands::{self, ApiCommand, CommandData, CommandMessage, DirItem};
-use websocket::{Message, OwnedMessage};
-use std::sync::mpsc::Sender;
+use crate::features::{
+    codec::{is_compressed_file, SUPPORTED_CODECS},
+    commands::{self, ApiCommand, CommandData, CommandMessage, DirItem, DirSort, ErrorCode, FtPath, ItemFilter, ShareInfo, SortKey, SymlinkPolicy},
+};
 
-use super::{build_item, dir_iter};
+use super::{auth::Auth, build_item, chksum::{self, ChksumCache}, snapshot::{self, Snapshot, Snapshots}, file_ops, grep, responder::{Responder, DATA_FRAME_SIZE}, search::{self, EntryFilter}, tree, upload, watcher::{self, WatchHub}, ShareConfig};
 
-pub fn handler(tx1: &Sender<OwnedMessage>, root_path: &str, client_key: &str, cmd: &ApiCommand) {
+/// server 可以处理的命令, 通过 `ReadConfig` 告知 client
+const SERVED_COMMANDS: [&str; 16] = [
+    "ReadConfig",
+    "ReadDirItem",
+    "ReadFileInfo",
+    "ReadPathInfo",
+    "ReadTree",
+    "DownloadFile",
+    "Checksum",
+    "Search",
+    "Grep",
+    "Watch",
+    "UploadStatus",
+    "UploadFile",
+    "MakeDir",
+    "Rename",
+    "Copy",
+    "Delete",
+];
+
+/// 单次列目录最多返回的条目数, 避免单个 Message frame 过大
+const MAX_TAKE_SIZE: usize = 1000;
+/// 单次下载的最大块大小
+const MAX_BLOCK_SIZE: usize = 1 << 30;
+
+/// 命令处理失败时返回的 (code, message), 以 `CommandData::Error` 响应
+pub(super) type HandlerError = (ErrorCode, String);
+pub(super) type HandlerResult<T> = Result<T, HandlerError>;
+
+pub fn handler(share: &ShareConfig, cmd: &ApiCommand, responder: &Responder, auth: &Mutex<Auth>, snapshots: &Mutex<Snapshots>, chksums: &ChksumCache, watches: &Arc<WatchHub>) {
+    let client_key = responder.client_key();
+    let is_auth_command = matches!(cmd.command, commands::Command::AuthChallenge {} | commands::Command::AuthResponse { .. });
+    let result = if !is_auth_command && !auth.lock().unwrap().is_authenticated(client_key) {
+        Err((ErrorCode::Unauthorized, "authentication required".to_string()))
+    } else if let commands::Command::DownloadFile { file_path, block_idx, block_size } = &cmd.command {
+        // note: 下载在发送数据块的同时返回, 成功时不再发送 Message
+        match download_file(share, cmd, file_path, *block_idx, *block_size, responder) {
+            Ok(()) => return ,
+            Err(e) => Err(e),
+        }
+    } else {
+        command_data(share, cmd, responder, auth, snapshots, chksums, watches)
+    };
+    let message = match result {
+        Ok(data) => CommandMessage {
+            version: cmd.version,
+            request_id: cmd.request_id,
+            status: 0,
+            data,
+        },
+        Err((code, message)) => {
+            println!("{} failed, {code}: {message}, client: {client_key}", cmd.command.name());
+            CommandMessage::error(cmd.version, cmd.request_id, code, message)
+        },
+    };
+    responder.message(&message);
+}
+
+fn command_data(share: &ShareConfig, cmd: &ApiCommand, responder: &Responder, auth: &Mutex<Auth>, snapshots: &Mutex<Snapshots>, chksums: &ChksumCache, watches: &Arc<WatchHub>) -> HandlerResult<CommandData> {
+    let client_key = responder.client_key();
     match &cmd.command {
-        commands::Command::ReadConfig {} => {
-            let data = CommandMessage {
-                version: cmd.version,
-                status: 0,
-                data: CommandData::ReadConfig {
-                    path: "/".to_string(),
-                },
+        commands::Command::AuthChallenge {} => {
+            Ok(CommandData::AuthChallenge {
+                challenge: auth.lock().unwrap().challenge(client_key),
+            })
+        }
+        commands::Command::AuthResponse { challenge, response } => {
+            let authenticated = match share.password.as_deref() {
+                Some(password) => auth.lock().unwrap().verify(client_key, password, challenge, response),
+                None => false,
             };
-            let msg = format!(
-                "{}:{}{}",
-                &client_key.len(),
-                &client_key,
-                serde_json::to_string(&data).unwrap()
-            );
-            let _ = tx1.send(OwnedMessage::Text(msg));
+            if !authenticated {
+                return Err((ErrorCode::Unauthorized, "password mismatch".to_string()));
+            }
+            Ok(CommandData::AuthResponse { authenticated })
+        }
+        commands::Command::ReadConfig { with_stats } => {
+            Ok(CommandData::ReadConfig {
+                share: share_info(share, cmd.version, *with_stats, responder),
+            })
         }
         commands::Command::ReadDirItem {
             dir_path,
             take_size,
             skip_size,
+            sort,
+            filter,
+            cursor,
+            with_xattrs,
         } => {
-            let mut dir_path = dir_path.clone();
-            let org_root_path = dir_path.root_path().clone();
-            dir_path.reset_root(&root_path);
-            let total = dir_iter(&dir_path).count();
-            let dir_items: Vec<DirItem> = dir_iter(&dir_path)
-                .skip(*skip_size)
-                .take(*take_size)
-                .map(|dir| {
-                    build_item(
-                        dir.unwrap().path().to_str().unwrap(),
-                        &root_path,
-                        &org_root_path,
-                    )
-                })
-                .collect();
-            let data = CommandMessage {
-                version: cmd.version,
-                status: 0,
-                data: CommandData::ReadDirItem {
-                    items: dir_items,
-                    total: total,
-                    taked_size: min(*take_size + *skip_size, total),
-                },
+            let query = ListQuery {
+                dir_path,
+                take_size: *take_size,
+                skip_size: *skip_size,
+                sort: *sort,
+                filter,
+                cursor: cursor.as_deref(),
+                with_xattrs: *with_xattrs,
             };
-            let msg = format!(
-                "{}:{}{}",
-                &client_key.len(),
-                &client_key,
-                serde_json::to_string(&data).unwrap()
-            );
-            let _ = tx1.send(OwnedMessage::Text(msg));
-        }
-        commands::Command::ReadFileInfo { file_path } => {
-            let mut file_path = file_path.clone();
+            read_dir_items(share, query, snapshots, responder)
+        },
+        commands::Command::ReadFileInfo { file_path, with_xattrs } => {
             let org_root_path = file_path.root_path().clone();
-            file_path.reset_root(&root_path);
-
-            let message = CommandMessage {
-                version: cmd.version,
-                status: 0,
-                data: CommandData::ReadFileInfo {
-                    item: build_item(&file_path.full_path(), &root_path, &org_root_path),
-                },
-            };
-
-            let msg = format!(
-                "{}:{}{}",
-                &client_key.len(),
-                &client_key,
-                serde_json::to_string(&message).unwrap()
-            );
-            println!("send msg: {}", msg);
-            let _ = tx1.send(OwnedMessage::Text(msg));
-        }
-        commands::Command::DownloadFile {
-            file_path,
-            block_idx,
-            block_size,
-        } => {
-            let mut file_path = file_path.clone();
-            file_path.reset_root(&root_path);
-            let mut f = fs::File::open(file_path.full_path()).unwrap();
-            if *block_idx > 0 {
-                let _seek_size = f
-                    .seek(SeekFrom::Start(((*block_idx) * (*block_size)) as u64))
-                    .unwrap();
-            }
-            let mut buffer: Vec<u8> = vec![0u8; *block_size];
-            let real_size = f.read(&mut buffer).unwrap();
-
-            let message = CommandMessage {
-                version: cmd.version,
-                status: 0,
-                data: CommandData::DownloadFile {
-                    data: buffer[..real_size].to_vec(),
-                    data_size: real_size,
-                },
-            };
-            let msg = format!(
-                "{}:{}{}",
-                &client_key.len(),
-                &client_key,
-                serde_json::to_string(&message).unwrap()
-            );
-            let _ = tx1.send(OwnedMessage::Text(msg));
+            let file_path = share_path(share, file_path)?;
+            let item = build_item_with_xattrs(share, &file_path.full_path(), &org_root_path, *with_xattrs)
+                .map_err(|e| io_error(e, &file_path))?;
+            Ok(CommandData::ReadFileInfo { item })
         }
         commands::Command::ReadPathInfo {
             path,
             take_size,
             skip_size,
+            sort,
+            filter,
+            cursor,
+            with_xattrs,
         } => {
-            let mut path = path.clone();
+            let query = ListQuery {
+                dir_path: path,
+                take_size: *take_size,
+                skip_size: *skip_size,
+                sort: *sort,
+                filter,
+                cursor: cursor.as_deref(),
+                with_xattrs: *with_xattrs,
+            };
+            // note: 带游标时读取快照中的后续页, 路径已在首页检查过
+            if query.cursor.is_some() {
+                return read_dir_items(share, query, snapshots, responder);
+            }
             let org_root_path = path.root_path().clone();
-            path.reset_root(&root_path);
-            let res_data = match fs::metadata(path.full_path()) {
-                Ok(meta) => {
-                    if meta.is_file() {
-                        CommandData::ReadFileInfo {
-                            item: build_item(&path.full_path(), &root_path, &org_root_path),
-                        }
-                    } else if meta.is_dir() {
-                        let total_count = dir_iter(&path).count();
-                        CommandData::ReadDirItem {
-                            items: dir_iter(&path)
-                                .skip(*skip_size)
-                                .take(*take_size)
-                                .map(|dir| {
-                                    build_item(
-                                        dir.unwrap().path().to_str().unwrap(),
-                                        &root_path,
-                                        &org_root_path,
-                                    )
-                                })
-                                .collect(),
-                            total: total_count,
-                            taked_size: min(*take_size + *skip_size, total_count),
-                        }
-                    } else {
-                        CommandData::Error {
-                            message: "todo next".to_string(),
-                        }
-                    }
+            let full_path = share_path(share, path)?;
+            // note: 不跟随符号链接时, 指向目录的链接作为链接本身返回
+            let meta = match share.symlinks {
+                SymlinkPolicy::Follow => fs::metadata(full_path.full_path()),
+                _ => fs::symlink_metadata(full_path.full_path()),
+            };
+            if meta.map_err(|e| io_error(e, &full_path))?.is_dir() {
+                read_dir_items(share, query, snapshots, responder)
+            } else {
+                let item = build_item_with_xattrs(share, &full_path.full_path(), &org_root_path, *with_xattrs)
+                    .map_err(|e| io_error(e, &full_path))?;
+                Ok(CommandData::ReadFileInfo { item })
+            }
+        }
+        commands::Command::ReadTree { path, max_depth, max_items } => {
+            tree::read_tree(share, cmd, path, *max_depth, *max_items, responder)
+        },
+        commands::Command::UploadStatus {
+            file_path,
+            file_size,
+            chksum,
+            overwrite,
+        } => upload::upload_status(share, file_path, *file_size, chksum, *overwrite),
+        commands::Command::UploadFile {
+            file_path,
+            file_size,
+            chksum,
+            block_idx,
+            block_size,
+            overwrite,
+            codec,
+            data,
+        } => {
+            let block = upload::UploadBlock { block_idx: *block_idx, block_size: *block_size, codec: *codec, data };
+            let result = upload::upload_file(share, file_path, *file_size, chksum, *overwrite, block);
+            // note: 上传完成时已校验 chksum, 直接缓存
+            if let (Ok(CommandData::UploadFile { completed: true, .. }), Ok(path)) = (&result, share_path(share, file_path)) {
+                let target = PathBuf::from(path.full_path());
+                if let Ok(meta) = fs::metadata(&target) {
+                    chksums.put(&target, &meta, &chksum.to_lowercase());
                 }
-                Err(err) => CommandData::Error {
-                    message: err.to_string(),
-                },
+            }
+            result
+        },
+        commands::Command::Checksum { file_path } => chksum::checksum(share, cmd, file_path, chksums, responder),
+        commands::Command::MakeDir { dir_path, parents } => file_ops::make_dir(share, dir_path, *parents),
+        commands::Command::Rename { from, to, overwrite } => file_ops::rename(share, from, to, *overwrite),
+        commands::Command::Copy { from, to, overwrite } => file_ops::copy(share, from, to, *overwrite, responder),
+        commands::Command::Delete { path, trash } => file_ops::delete(share, path, *trash),
+        commands::Command::Search { path, pattern, regex, filter, max_results } => {
+            let query = search::SearchQuery { path, pattern, regex: *regex, filter, max_results: *max_results };
+            search::search(share, cmd, query, responder)
+        },
+        commands::Command::Grep { path, pattern, regex, ignore_case, context, include, max_file_size, max_matches } => {
+            let query = grep::GrepQuery {
+                path,
+                pattern,
+                regex: *regex,
+                ignore_case: *ignore_case,
+                context: *context,
+                include: include.as_deref(),
+                max_file_size: *max_file_size,
+                max_matches: *max_matches,
             };
+            grep::grep(share, cmd, query, responder)
+        },
+        commands::Command::Watch { path, recursive } => watcher::watch(share, cmd, path, *recursive, watches, responder),
+        command => Err((ErrorCode::Unsupported, format!("unsupported command {}", command.name()))),
+    }
+}
+
+/// 列目录的参数, 来自 `ReadDirItem` 与 `ReadPathInfo`
+struct ListQuery<'a> {
+    dir_path: &'a FtPath,
+    take_size: usize,
+    skip_size: usize,
+    sort: DirSort,
+    filter: &'a ItemFilter,
+    cursor: Option<&'a str>,
+    with_xattrs: bool,
+}
+
+/// 首页读取目录并过滤、排序, 还有后续页时保存为快照; 后续页按游标从快照中读取, 不再读取目录
+fn read_dir_items(share: &ShareConfig, query: ListQuery, snapshots: &Mutex<Snapshots>, responder: &Responder) -> HandlerResult<CommandData> {
+    let take_size = query.take_size;
+    if take_size > MAX_TAKE_SIZE {
+        return Err((ErrorCode::TooLarge, format!("take size {take_size} exceeds {MAX_TAKE_SIZE}")));
+    }
+    let client_key = responder.client_key();
+    let (snapshot_id, entries, org_root_path, offset) = match query.cursor {
+        Some(cursor) => {
+            let (id, offset) = snapshot::decode_cursor(cursor)
+                .ok_or((ErrorCode::CursorExpired, "invalid cursor".to_string()))?;
+            let (entries, org_root_path) = snapshots.lock().unwrap().get(client_key, id)
+                .ok_or((ErrorCode::CursorExpired, "cursor expired, list the directory again".to_string()))?;
+            (Some(id.to_string()), entries, org_root_path, offset)
+        },
+        None => {
+            let entries = list_dir(share, query.dir_path, query.sort, query.filter)?;
+            (None, Arc::new(entries), query.dir_path.root_path().clone(), query.skip_size)
+        },
+    };
+    let total = entries.len();
+    let mut items = vec![];
+    for path in entries.iter().skip(offset).take(take_size) {
+        if responder.is_cancelled() {
+            break ;
+        }
+        match build_item_with_xattrs(share, &path.to_string_lossy(), &org_root_path, query.with_xattrs) {
+            Ok(item) => items.push(item),
+            // note: 列目录期间被删除或无权限读取的条目跳过, 不影响其他条目
+            Err(e) => eprintln!("skip {}, err: {e}", path.display()),
+        }
+    }
+    let taked_size = min(offset.saturating_add(take_size), total);
+    let cursor = if taked_size < total {
+        let id = snapshot_id.unwrap_or_else(|| {
+            snapshots.lock().unwrap().create(client_key, Snapshot { entries: entries.clone(), org_root_path })
+        });
+        Some(snapshot::encode_cursor(&id, taked_size))
+    } else {
+        if let Some(id) = snapshot_id {
+            snapshots.lock().unwrap().remove(&id);
+        }
+        None
+    };
+    Ok(CommandData::ReadDirItem { items, total, taked_size, cursor })
+}
 
-            let message = CommandMessage {
-                version: cmd.version,
-                status: 0,
-                data: res_data,
+/// 扩展属性读取失败时仍返回条目, xattrs 为 None
+fn build_item_with_xattrs(share: &ShareConfig, path: &str, org_root: &str, with_xattrs: bool) -> io::Result<DirItem> {
+    let mut item = build_item(share, path, org_root)?;
+    if with_xattrs {
+        if let Err(e) = item.info.load_xattrs(Path::new(path)) {
+            eprintln!("read xattrs of {path} failed, err: {e}");
+        }
+    }
+    Ok(item)
+}
+
+/// 读取目录下过滤并排序后的条目
+fn list_dir(share: &ShareConfig, dir_path: &FtPath, sort: DirSort, filter: &ItemFilter) -> HandlerResult<Vec<PathBuf>> {
+    let root_path = share.root_path.as_str();
+    let dir_path = content_path(share, dir_path)?;
+    if !fs::metadata(dir_path.full_path()).map_err(|e| io_error(e, &dir_path))?.is_dir() {
+        return Err((ErrorCode::NotADirectory, format!("/{}: not a directory", dir_path.path())));
+    }
+    let filter = EntryFilter::new(filter)?;
+    let mut entries: Vec<(PathBuf, Metadata)> = fs::read_dir(dir_path.full_path())
+        .map_err(|e| io_error(e, &dir_path))?
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| !upload::is_part_file(path) && !file_ops::is_trash(root_path, path) && !share.hides(path))
+        // note: 跟随符号链接时按目标排序, 目标不存在时按链接本身
+        .filter_map(|path| {
+            let meta = match share.symlinks {
+                SymlinkPolicy::Follow => fs::metadata(&path).or_else(|_| fs::symlink_metadata(&path)),
+                _ => fs::symlink_metadata(&path),
             };
-            let msg = format!(
-                "{}:{}{}",
-                &client_key.len(),
-                &client_key,
-                serde_json::to_string(&message).unwrap()
-            );
-            let _ = tx1.send(OwnedMessage::Text(msg));
+            Some((path, meta.ok()?))
+        })
+        .filter(|(path, meta)| filter.matches(path, meta))
+        .collect();
+    sort_entries(&mut entries, sort);
+    Ok(entries.into_iter().map(|(path, _)| path).collect())
+}
+
+fn sort_entries(entries: &mut [(PathBuf, Metadata)], sort: DirSort) {
+    let modified_at = |meta: &Metadata| meta.modified().unwrap_or(UNIX_EPOCH);
+    let size = |meta: &Metadata| if meta.is_dir() { 0 } else { meta.len() };
+    entries.sort_by(|(a_path, a_meta), (b_path, b_meta)| {
+        let ordering = match sort.key {
+            SortKey::Name => Ordering::Equal,
+            SortKey::Size => size(a_meta).cmp(&size(b_meta)),
+            SortKey::Modified => modified_at(a_meta).cmp(&modified_at(b_meta)),
+            SortKey::Type => b_meta.is_dir().cmp(&a_meta.is_dir()),
+        };
+        let ordering = ordering.then_with(|| a_path.file_name().cmp(&b_path.file_name()));
+        if sort.descending { ordering.reverse() } else { ordering }
+    });
+}
+
+fn download_file(share: &ShareConfig, cmd: &ApiCommand, file_path: &FtPath, block_idx: usize, block_size: usize, responder: &Responder) -> HandlerResult<()> {
+    if block_size > MAX_BLOCK_SIZE {
+        return Err((ErrorCode::TooLarge, format!("block size {block_size} exceeds {MAX_BLOCK_SIZE}")));
+    }
+    let offset = block_idx.checked_mul(block_size)
+        .ok_or((ErrorCode::BadRequest, format!("block {block_idx} out of range")))? as u64;
+    let (file_path, _) = regular_file(share, file_path)?;
+    let mut f = fs::File::open(file_path.full_path()).map_err(|e| io_error(e, &file_path))?;
+    let meta = f.metadata().map_err(|e| io_error(e, &file_path))?;
+    if offset > 0 {
+        f.seek(SeekFrom::Start(offset)).map_err(|e| io_error(e, &file_path))?;
+    }
+    let real_size = min(meta.len().saturating_sub(offset), block_size as u64) as usize;
+
+    responder.message(&CommandMessage {
+        version: cmd.version,
+        request_id: cmd.request_id,
+        status: 0,
+        data: CommandData::DownloadFile {
+            data_size: real_size,
+        },
+    });
+    let compressible = !is_compressed_file(&file_path.full_path());
+    let mut reader = f.take(real_size as u64);
+    let mut buffer = vec![0u8; min(real_size, DATA_FRAME_SIZE)];
+    let mut sent_size = 0;
+    while !responder.is_cancelled() {
+        // note: 发送数据块后出错时 client 收到 Error Message 而不是长度不足的数据
+        let size = reader.read(&mut buffer).map_err(|e| io_error(e, &file_path))?;
+        if size == 0 {
+            break ;
+        }
+        sent_size += size;
+        responder.data(buffer[..size].to_vec(), compressible);
+    }
+    if sent_size < real_size && !responder.is_cancelled() {
+        return Err((ErrorCode::Internal, format!("/{}: file truncated while reading", file_path.path())));
+    }
+    Ok(())
+}
+
+/// 将 client 请求的路径转换为共享目录内的路径, 拒绝包含 `..` 的路径, 避免访问共享目录以外的文件;
+/// 不跟随符号链接时逐级检查, 拒绝经过符号链接目录的路径, 最后一级为链接时按策略隐藏或由调用方处理
+pub(super) fn share_path(share: &ShareConfig, path: &FtPath) -> HandlerResult<FtPath> {
+    let relative_path = path.path();
+    if Path::new(&relative_path).components().any(|component| component == Component::ParentDir) {
+        return Err((ErrorCode::PermissionDenied, format!("/{}: outside of the share", relative_path)));
+    }
+    let mut path = path.clone();
+    path.reset_root(&share.root_path);
+    if share.symlinks == SymlinkPolicy::Follow {
+        return Ok(path);
+    }
+    // note: 共享目录本身可以是符号链接, 只检查其下的各级
+    let components: Vec<_> = Path::new(&relative_path).components().collect();
+    let mut current = PathBuf::from(&share.root_path);
+    for (idx, component) in components.iter().enumerate() {
+        current.push(component);
+        if !current.is_symlink() {
+            continue ;
+        }
+        if share.symlinks == SymlinkPolicy::Hide {
+            return Err(io_error(io::Error::from(io::ErrorKind::NotFound), &path));
+        }
+        if idx + 1 < components.len() {
+            return Err((ErrorCode::PermissionDenied, format!("/{}: path goes through a symlink", relative_path)));
+        }
+    }
+    Ok(path)
+}
+
+/// 修改路径(创建、改名、删除、写入)前检查, 无论符号链接策略如何, 上级目录解析后都必须位于共享目录以内;
+/// 路径本身不解析, 调用方按 `symlink_metadata` 操作链接本身
+pub(super) fn mutable_path(share: &ShareConfig, path: &FtPath) -> HandlerResult<FtPath> {
+    let path = share_path(share, path)?;
+    let outside = || (ErrorCode::PermissionDenied, format!("/{}: outside of the share", path.path()));
+    let root = fs::canonicalize(&share.root_path).map_err(|e| io_error(e, &path))?;
+    let full_path = PathBuf::from(path.full_path());
+    // note: 上级目录可能还不存在(MakeDir parents), 检查已存在的最近一级
+    for ancestor in full_path.ancestors().skip(1) {
+        match fs::canonicalize(ancestor) {
+            Ok(real_path) if real_path.starts_with(&root) => return Ok(path),
+            Ok(_) => return Err(outside()),
+            // note: 目标不存在的链接无法确认位置
+            Err(e) if e.kind() == io::ErrorKind::NotFound && fs::symlink_metadata(ancestor).is_err() => continue ,
+            Err(_) => return Err(outside()),
+        }
+    }
+    Err(outside())
+}
+
+/// 读取路径的内容(文件内容或目录下的条目)前检查, 不跟随符号链接时最后一级也不能是链接
+pub(super) fn content_path(share: &ShareConfig, path: &FtPath) -> HandlerResult<FtPath> {
+    let path = share_path(share, path)?;
+    if share.symlinks == SymlinkPolicy::Link && Path::new(&path.full_path()).is_symlink() {
+        return Err((ErrorCode::BadRequest, format!("/{}: is a symlink", path.path())));
+    }
+    Ok(path)
+}
+
+/// 读取文件内容前检查, 只接受普通文件; 打开 FIFO 等特殊文件会一直阻塞
+pub(super) fn regular_file(share: &ShareConfig, path: &FtPath) -> HandlerResult<(FtPath, Metadata)> {
+    let path = content_path(share, path)?;
+    let meta = fs::metadata(path.full_path()).map_err(|e| io_error(e, &path))?;
+    if !meta.is_file() {
+        return Err((ErrorCode::BadRequest, format!("/{}: not a file", path.path())));
+    }
+    Ok((path, meta))
+}
+
+/// 错误信息中只包含共享目录内的相对路径, 不暴露 server 的目录结构
+pub(super) fn io_error(e: io::Error, path: &FtPath) -> HandlerError {
+    (ErrorCode::from(&e), format!("/{}: {}", path.path(), e))
+}
+
+fn share_info(share: &ShareConfig, protocol_version: u16, with_stats: bool, responder: &Responder) -> ShareInfo {
+    let (file_count, total_size) = if with_stats {
+        share_stats(Path::new(&share.root_path), responder)
+    } else {
+        (0, 0)
+    };
+    ShareInfo {
+        name: share.name.clone(),
+        server: format!("{}/{}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION")),
+        protocol_version,
+        commands: SERVED_COMMANDS.iter()
+            .filter(|command| share.allows(command))
+            .map(|command| command.to_string())
+            .collect(),
+        codecs: SUPPORTED_CODECS.to_vec(),
+        read_only: share.read_only,
+        file_count,
+        total_size,
+        free_space: free_space(&share.root_path),
+        symlinks: share.symlinks,
+    }
+}
+
+/// 统计共享目录下的文件数与总大小, 不跟随符号链接; 请求取消后返回已统计的部分
+fn share_stats(root: &Path, responder: &Responder) -> (u64, u64) {
+    let (mut file_count, mut total_size) = (0, 0);
+    let mut dirs: Vec<PathBuf> = vec![root.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        if responder.is_cancelled() {
+            break ;
         }
-        _ => {
-            println!("cannot support comand:{cmd:#?}");
+        let entries = match fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(_) => continue,
+        };
+        for entry in entries.flatten() {
+            match entry.metadata() {
+                Ok(meta) if meta.is_dir() => dirs.push(entry.path()),
+                Ok(meta) if meta.is_file() => {
+                    file_count += 1;
+                    total_size += meta.len();
+                },
+                _ => {},
+            }
         }
     }
+    (file_count, total_size)
+}
+
+// note: statvfs 各字段的类型随架构不同
+#[allow(clippy::unnecessary_cast)]
+pub(super) fn free_space(path: &str) -> u64 {
+    match nix::sys::statvfs::statvfs(path) {
+        Ok(stat) => stat.blocks_available() as u64 * stat.fragment_size() as u64,
+        Err(_) => 0,
+    }
+}
+
+#[cfg(test)]
+mod test_command_handler {
+    use std::{fs, os::unix::{fs::symlink, net::UnixListener}, path::PathBuf, sync::{mpsc::channel, Arc, Mutex}};
+
+    use websocket::OwnedMessage;
+
+    use crate::{common::test_util::TempDir, features::{
+        codec::SUPPORTED_CODECS,
+        commands::{ApiCommand, Command, CommandData, CommandMessage, DirItem, DirItemInfo, DirSort, ErrorCode, FtPath, ItemFilter, ShareInfo, SortKey, SymlinkPolicy},
+        crypto::auth_response,
+        frame::Frame,
+        handshake::PROTOCOL_VERSION_MAX,
+        server::{auth::Auth, chksum::ChksumCache, responder::Responder, search::EntryFilter, snapshot::Snapshots, watcher::WatchHub, ShareConfig},
+    }};
+
+    use super::{content_path, handler, read_dir_items, regular_file, share_path, sort_entries, ListQuery, SERVED_COMMANDS};
+
+    fn read_config(share: &ShareConfig, with_stats: bool) -> ShareInfo {
+        let mut auth = Auth::default();
+        let challenge = auth.challenge("client");
+        assert!(auth.verify("client", "secret", &challenge, &auth_response("secret", &challenge, "client")));
+        let (tx, rx) = channel();
+        let cmd = ApiCommand::new(Command::ReadConfig { with_stats });
+        let responder = Responder::new(&tx, "client", cmd.request_id);
+        let db_dir = TempDir::new("share-info-db");
+        let watches = Arc::new(WatchHub::default());
+        handler(share, &cmd, &responder, &Mutex::new(auth), &Mutex::new(Snapshots::default()), &ChksumCache::open_in(&db_dir), &watches);
+        let Ok(OwnedMessage::Binary(bin)) = rx.try_recv() else {
+            panic!("response message missing");
+        };
+        let frame = Frame::decode(&bin).unwrap();
+        match serde_json::from_slice::<CommandMessage>(&frame.payload).unwrap().data {
+            CommandData::ReadConfig { share } => share,
+            data => panic!("unexpected message {data:?}"),
+        }
+    }
+
+    #[test]
+    fn test_read_config() {
+        let root = TempDir::new("share-info");
+        fs::create_dir(root.join("dir")).unwrap();
+        fs::write(root.join("a"), "123").unwrap();
+        fs::write(root.join("dir").join("b"), "45").unwrap();
+        let share = ShareConfig::new(&root);
+
+        let served_except = |excluded: &[&str]| -> Vec<&str> {
+            SERVED_COMMANDS.into_iter().filter(|command| !excluded.contains(command)).collect()
+        };
+
+        let info = read_config(&share, false);
+        assert_eq!(info.protocol_version, PROTOCOL_VERSION_MAX);
+        // 只读时只返回读取的命令
+        assert_eq!(info.commands, served_except(&["UploadStatus", "UploadFile", "MakeDir", "Rename", "Copy", "Delete"]));
+        assert_eq!(info.codecs, SUPPORTED_CODECS);
+        assert!(info.read_only);
+        assert!(info.free_space > 0);
+        // 不统计时不遍历共享目录
+        assert_eq!((info.file_count, info.total_size), (0, 0));
+
+        let info = read_config(&share, true);
+        assert_eq!((info.file_count, info.total_size), (2, 5));
+
+        let share = share.writable(&["mkdir", "delete"]);
+        let info = read_config(&share, false);
+        assert_eq!(info.commands, served_except(&["Rename", "Copy"]));
+        assert!(!info.read_only);
+    }
+
+    #[test]
+    fn test_sort_and_filter_entries() {
+        let dir = TempDir::new("sort");
+        fs::create_dir(dir.join("b_dir")).unwrap();
+        fs::write(dir.join("c.log"), "1").unwrap();
+        fs::write(dir.join("a.LOG"), "123").unwrap();
+        fs::write(dir.join(".hidden"), "12").unwrap();
+        let entries = || -> Vec<_> {
+            fs::read_dir(&dir).unwrap().flatten().map(|entry| (entry.path(), entry.metadata().unwrap())).collect()
+        };
+        let names = |entries: &[(PathBuf, fs::Metadata)]| -> Vec<String> {
+            entries.iter().map(|(path, _)| path.file_name().unwrap().to_string_lossy().to_string()).collect()
+        };
+
+        let mut sorted = entries();
+        sort_entries(&mut sorted, DirSort::default());
+        assert_eq!(names(&sorted), [".hidden", "a.LOG", "b_dir", "c.log"]);
+        sort_entries(&mut sorted, DirSort { key: SortKey::Size, descending: true });
+        assert_eq!(names(&sorted), ["a.LOG", ".hidden", "c.log", "b_dir"]);
+        sort_entries(&mut sorted, DirSort { key: SortKey::Type, descending: false });
+        assert_eq!(names(&sorted), ["b_dir", ".hidden", "a.LOG", "c.log"]);
+
+        let filter = ItemFilter { extensions: vec!["log".to_string()], ..Default::default() };
+        let filter = EntryFilter::new(&filter).unwrap();
+        let mut filtered: Vec<_> = entries().into_iter().filter(|(path, meta)| filter.matches(path, meta)).collect();
+        sort_entries(&mut filtered, DirSort::default());
+        assert_eq!(names(&filtered), ["a.LOG", "c.log"]);
+
+        let filter = ItemFilter { name: Some("[ab]*".to_string()), skip_hidden: true, ..Default::default() };
+        let filter = EntryFilter::new(&filter).unwrap();
+        let mut filtered: Vec<_> = entries().into_iter().filter(|(path, meta)| filter.matches(path, meta)).collect();
+        sort_entries(&mut filtered, DirSort::default());
+        assert_eq!(names(&filtered), ["a.LOG", "b_dir"]);
+    }
+
+    #[test]
+    fn test_read_dir_items_with_cursor() {
+        let root = TempDir::new("cursor");
+        ["a", "b", "c"].iter().for_each(|name| fs::write(root.join(name), name).unwrap());
+        let share = ShareConfig::new(&root);
+        let snapshots = Mutex::new(Snapshots::default());
+        let (tx, _rx) = channel();
+        let responder = Responder::new(&tx, "client", 1);
+        let dir_path = FtPath::new_relative("/save".to_string(), "/".to_string());
+        let filter = ItemFilter::default();
+        let list = |cursor: Option<&str>, responder: &Responder| {
+            let query = ListQuery { dir_path: &dir_path, take_size: 2, skip_size: 0, sort: DirSort::default(), filter: &filter, cursor, with_xattrs: false };
+            read_dir_items(&share, query, &snapshots, responder)
+        };
+        let names = |items: &[DirItem]| -> Vec<String> {
+            items.iter().map(|item| item.path().path().trim_start_matches('/').to_string()).collect()
+        };
+
+        let cursor = match list(None, &responder) {
+            Ok(CommandData::ReadDirItem { items, total: 3, taked_size: 2, cursor: Some(cursor) }) => {
+                assert_eq!(names(&items), ["a", "b"]);
+                cursor
+            },
+            result => panic!("unexpected result {result:?}"),
+        };
+        // 翻页期间新增的条目不影响后续页
+        fs::write(root.join("0"), "0").unwrap();
+        match list(Some(&cursor), &responder) {
+            Ok(CommandData::ReadDirItem { items, total: 3, taked_size: 3, cursor: None }) => assert_eq!(names(&items), ["c"]),
+            result => panic!("unexpected result {result:?}"),
+        }
+        // 最后一页后快照被删除
+        assert!(matches!(list(Some(&cursor), &responder), Err((ErrorCode::CursorExpired, _))));
+
+        let cursor = match list(None, &responder) {
+            Ok(CommandData::ReadDirItem { cursor: Some(cursor), .. }) => cursor,
+            result => panic!("unexpected result {result:?}"),
+        };
+        let other = Responder::new(&tx, "other", 1);
+        assert!(matches!(list(Some(&cursor), &other), Err((ErrorCode::CursorExpired, _))));
+        assert!(matches!(list(Some("bad"), &responder), Err((ErrorCode::CursorExpired, _))));
+    }
+
+    #[test]
+    fn test_symlink_policy() {
+        let root = TempDir::new("symlink");
+        fs::write(root.join("a.txt"), "a").unwrap();
+        symlink("a.txt", root.join("link")).unwrap();
+        symlink("missing", root.join("broken")).unwrap();
+        let _socket = UnixListener::bind(root.join("sock")).unwrap();
+        let snapshots = Mutex::new(Snapshots::default());
+        let (tx, _rx) = channel();
+        let responder = Responder::new(&tx, "client", 1);
+        let dir_path = FtPath::new_relative("/save".to_string(), "/".to_string());
+        let filter = ItemFilter::default();
+        let list = |share: &ShareConfig| -> Vec<(String, DirItemInfo)> {
+            let query = ListQuery { dir_path: &dir_path, take_size: 10, skip_size: 0, sort: DirSort::default(), filter: &filter, cursor: None, with_xattrs: false };
+            match read_dir_items(share, query, &snapshots, &responder) {
+                Ok(CommandData::ReadDirItem { items, .. }) => items.into_iter()
+                    .map(|item| (item.path().path().trim_start_matches('/').to_string(), item.info))
+                    .collect(),
+                result => panic!("unexpected result {result:?}"),
+            }
+        };
+        let link = |path: &str| FtPath::new_relative("/save".to_string(), path.to_string());
+
+        let share_follow = ShareConfig::new(&root);
+        let items = list(&share_follow);
+        let names: Vec<_> = items.iter().map(|(name, info)| format!("{}{name}", info.type_flag())).collect();
+        assert_eq!(names, ["-a.txt", "lbroken", "-link", "ssock"]);
+        assert!(regular_file(&share_follow, &link("link")).is_ok());
+        assert!(matches!(regular_file(&share_follow, &link("sock")), Err((ErrorCode::BadRequest, _))));
+
+        let share_link = ShareConfig::new(&root).with_symlinks(SymlinkPolicy::Link);
+        let items = list(&share_link);
+        assert!(matches!(&items[2].1, DirItemInfo::Symlink { target, .. } if target == "a.txt"));
+        assert!(matches!(regular_file(&share_link, &link("link")), Err((ErrorCode::BadRequest, _))));
+
+        let share_hide = ShareConfig::new(&root).with_symlinks(SymlinkPolicy::Hide);
+        let names: Vec<_> = list(&share_hide).into_iter().map(|(name, _)| name).collect();
+        assert_eq!(names, ["a.txt", "sock"]);
+        assert!(matches!(regular_file(&share_hide, &link("link")), Err((ErrorCode::NotFound, _))));
+    }
+
+    #[test]
+    fn test_symlinked_dir_in_path() {
+        let base = TempDir::new("symdir");
+        let (root, outside) = (base.join("share"), base.join("outside"));
+        fs::create_dir_all(&root).unwrap();
+        fs::create_dir_all(&outside).unwrap();
+        fs::write(outside.join("secret.txt"), "secret").unwrap();
+        symlink(&outside, root.join("linkdir")).unwrap();
+        let secret = FtPath::new_relative("/save".to_string(), "/linkdir/secret.txt".to_string());
+        let linkdir = FtPath::new_relative("/save".to_string(), "/linkdir".to_string());
+
+        let share_follow = ShareConfig::new(&root);
+        assert!(regular_file(&share_follow, &secret).is_ok());
+        assert!(content_path(&share_follow, &linkdir).is_ok());
+
+        let share_link = ShareConfig::new(&root).with_symlinks(SymlinkPolicy::Link);
+        assert!(matches!(regular_file(&share_link, &secret), Err((ErrorCode::PermissionDenied, _))));
+        assert!(matches!(share_path(&share_link, &secret), Err((ErrorCode::PermissionDenied, _))));
+        assert!(share_path(&share_link, &linkdir).is_ok());
+        assert!(matches!(content_path(&share_link, &linkdir), Err((ErrorCode::BadRequest, _))));
+
+        let share_hide = ShareConfig::new(&root).with_symlinks(SymlinkPolicy::Hide);
+        assert!(matches!(regular_file(&share_hide, &secret), Err((ErrorCode::NotFound, _))));
+        assert!(matches!(content_path(&share_hide, &linkdir), Err((ErrorCode::NotFound, _))));
+    }
 }
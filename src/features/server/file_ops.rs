@@ -0,0 +1,361 @@
+use std::{fs, io, os::unix::fs::symlink, path::{Component, Path, PathBuf}, time::{SystemTime, UNIX_EPOCH}};
+
+use crate::features::commands::{CommandData, ErrorCode, FtPath};
+
+use super::{build_item, command_handler::{io_error, mutable_path, HandlerResult}, responder::Responder, upload, ShareConfig};
+
+/// 可以在 server.db 中单独开启的文件操作与对应的命令, 均要求共享目录可写
+pub const FILE_OPS: [(&str, &str); 4] = [
+    ("mkdir", "MakeDir"),
+    ("rename", "Rename"),
+    ("copy", "Copy"),
+    ("delete", "Delete"),
+];
+
+/// 回收站, 位于共享目录下, 列目录时不返回
+const TRASH_DIR: &str = ".fttrash";
+
+pub fn op_name(command: &str) -> Option<&'static str> {
+    FILE_OPS.iter().find(|(_, name)| *name == command).map(|(op, _)| *op)
+}
+
+pub fn is_trash(root_path: &str, path: &Path) -> bool {
+    path == Path::new(root_path).join(TRASH_DIR)
+}
+
+fn check_allowed(share: &ShareConfig, command: &str) -> HandlerResult<()> {
+    if share.read_only {
+        return Err((ErrorCode::ReadOnly, "share is read only".to_string()));
+    }
+    if !share.allows(command) {
+        return Err((ErrorCode::PermissionDenied, format!("{} is not enabled on this share", op_name(command).unwrap_or(command))));
+    }
+    Ok(())
+}
+
+/// 转换为共享目录内的路径, 不允许操作共享目录本身及经过符号链接指向共享目录以外的路径
+fn target_path(share: &ShareConfig, path: &FtPath) -> HandlerResult<(FtPath, PathBuf)> {
+    if path.path().trim_matches('/').is_empty() {
+        return Err((ErrorCode::BadRequest, "can not modify the share root".to_string()));
+    }
+    let path = mutable_path(share, path)?;
+    let full_path = PathBuf::from(path.full_path());
+    Ok((path, full_path))
+}
+
+/// 目标已存在且 overwrite 为 false 时返回 AlreadyExists; 不删除目标, 由 `move_over` 替换
+fn check_target(from: &Path, to: &Path, to_path: &FtPath, overwrite: bool) -> HandlerResult<()> {
+    if to.starts_with(from) {
+        return Err((ErrorCode::BadRequest, format!("/{}: target is inside of the source", to_path.path())));
+    }
+    match fs::symlink_metadata(to) {
+        Ok(_) if !overwrite => Err((ErrorCode::AlreadyExists, format!("/{}: already exists", to_path.path()))),
+        Ok(_) => Ok(()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(io_error(e, to_path)),
+    }
+}
+
+/// 复制或移动后的符号链接(包括目录中的)必须指向共享目录以内, 相对链接按新的位置解析
+fn check_links(share: &ShareConfig, from: &Path, to: &Path, to_path: &FtPath) -> HandlerResult<()> {
+    let root = fs::canonicalize(&share.root_path).map_err(|e| io_error(e, to_path))?;
+    // note: mutable_path 已确认上级目录存在且位于共享目录以内
+    let parent = to.parent().and_then(|parent| fs::canonicalize(parent).ok()).unwrap_or_else(|| root.clone());
+    let to = parent.join(to.file_name().unwrap_or_default());
+    check_links_at(&root, from, &to, to_path)
+}
+
+fn check_links_at(root: &Path, from: &Path, to: &Path, to_path: &FtPath) -> HandlerResult<()> {
+    let meta = fs::symlink_metadata(from).map_err(|e| io_error(e, to_path))?;
+    if meta.is_symlink() {
+        let target = fs::read_link(from).map_err(|e| io_error(e, to_path))?;
+        if !resolve_link(to, &target).starts_with(root) {
+            return Err((ErrorCode::PermissionDenied, format!("/{}: symbolic link to {} is outside of the share", to_path.path(), target.display())));
+        }
+    } else if meta.is_dir() {
+        for entry in fs::read_dir(from).map_err(|e| io_error(e, to_path))? {
+            let entry = entry.map_err(|e| io_error(e, to_path))?;
+            check_links_at(root, &entry.path(), &to.join(entry.file_name()), to_path)?;
+        }
+    }
+    Ok(())
+}
+
+/// 位于 link 的符号链接指向的路径, 目标存在时解析为真实路径(目标可能是另一个链接)
+fn resolve_link(link: &Path, target: &Path) -> PathBuf {
+    let mut resolved = link.parent().map(Path::to_path_buf).unwrap_or_default();
+    for component in target.components() {
+        match component {
+            Component::RootDir => resolved = PathBuf::from("/"),
+            Component::ParentDir => {
+                resolved.pop();
+            },
+            Component::Normal(name) => resolved.push(name),
+            Component::CurDir | Component::Prefix(_) => {},
+        }
+    }
+    fs::canonicalize(&resolved).unwrap_or(resolved)
+}
+
+/// 与 to 在同一目录的临时路径, 列目录等不返回
+fn temp_sibling(to: &Path) -> PathBuf {
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos();
+    upload::part_path(to, &format!("{:016x}", nanos as u64))
+}
+
+/// 将 from 移动到 to, 已存在的文件由 rename 直接替换; rename 无法替换目录或用目录替换文件,
+/// 此时先将目标移到临时路径, 完成后再删除, 失败时恢复原目标
+fn move_over(from: &Path, to: &Path) -> io::Result<()> {
+    let replace_dir = match fs::symlink_metadata(to) {
+        Ok(meta) => meta.is_dir() || fs::symlink_metadata(from)?.is_dir(),
+        Err(_) => false,
+    };
+    if !replace_dir {
+        return fs::rename(from, to);
+    }
+    let old = temp_sibling(to);
+    fs::rename(to, &old)?;
+    if let Err(e) = fs::rename(from, to) {
+        let _ = fs::rename(&old, to);
+        return Err(e);
+    }
+    remove_path(&old, &fs::symlink_metadata(&old)?)
+}
+
+fn remove_path(path: &Path, meta: &fs::Metadata) -> io::Result<()> {
+    if meta.is_dir() {
+        fs::remove_dir_all(path)
+    } else {
+        fs::remove_file(path)
+    }
+}
+
+fn operation_item(share: &ShareConfig, full_path: &Path, path: &FtPath, org_root_path: &str) -> HandlerResult<CommandData> {
+    let item = build_item(share, &full_path.to_string_lossy(), org_root_path)
+        .map_err(|e| io_error(e, path))?;
+    Ok(CommandData::FileOperation { item })
+}
+
+pub fn make_dir(share: &ShareConfig, dir_path: &FtPath, parents: bool) -> HandlerResult<CommandData> {
+    check_allowed(share, "MakeDir")?;
+    let (path, full_path) = target_path(share, dir_path)?;
+    let result = if parents {
+        fs::create_dir_all(&full_path)
+    } else {
+        fs::create_dir(&full_path)
+    };
+    result.map_err(|e| io_error(e, &path))?;
+    operation_item(share, &full_path, &path, dir_path.root_path())
+}
+
+pub fn rename(share: &ShareConfig, from: &FtPath, to: &FtPath, overwrite: bool) -> HandlerResult<CommandData> {
+    check_allowed(share, "Rename")?;
+    let (from_path, from_full_path) = target_path(share, from)?;
+    let (to_path, to_full_path) = target_path(share, to)?;
+    fs::symlink_metadata(&from_full_path).map_err(|e| io_error(e, &from_path))?;
+    check_target(&from_full_path, &to_full_path, &to_path, overwrite)?;
+    check_links(share, &from_full_path, &to_full_path, &to_path)?;
+    move_over(&from_full_path, &to_full_path).map_err(|e| io_error(e, &to_path))?;
+    operation_item(share, &to_full_path, &to_path, to.root_path())
+}
+
+pub fn copy(share: &ShareConfig, from: &FtPath, to: &FtPath, overwrite: bool, responder: &Responder) -> HandlerResult<CommandData> {
+    check_allowed(share, "Copy")?;
+    let (from_path, from_full_path) = target_path(share, from)?;
+    let (to_path, to_full_path) = target_path(share, to)?;
+    let meta = fs::symlink_metadata(&from_full_path).map_err(|e| io_error(e, &from_path))?;
+    if !meta.is_file() && !meta.is_dir() && !meta.is_symlink() {
+        return Err((ErrorCode::BadRequest, format!("/{}: not a regular file", from_path.path())));
+    }
+    check_target(&from_full_path, &to_full_path, &to_path, overwrite)?;
+    check_links(share, &from_full_path, &to_full_path, &to_path)?;
+    // note: 先复制到临时路径, 完成后再替换目标, 复制失败或取消时目标不受影响
+    let staged = temp_sibling(&to_full_path);
+    let result = copy_tree(&from_full_path, &staged, responder).and_then(|_| move_over(&staged, &to_full_path));
+    if let Err(e) = result {
+        if let Ok(meta) = fs::symlink_metadata(&staged) {
+            let _ = remove_path(&staged, &meta);
+        }
+        return Err(io_error(e, &to_path));
+    }
+    operation_item(share, &to_full_path, &to_path, to.root_path())
+}
+
+/// 递归复制, 符号链接复制为链接本身, 跳过 FIFO 等特殊文件(打开 FIFO 会一直阻塞); 请求取消后停止
+fn copy_tree(from: &Path, to: &Path, responder: &Responder) -> io::Result<()> {
+    if responder.is_cancelled() {
+        return Err(io::Error::new(io::ErrorKind::Interrupted, "cancelled"));
+    }
+    let meta = fs::symlink_metadata(from)?;
+    if meta.is_symlink() {
+        symlink(fs::read_link(from)?, to)
+    } else if meta.is_dir() {
+        fs::create_dir(to)?;
+        for entry in fs::read_dir(from)? {
+            let entry = entry?;
+            copy_tree(&entry.path(), &to.join(entry.file_name()), responder)?;
+        }
+        fs::set_permissions(to, meta.permissions())
+    } else if meta.is_file() {
+        fs::copy(from, to).map(|_| ())
+    } else {
+        Ok(())
+    }
+}
+
+pub fn delete(share: &ShareConfig, path: &FtPath, trash: bool) -> HandlerResult<CommandData> {
+    check_allowed(share, "Delete")?;
+    let (del_path, full_path) = target_path(share, path)?;
+    let meta = fs::symlink_metadata(&full_path).map_err(|e| io_error(e, &del_path))?;
+    let trash_dir = Path::new(&share.root_path).join(TRASH_DIR);
+    // note: 回收站中的内容直接删除
+    if !trash || full_path.starts_with(&trash_dir) {
+        remove_path(&full_path, &meta).map_err(|e| io_error(e, &del_path))?;
+        return Ok(CommandData::Delete { trash_path: None });
+    }
+    fs::create_dir_all(&trash_dir).map_err(|e| io_error(e, &del_path))?;
+    let millis = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis();
+    let name = full_path.file_name().map(|name| name.to_string_lossy().to_string()).unwrap_or_default();
+    let trash_name = format!("{TRASH_DIR}/{millis}-{name}");
+    fs::rename(&full_path, Path::new(&share.root_path).join(&trash_name)).map_err(|e| io_error(e, &del_path))?;
+    Ok(CommandData::Delete {
+        trash_path: Some(FtPath::new_relative(path.root_path().clone(), trash_name)),
+    })
+}
+
+#[cfg(test)]
+mod test_file_ops {
+    use std::{fs, os::unix::{fs::symlink, net::UnixListener}, sync::{mpsc::channel, Arc}};
+
+    use crate::{
+        common::test_util::TempDir,
+        features::{commands::{CommandData, ErrorCode, FtPath}, server::{responder::{InFlight, Responder}, ShareConfig}},
+    };
+
+    use super::{copy, delete, make_dir, rename};
+
+    #[test]
+    fn test_file_ops() {
+        let root = TempDir::new("file-ops");
+        let mut share = ShareConfig::new(&root).writable(&["mkdir"]);
+        let (tx, _rx) = channel();
+        let responder = Responder::new(&tx, "client", 1);
+        let path = |path: &str| FtPath::new_relative("/save".to_string(), path.to_string());
+
+        assert!(matches!(make_dir(&share, &path("/a/b"), false), Err((ErrorCode::NotFound, _))));
+        assert!(matches!(make_dir(&share, &path("/a/b"), true),
+            Ok(CommandData::FileOperation { item }) if item.path().path() == "a/b" && item.path().root_path() == "/save"));
+        assert!(matches!(make_dir(&share, &path("/a/b"), false), Err((ErrorCode::AlreadyExists, _))));
+        fs::write(root.join("a/b/f.txt"), "file").unwrap();
+        // 未开启的操作
+        assert!(matches!(rename(&share, &path("/a"), &path("/c"), false), Err((ErrorCode::PermissionDenied, _))));
+        share = share.writable(&["rename", "copy", "delete"]);
+
+        assert!(matches!(copy(&share, &path("/a"), &path("/a/b/c"), false, &responder), Err((ErrorCode::BadRequest, _))));
+        // 特殊文件不复制
+        let _socket = UnixListener::bind(root.join("a/b/app.sock")).unwrap();
+        assert!(matches!(copy(&share, &path("/a/b/app.sock"), &path("/app.sock"), false, &responder), Err((ErrorCode::BadRequest, _))));
+        copy(&share, &path("/a"), &path("/c"), false, &responder).unwrap();
+        assert_eq!(fs::read_to_string(root.join("c/b/f.txt")).unwrap(), "file");
+        assert!(!root.join("c/b/app.sock").exists());
+        assert!(matches!(rename(&share, &path("/c"), &path("/a"), false), Err((ErrorCode::AlreadyExists, _))));
+        rename(&share, &path("/c/b/f.txt"), &path("/a/b/f.txt"), true).unwrap();
+        assert!(!root.join("c/b/f.txt").exists());
+        assert!(matches!(rename(&share, &path("/"), &path("/d"), false), Err((ErrorCode::BadRequest, _))));
+
+        let trash_path = match delete(&share, &path("/a"), true) {
+            Ok(CommandData::Delete { trash_path: Some(trash_path) }) => trash_path,
+            result => panic!("unexpected result {result:?}"),
+        };
+        assert!(!root.join("a").exists());
+        assert!(root.join(trash_path.path()).join("b/f.txt").exists());
+        assert!(matches!(delete(&share, &trash_path, true), Ok(CommandData::Delete { trash_path: None })));
+        assert!(matches!(delete(&share, &path("/c"), false), Ok(CommandData::Delete { trash_path: None })));
+        assert!(matches!(delete(&share, &path("/c"), false), Err((ErrorCode::NotFound, _))));
+
+        // 覆盖时先复制到临时路径再替换, 取消后目标不受影响
+        fs::create_dir_all(root.join("d/e")).unwrap();
+        fs::write(root.join("x.txt"), "x").unwrap();
+        let in_flight = Arc::new(InFlight::default());
+        let mut cancelled = Responder::new(&tx, "client", 2);
+        assert!(cancelled.track(in_flight.clone(), false));
+        assert!(in_flight.cancel("client", 2));
+        assert!(copy(&share, &path("/x.txt"), &path("/d"), true, &cancelled).is_err());
+        assert!(root.join("d/e").is_dir());
+        copy(&share, &path("/x.txt"), &path("/d"), true, &responder).unwrap();
+        assert_eq!(fs::read_to_string(root.join("d")).unwrap(), "x");
+        fs::create_dir_all(root.join("f/g")).unwrap();
+        rename(&share, &path("/f"), &path("/d"), true).unwrap();
+        assert!(root.join("d/g").is_dir() && !root.join("f").exists());
+        rename(&share, &path("/x.txt"), &path("/d"), true).unwrap();
+        assert_eq!(fs::read_to_string(root.join("d")).unwrap(), "x");
+        // 没有遗留的临时文件
+        let names: Vec<_> = fs::read_dir(&root).unwrap().map(|entry| entry.unwrap().file_name()).collect();
+        assert!(names.iter().all(|name| !name.to_string_lossy().ends_with(".ftpart")), "{names:?}");
+
+        share.read_only = true;
+        assert!(matches!(delete(&share, &path("/c"), false), Err((ErrorCode::ReadOnly, _))));
+    }
+
+    #[test]
+    fn test_symlinked_dir_outside() {
+        let base = TempDir::new("file-ops-link");
+        let (root, outside) = (base.join("share"), base.join("outside"));
+        fs::create_dir_all(&root).unwrap();
+        fs::create_dir_all(&outside).unwrap();
+        fs::write(outside.join("f.txt"), "outside").unwrap();
+        symlink(&outside, root.join("linkdir")).unwrap();
+        fs::write(root.join("a.txt"), "a").unwrap();
+        let share = ShareConfig::new(&root).writable(&["mkdir", "rename", "copy", "delete"]);
+        let (tx, _rx) = channel();
+        let responder = Responder::new(&tx, "client", 1);
+        let path = |path: &str| FtPath::new_relative("/save".to_string(), path.to_string());
+
+        assert!(matches!(make_dir(&share, &path("/linkdir/new/sub"), true), Err((ErrorCode::PermissionDenied, _))));
+        assert!(matches!(copy(&share, &path("/a.txt"), &path("/linkdir/a.txt"), false, &responder), Err((ErrorCode::PermissionDenied, _))));
+        assert!(matches!(rename(&share, &path("/linkdir/f.txt"), &path("/f.txt"), false), Err((ErrorCode::PermissionDenied, _))));
+        assert!(matches!(delete(&share, &path("/linkdir/f.txt"), false), Err((ErrorCode::PermissionDenied, _))));
+        assert!(!outside.join("new").exists() && !outside.join("a.txt").exists());
+        assert!(outside.join("f.txt").exists());
+
+        // 指向共享目录以外的链接不能复制或移动, 可以删除, 目标不受影响
+        assert!(matches!(rename(&share, &path("/linkdir"), &path("/linkdir2"), false), Err((ErrorCode::PermissionDenied, _))));
+        assert!(matches!(copy(&share, &path("/linkdir"), &path("/linkdir2"), false, &responder), Err((ErrorCode::PermissionDenied, _))));
+        assert!(!root.join("linkdir2").is_symlink());
+        delete(&share, &path("/linkdir"), false).unwrap();
+        assert!(!root.join("linkdir").is_symlink());
+        assert_eq!(fs::read_to_string(outside.join("f.txt")).unwrap(), "outside");
+    }
+
+    #[test]
+    fn test_symlink_escape() {
+        let base = TempDir::new("file-ops-escape");
+        let root = base.join("share");
+        fs::create_dir_all(root.join("a/b")).unwrap();
+        fs::write(base.join("secret"), "secret").unwrap();
+        fs::write(root.join("a/f.txt"), "f").unwrap();
+        // 在原位置指向共享目录以内, 移动到上级目录后指向共享目录以外
+        symlink("../../a/f.txt", root.join("a/b/up")).unwrap();
+        symlink(base.join("secret"), root.join("a/up")).unwrap();
+        let share = ShareConfig::new(&root).writable(&["rename", "copy"]);
+        let (tx, _rx) = channel();
+        let responder = Responder::new(&tx, "client", 1);
+        let path = |path: &str| FtPath::new_relative("/save".to_string(), path.to_string());
+
+        assert!(matches!(rename(&share, &path("/a/b/up"), &path("/up"), false), Err((ErrorCode::PermissionDenied, _))));
+        assert!(matches!(copy(&share, &path("/a/b/up"), &path("/up"), false, &responder), Err((ErrorCode::PermissionDenied, _))));
+        // 目录中的链接按新的位置检查
+        assert!(matches!(copy(&share, &path("/a/b"), &path("/b"), false, &responder), Err((ErrorCode::PermissionDenied, _))));
+        assert!(matches!(rename(&share, &path("/a/b"), &path("/b"), false), Err((ErrorCode::PermissionDenied, _))));
+        assert!(!root.join("up").is_symlink() && !root.join("b").exists());
+        assert!(root.join("a/b/up").is_symlink());
+        // 已经指向共享目录以外的链接
+        assert!(matches!(rename(&share, &path("/a/up"), &path("/a/b/up2"), false), Err((ErrorCode::PermissionDenied, _))));
+
+        // 移动后仍在共享目录以内
+        copy(&share, &path("/a/b"), &path("/a/c"), false, &responder).unwrap();
+        assert_eq!(fs::read_to_string(root.join("a/c/up")).unwrap(), "f");
+        rename(&share, &path("/a/c/up"), &path("/a/b/up2"), false).unwrap();
+        assert_eq!(fs::read_to_string(root.join("a/b/up2")).unwrap(), "f");
+    }
+}
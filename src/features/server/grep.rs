@@ -0,0 +1,243 @@
+use std::{collections::VecDeque, fs, io::{self, BufRead as _, BufReader, Read as _}, mem, path::{Path, PathBuf}};
+
+use regex::{Regex, RegexBuilder};
+
+use crate::features::commands::{ApiCommand, CommandData, CommandMessage, ErrorCode, FtPath, GrepMatch};
+
+use super::{command_handler::{content_path, io_error, HandlerResult}, responder::Responder, search::{walk, Matcher}, ShareConfig};
+
+/// 单次查找最多返回的匹配行数
+const MAX_GREP_MATCHES: usize = 1000;
+/// 默认跳过大于该字节数的文件
+const DEFAULT_MAX_FILE_SIZE: u64 = 16 << 20;
+const MAX_CONTEXT_LINES: usize = 10;
+/// 超过该字节数的行被截断
+const MAX_LINE_SIZE: usize = 1024;
+/// 文件开头包含 NUL 字节时视为二进制文件
+const BINARY_CHECK_SIZE: usize = 8192;
+/// 每批返回的匹配数, 长时间没有结果时发送空的一批
+const GREP_BATCH_SIZE: usize = 100;
+
+pub struct GrepQuery<'a> {
+    pub path: &'a FtPath,
+    pub pattern: &'a str,
+    pub regex: bool,
+    pub ignore_case: bool,
+    pub context: usize,
+    pub include: Option<&'a str>,
+    pub max_file_size: u64,
+    pub max_matches: usize,
+}
+
+/// 收集匹配行, 按批发送
+struct GrepSender<'a> {
+    cmd: &'a ApiCommand,
+    responder: &'a Responder,
+    matches: Vec<GrepMatch>,
+    matched: usize,
+    max_matches: usize,
+}
+
+impl GrepSender<'_> {
+    /// 达到上限时返回 false
+    fn push(&mut self, grep_match: GrepMatch) -> bool {
+        if self.matched == self.max_matches {
+            return false;
+        }
+        self.matched += 1;
+        self.matches.push(grep_match);
+        self.flush(false);
+        true
+    }
+
+    fn flush(&mut self, force: bool) {
+        let ready = force || self.matches.len() >= GREP_BATCH_SIZE;
+        self.responder.message_or_keepalive(ready, || CommandMessage {
+            version: self.cmd.version,
+            request_id: self.cmd.request_id,
+            status: 0,
+            data: CommandData::GrepMatches { matches: mem::take(&mut self.matches) },
+        });
+    }
+}
+
+pub fn grep(share: &ShareConfig, cmd: &ApiCommand, query: GrepQuery, responder: &Responder) -> HandlerResult<CommandData> {
+    let max_matches = match query.max_matches {
+        0 => MAX_GREP_MATCHES,
+        max_matches if max_matches > MAX_GREP_MATCHES => {
+            return Err((ErrorCode::TooLarge, format!("max matches {max_matches} exceeds {MAX_GREP_MATCHES}")));
+        },
+        max_matches => max_matches,
+    };
+    if query.context > MAX_CONTEXT_LINES {
+        return Err((ErrorCode::TooLarge, format!("context {} exceeds {MAX_CONTEXT_LINES}", query.context)));
+    }
+    let max_file_size = if query.max_file_size == 0 { DEFAULT_MAX_FILE_SIZE } else { query.max_file_size };
+    let pattern = if query.regex { query.pattern.to_string() } else { regex::escape(query.pattern) };
+    let regex = RegexBuilder::new(&pattern)
+        .case_insensitive(query.ignore_case)
+        .build()
+        .map_err(|e| (ErrorCode::BadRequest, format!("invalid regex, {e}")))?;
+    let include = query.include.map(|include| Matcher::new(include, false)).transpose()?;
+
+    let org_root_path = query.path.root_path().clone();
+    let path = content_path(share, query.path)?;
+    let full_path = PathBuf::from(path.full_path());
+    let meta = fs::metadata(&full_path).map_err(|e| io_error(e, &path))?;
+
+    let mut sender = GrepSender { cmd, responder, matches: vec![], matched: 0, max_matches };
+    let (mut scanned, mut skipped, mut truncated) = (0, 0, false);
+    let mut scan = |file: &Path, size: u64| {
+        sender.flush(false);
+        if size > max_file_size {
+            skipped += 1;
+            return true;
+        }
+        let mut ft_path = FtPath::new_absolute(share.root_path.clone(), file.to_string_lossy().to_string());
+        ft_path.reset_root(&org_root_path);
+        match grep_file(file, &ft_path, &regex, query.context, &mut sender) {
+            Ok(Some(completed)) => {
+                scanned += 1;
+                truncated = !completed;
+                completed
+            },
+            Ok(None) => {
+                skipped += 1;
+                true
+            },
+            Err(e) => {
+                eprintln!("grep {} failed, err: {e}", file.display());
+                skipped += 1;
+                true
+            },
+        }
+    };
+    if meta.is_file() {
+        scan(&full_path, meta.len());
+    } else {
+        walk(&share.root_path, &full_path, |file, meta| {
+            if responder.is_cancelled() {
+                return false;
+            }
+            let name = file.file_name().map(|name| name.to_string_lossy()).unwrap_or_default();
+            if !meta.is_file() || include.as_ref().is_some_and(|include| !include.is_match(&name)) {
+                return true;
+            }
+            scan(file, meta.len())
+        });
+    }
+    if !sender.matches.is_empty() {
+        sender.flush(true);
+    }
+    Ok(CommandData::Grep { scanned, skipped, matched: sender.matched, truncated })
+}
+
+/// 查找一个文件, 二进制文件返回 None; 达到匹配上限或请求取消时返回 Some(false)
+fn grep_file(file: &Path, path: &FtPath, regex: &Regex, context: usize, sender: &mut GrepSender) -> io::Result<Option<bool>> {
+    let mut reader = BufReader::new(fs::File::open(file)?);
+    if reader.fill_buf()?.iter().take(BINARY_CHECK_SIZE).any(|b| *b == 0) {
+        return Ok(None);
+    }
+    let mut before: VecDeque<String> = VecDeque::with_capacity(context);
+    // note: 等待补充后续行的匹配, 第二项为还需要的行数
+    let mut pending: Vec<(GrepMatch, usize)> = vec![];
+    let mut buf = vec![];
+    let mut line_number = 0;
+    loop {
+        buf.clear();
+        // note: 过长的行只保留开头部分, 其余部分直接跳过, 不会因为单行过大占用内存
+        let size = (&mut reader).take(MAX_LINE_SIZE as u64).read_until(b'\n', &mut buf)?;
+        if size == 0 {
+            break ;
+        }
+        if buf.last() != Some(&b'\n') && size == MAX_LINE_SIZE {
+            reader.skip_until(b'\n')?;
+        }
+        line_number += 1;
+        if line_number % 4096 == 0 {
+            if sender.responder.is_cancelled() {
+                return Ok(Some(false));
+            }
+            sender.flush(false);
+        }
+        let line = String::from_utf8_lossy(&buf).trim_end_matches(['\n', '\r']).to_string();
+        for (grep_match, remaining) in pending.iter_mut() {
+            grep_match.after.push(line.clone());
+            *remaining -= 1;
+        }
+        // note: 匹配按行号顺序加入, 先加入的先补充完
+        while pending.first().is_some_and(|(_, remaining)| *remaining == 0) {
+            if !sender.push(pending.remove(0).0) {
+                return Ok(Some(false));
+            }
+        }
+        if regex.is_match(&line) {
+            let grep_match = GrepMatch {
+                path: path.clone(),
+                line_number,
+                line: line.clone(),
+                before: before.iter().cloned().collect(),
+                after: vec![],
+            };
+            if context > 0 {
+                pending.push((grep_match, context));
+            } else if !sender.push(grep_match) {
+                return Ok(Some(false));
+            }
+        }
+        if context > 0 {
+            if before.len() == context {
+                before.pop_front();
+            }
+            before.push_back(line);
+        }
+    }
+    for (grep_match, _) in pending {
+        if !sender.push(grep_match) {
+            return Ok(Some(false));
+        }
+    }
+    Ok(Some(true))
+}
+
+#[cfg(test)]
+mod test_grep {
+    use std::{fs, sync::mpsc::channel};
+
+    use regex::Regex;
+
+    use crate::{common::test_util::TempDir, features::{commands::{ApiCommand, Command, FtPath}, server::responder::Responder}};
+
+    use super::{grep_file, GrepSender};
+
+    #[test]
+    fn test_grep_file() {
+        let dir = TempDir::new("grep");
+        let text = dir.join("app.log");
+        fs::write(&text, format!("start\nerror: a\nok\nerror: b\n{}\nend", "x".repeat(2000))).unwrap();
+        let binary = dir.join("app.bin");
+        fs::write(&binary, b"error\0binary").unwrap();
+
+        let (tx, _rx) = channel();
+        let responder = Responder::new(&tx, "client", 1);
+        let cmd = ApiCommand::new(Command::ReadConfig { with_stats: false });
+        let mut sender = GrepSender { cmd: &cmd, responder: &responder, matches: vec![], matched: 0, max_matches: 10 };
+        let path = FtPath::new_relative("/".to_string(), "app.log".to_string());
+        let regex = Regex::new("error").unwrap();
+
+        assert!(grep_file(&binary, &path, &regex, 1, &mut sender).unwrap().is_none());
+        assert_eq!(grep_file(&text, &path, &regex, 1, &mut sender).unwrap(), Some(true));
+        assert_eq!(sender.matched, 2);
+        let lines: Vec<_> = sender.matches.iter()
+            .map(|m| (m.line_number, m.line.as_str(), m.before.clone(), m.after.clone()))
+            .collect();
+        assert_eq!(lines[0], (2, "error: a", vec!["start".to_string()], vec!["ok".to_string()]));
+        assert_eq!(lines[1].0, 4);
+        // 过长的行被截断, 行号不受影响
+        assert_eq!(lines[1].3[0].len(), 1024);
+
+        sender.max_matches = 3;
+        assert_eq!(grep_file(&text, &path, &Regex::new("^e").unwrap(), 0, &mut sender).unwrap(), Some(false));
+        assert_eq!(sender.matched, 3);
+    }
+}
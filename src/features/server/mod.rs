@@ -1,32 +1,51 @@
-use std::cmp::min;
-use std::fs::{self, ReadDir};
-use std::{sync::mpsc::channel, thread};
+use std::io;
+use std::path::Path;
+use std::sync::{mpsc::channel, Arc, Mutex};
+use std::thread;
 
-use std::io::{Read, Seek, SeekFrom};
-use clap::Parser;
+use clap::{Parser, ValueEnum as _};
 use websocket::{Message, OwnedMessage};
 use crate::common;
-use crate::features::commands::{self, CommandData, CommandMessage, DirItem, ApiCommand};
+use crate::features::commands::{Command, CommandData, CommandMessage, DirItem, DirItemInfo, ApiCommand, ErrorCode, SymlinkPolicy};
+use crate::features::crypto::{self, KeyExchange, Role, Session, SessionStore};
+use crate::features::encoding::Encoding;
+use crate::features::frame::{Frame, FrameType};
+use crate::features::handshake::{Handshake, HandshakeResult, FEATURE_FLOW_CONTROL};
+
+use auth::Auth;
+use chksum::ChksumCache;
+use responder::{InFlight, Responder};
+use snapshot::Snapshots;
+use watcher::WatchHub;
 
 use super::commands::FtPath;
 
+mod auth;
+mod chksum;
 mod cli_command;
 mod command_handler;
+mod file_ops;
+mod grep;
+mod responder;
+mod search;
+mod snapshot;
+mod tree;
+mod upload;
+mod watcher;
 
 
 pub fn main() {
     use cli_command::Commands;
-    use common::{config, utils};
+    use common::config;
     let cli = cli_command::Cli::parse();
-    let mut config = config::Config::new(
-        Some(utils::config_dir()), 
-        Some(config::FILE_TUNNEL_CFG_SERVER.to_string()),
-        Some(config::FILE_TUNNEL_ENDPOINT_SERVER.to_string()),
-    );
-    config.init();
+    let mut config = open_config();
     match &cli.command {
-        Commands::SetConfig { path, tunnel_host, password } => {
+        Commands::SetConfig { path, tunnel_host, password, name, writable, allow, symlinks } => {
             config.set(config::CFG_PATH.to_string(), path.clone(), None);
+            config.set(config::CFG_SHARE_NAME.to_string(), name.clone().unwrap_or_default(), None);
+            config.set(config::CFG_READ_ONLY.to_string(), (!writable).to_string(), None);
+            config.set(config::CFG_FILE_OPS.to_string(), allow.join(","), None);
+            config.set(config::CFG_SYMLINKS.to_string(), format!("{symlinks:?}").to_lowercase(), None);
             config.set(config::CFG_TUNNEL_HOST.to_string(), tunnel_host.clone(), None);
             config.set(config::CFG_SHARE_KEY.to_string(), common::gen_uuid(), None);
             match password {
@@ -41,9 +60,9 @@ pub fn main() {
         Commands::ShowConfig { names } => {
             let names: Vec<String> = match names {
                 Some(names) => {
-                    let new_names: Vec<String> = names.into_iter()
+                    let new_names: Vec<String> = names.iter()
                             .filter(|name| config.keys().contains(*name))
-                            .map(|f| f.clone())
+                            .cloned()
                             .collect();
                     if new_names.len() != names.len() {
                         eprintln!("some cfg name be ignored");
@@ -52,8 +71,8 @@ pub fn main() {
                 },
                 None => {
                     let new_names: Vec<String> = config.keys().iter()
-                                    .filter(|f| (**f) != config::CFG_PASSWORD.to_string())
-                                    .map(|f| f.clone())
+                                    .filter(|f| *f != config::CFG_PASSWORD)
+                                    .cloned()
                                     .collect();
                     new_names
                 }
@@ -68,8 +87,12 @@ pub fn main() {
                 config::CFG_TUNNEL_HOST.to_string()
             ]));
 
-            let share_key_chars: Vec<u8> = config_values.get(&config::CFG_SHARE_KEY.to_string()).unwrap().as_bytes().into_iter().map(|c| *c).collect();
-            let tunnel_host = config_values.get(&config::CFG_TUNNEL_HOST.to_string()).unwrap();
+            let config = Arc::new(Mutex::new(config));
+            let chksums = Arc::new(ChksumCache::new(config.clone()).expect("open chksum cache failed"));
+            chksum::spawn_warm(chksums.clone());
+
+            let share_key_chars: Vec<u8> = config_values.get(config::CFG_SHARE_KEY).unwrap().as_bytes().to_vec();
+            let tunnel_host = config_values.get(config::CFG_TUNNEL_HOST).unwrap();
             let mut headers = websocket::header::Headers::new();
             headers.append_raw("X-Share-Key", share_key_chars);
             let client = websocket::ClientBuilder::new(
@@ -84,6 +107,8 @@ pub fn main() {
 
             let (tx, rx) = channel();
             let tx1 = tx.clone();
+            let handshake = Frame::new(FrameType::Handshake, "", 0, serde_json::to_vec(&Handshake::local()).unwrap());
+            let _ = tx.send(OwnedMessage::Binary(handshake.encode().unwrap()));
             let send_loop = thread::spawn(move|| {
                 loop {
                     let message = match rx.recv() {
@@ -94,13 +119,10 @@ pub fn main() {
                         }
                     };
 
-                    match message {
-                        OwnedMessage::Close(_) => {
-                            println!("msg: {:?}", message);
-                            let _ = sender.send_message(&message);
-                            return ;
-                        }
-                        _ => {}
+                    if let OwnedMessage::Close(_) = message {
+                        println!("msg: {:?}", message);
+                        let _ = sender.send_message(&message);
+                        return ;
                     };
 
                     match sender.send_message(&message) {
@@ -114,6 +136,12 @@ pub fn main() {
                 }
             });
             let recv_loop = thread::spawn(move|| {
+                let mut protocol: Option<Handshake> = None;
+                let mut sessions = SessionStore::default();
+                let auth = Arc::new(Mutex::new(Auth::default()));
+                let in_flight = Arc::new(InFlight::default());
+                let snapshots = Arc::new(Mutex::new(Snapshots::default()));
+                let watches = Arc::new(WatchHub::default());
                 for message in recver.incoming_messages() {
                     let message = match message {
                         Ok(m) => m,
@@ -135,44 +163,99 @@ pub fn main() {
                             println!(">: {:?}", data);
                         },
                         OwnedMessage::Binary(bin) => {
-                            println!(">bin: {:?}", bin);
-                        }
-                        OwnedMessage::Text(txt) => {
-                            if txt.len() > 50 {
-                                println!("txt: {}", &txt[..50]);
-                            } else {
-                                println!("txt: {}", txt);
+                            let frame = match Frame::decode(&bin) {
+                                Ok(frame) => frame,
+                                Err(e) => {
+                                    eprintln!("frame decode failed, err: {e}");
+                                    continue ;
+                                }
+                            };
+                            if frame.frame_type == FrameType::Handshake {
+                                match serde_json::from_slice::<HandshakeResult>(&frame.payload) {
+                                    Ok(HandshakeResult::Accepted { version, features }) => {
+                                        println!("handshake accepted, version: {version}, features: {features:?}");
+                                        protocol = Some(Handshake { min_version: version, max_version: version, features });
+                                    },
+                                    Ok(HandshakeResult::Rejected { message }) => {
+                                        eprintln!("handshake rejected, {message}");
+                                        let _ = tx1.send(OwnedMessage::Close(None));
+                                        break ;
+                                    },
+                                    Err(e) => eprintln!("handshake parse failed, err: {e}"),
+                                }
+                                continue ;
+                            }
+                            if let Some(credits) = frame.window_credits() {
+                                in_flight.grant(&frame.client_key, frame.request_id, credits);
+                                continue ;
+                            }
+                            if frame.frame_type == FrameType::Cancel {
+                                if in_flight.cancel(&frame.client_key, frame.request_id) {
+                                    println!("request {} of {:?} cancelled", frame.request_id, frame.client_key);
+                                }
+                                continue ;
+                            }
+                            if frame.frame_type != FrameType::Command {
+                                eprintln!("unexpected frame type: {:?}", frame.frame_type);
+                                continue ;
+                            }
+                            let mut responder = Responder::new(&tx1, &frame.client_key, frame.request_id);
+                            let parsed = parse_command(&frame, &sessions);
+                            if let Ok((_, Some(session))) = &parsed {
+                                responder.set_session(session.clone());
                             }
-                            match txt.split_once(":") {
-                                Some((client_key_size_str, next_data)) => {
-                                    let client_key_size: usize = client_key_size_str.to_string().parse().unwrap();
-                                    let client_key = next_data[..client_key_size].to_string();
-                                    let next_data = next_data[client_key_size..].to_string();
-                                    let cmd: Result<ApiCommand, serde_json::Error> = serde_json::from_str(&next_data);
-                                    let root_path = config.get_key(config::CFG_PATH.to_string()).unwrap();
-                                    match cmd {
-                                        Ok(cmd) => {
-                                            command_handler::handler(&tx1, root_path.as_str(), client_key.as_str(), &cmd);
-                                            let mut msg = vec![client_key_size as u8];
-                                            let chars: Vec<u8> = client_key.chars().map(|c| c as u8).collect();
-                                            msg.extend(chars.iter());
-                                            msg.extend(vec![0u8;4]);
-                                            eprintln!("bin_msg: {:?}", msg);
-                                            let _ = tx1.send(OwnedMessage::Binary(msg)).unwrap();
-                                        },
-                                        Err(e) => {
-                                            let mut msg = vec![client_key_size as u8];
-                                            let chars: Vec<u8> = client_key.chars().map(|c| c as u8).collect();
-                                            msg.extend(chars.iter());
-                                            msg.extend(vec![0u8;4]);
-                                            eprintln!("bin_msg 111: {:?}", msg);
-                                            println!("cmd parse failed, err:{e}, txt: {txt}");
-                                            let _ = tx1.send(OwnedMessage::Binary(vec![0u8; 4])).unwrap();
-                                        }
+                            let reply = match parsed {
+                                Ok((cmd, _)) if !Handshake::local().supports(cmd.version) || protocol.is_none() => {
+                                    println!("unsupported protocol version: {}, handshake: {:?}", cmd.version, protocol);
+                                    Some(CommandMessage::error(
+                                        cmd.version,
+                                        frame.request_id,
+                                        ErrorCode::UnsupportedVersion,
+                                        format!("unsupported protocol version {}", cmd.version),
+                                    ))
+                                },
+                                Ok((cmd, _)) if cmd.request_id != frame.request_id => {
+                                    println!("request id mismatch, frame: {}, cmd: {}", frame.request_id, cmd.request_id);
+                                    Some(CommandMessage::error(cmd.version, frame.request_id, ErrorCode::BadRequest, "request id mismatch".to_string()))
+                                },
+                                Ok((cmd, None)) => {
+                                    let password = config.lock().unwrap().get_key(config::CFG_PASSWORD.to_string());
+                                    Some(open_session(&cmd, &frame.client_key, password, &mut sessions))
+                                },
+                                Ok((cmd, Some(_session))) => {
+                                    responder.set_codecs(&cmd.codecs);
+                                    // note: 在接收线程中登记, 保证之后到达的 Cancel frame 能找到该请求
+                                    let flow_control = protocol.as_ref().is_some_and(|protocol| protocol.has_feature(FEATURE_FLOW_CONTROL));
+                                    if !responder.track(in_flight.clone(), flow_control) {
+                                        println!("too many requests in flight, client: {}", frame.client_key);
+                                        responder.message(&CommandMessage::error(cmd.version, cmd.request_id, ErrorCode::Unavailable, "too many requests in flight, try again later".to_string()));
+                                        responder.end();
+                                        continue ;
                                     }
+                                    let share = ShareConfig::load(&mut config.lock().unwrap());
+                                    let auth = auth.clone();
+                                    let snapshots = snapshots.clone();
+                                    let chksums = chksums.clone();
+                                    let watches = watches.clone();
+                                    // note: 每个请求在独立线程中处理, 同一 client 的多个请求互不阻塞, 线程数受进行中的请求数限制
+                                    thread::spawn(move || {
+                                        command_handler::handler(&share, &cmd, &responder, &auth, &snapshots, &chksums, &watches);
+                                        responder.end();
+                                    });
+                                    continue ;
                                 },
-                                None => {}
+                                Err((code, message)) => {
+                                    println!("{message}, client: {}", frame.client_key);
+                                    Some(CommandMessage::error(1, frame.request_id, code, message))
+                                }
+                            };
+                            if let Some(message) = reply {
+                                responder.message(&message);
                             }
+                            responder.end();
+                        }
+                        OwnedMessage::Text(txt) => {
+                            println!("txt: {}", txt);
                         }
                     }
                 }
@@ -188,13 +271,202 @@ pub fn main() {
     }
 }
 
-fn build_item(path: &str, root: &str, org_root: &str) -> DirItem {
-    let mut item = DirItem::from(path);
-    item.path.replace_root_path(&root);
-    item.path.reset_root(&org_root);
-    item
+fn open_config() -> common::config::Config {
+    use common::{config, utils};
+    let mut config = config::Config::new(
+        Some(utils::config_dir()),
+        Some(config::FILE_TUNNEL_CFG_SERVER.to_string()),
+        Some(config::FILE_TUNNEL_ENDPOINT_SERVER.to_string()),
+    );
+    config.init();
+    config
+}
+
+/// 处理命令时使用的共享配置, 每个命令读取一次, 修改配置后无需重启 server
+pub struct ShareConfig {
+    pub root_path: String,
+    pub password: Option<String>,
+    pub name: String,
+    pub read_only: bool,
+    /// 允许的文件操作, 见 `file_ops::FILE_OPS`
+    pub file_ops: Vec<String>,
+    pub symlinks: SymlinkPolicy,
 }
 
-fn dir_iter(ft_path: &FtPath) -> ReadDir {
-    fs::read_dir(ft_path.full_path()).unwrap()
+impl ShareConfig {
+    fn load(config: &mut common::config::Config) -> Self {
+        use common::config;
+        let root_path = config.get_key(config::CFG_PATH.to_string()).unwrap();
+        let name = config.get_key(config::CFG_SHARE_NAME.to_string())
+            .filter(|name| !name.is_empty())
+            .or_else(|| Path::new(&root_path).file_name().map(|name| name.to_string_lossy().to_string()))
+            .unwrap_or("/".to_string());
+        // note: 未设置时按只读处理
+        let read_only = config.get_key(config::CFG_READ_ONLY.to_string()).is_none_or(|value| value != "false");
+        let file_ops = config.get_key(config::CFG_FILE_OPS.to_string())
+            .map(|ops| ops.split(',').filter(|op| !op.is_empty()).map(|op| op.to_string()).collect())
+            .unwrap_or_default();
+        let symlinks = config.get_key(config::CFG_SYMLINKS.to_string())
+            .and_then(|value| SymlinkPolicy::from_str(&value, true).ok())
+            .unwrap_or_default();
+        Self {
+            password: config.get_key(config::CFG_PASSWORD.to_string()),
+            root_path,
+            name,
+            read_only,
+            file_ops,
+            symlinks,
+        }
+    }
+
+    /// 测试使用的共享配置, 默认只读且跟随符号链接
+    #[cfg(test)]
+    pub fn new(root_path: impl AsRef<Path>) -> Self {
+        Self {
+            root_path: root_path.as_ref().to_string_lossy().to_string(),
+            password: None,
+            name: String::new(),
+            read_only: true,
+            file_ops: vec![],
+            symlinks: SymlinkPolicy::Follow,
+        }
+    }
+
+    #[cfg(test)]
+    pub fn writable(self, file_ops: &[&str]) -> Self {
+        Self { read_only: false, file_ops: file_ops.iter().map(|op| op.to_string()).collect(), ..self }
+    }
+
+    #[cfg(test)]
+    pub fn with_symlinks(self, symlinks: SymlinkPolicy) -> Self {
+        Self { symlinks, ..self }
+    }
+
+    /// 按策略不返回该路径(路径本身为符号链接)
+    pub fn hides(&self, path: &Path) -> bool {
+        self.symlinks == SymlinkPolicy::Hide && path.is_symlink()
+    }
+
+    /// 按配置是否接受该命令, 修改文件的命令要求可写, 文件操作还需要单独开启
+    pub fn allows(&self, command: &str) -> bool {
+        match (command, file_ops::op_name(command)) {
+            (_, Some(op)) => !self.read_only && self.file_ops.iter().any(|allowed| allowed == op),
+            ("UploadStatus" | "UploadFile", None) => !self.read_only,
+            _ => true,
+        }
+    }
+}
+
+/// 命令解析失败时返回的 (code, message)
+type ParseError = (ErrorCode, String);
+
+/// 解析命令, 加密的命令使用对应的会话解密
+fn parse_command(frame: &Frame, sessions: &SessionStore) -> Result<(ApiCommand, Option<Arc<Session>>), ParseError> {
+    let (payload, session) = match crypto::sealed_session_id(&frame.payload) {
+        Some(session_id) => {
+            // note: 会话不存在或不属于该 client 时同样要求重新建立会话
+            let session = sessions.get(&frame.client_key, &session_id)
+                .ok_or((ErrorCode::Unauthorized, "session expired, open session again".to_string()))?;
+            let aad = crypto::aad(frame.request_id, frame.frame_type, frame.flags());
+            let payload = session.open(&aad, &frame.payload).map_err(|e| (ErrorCode::Unauthorized, e.to_string()))?;
+            (payload, Some(session))
+        },
+        None => (frame.payload.clone(), None),
+    };
+    let encoding = session.as_ref().map(|session| session.encoding()).unwrap_or_default();
+    let cmd = encoding.decode::<ApiCommand>(&payload)
+        .map_err(|e| (ErrorCode::BadRequest, format!("cmd parse failed, {e}")))?;
+    Ok((cmd, session))
+}
+
+/// 明文只接受 `OpenSession` 与 `ConfirmSession`, 其他命令必须在加密会话中发送;
+/// client 的确认值校验通过前, 回复中不包含任何由密码派生的内容
+fn open_session(cmd: &ApiCommand, client_key: &str, password: Option<String>, sessions: &mut SessionStore) -> CommandMessage {
+    let result = match (&cmd.command, password) {
+        (Command::OpenSession { .. }, Some(_)) if !sessions.allow_open(client_key) => {
+            Err((ErrorCode::Unavailable, "too many sessions opened, try again later".to_string()))
+        },
+        (Command::OpenSession { public_key, encodings }, Some(password)) => {
+            let key_exchange = KeyExchange::new(&password);
+            let server_public_key = key_exchange.public_key();
+            let session_id = crypto::gen_session_id();
+            match key_exchange.establish(Role::Server, public_key, &session_id) {
+                Ok(session) => {
+                    let encoding = Encoding::select(encodings);
+                    sessions.insert(client_key, session.with_encoding(encoding));
+                    Ok(CommandData::OpenSession { session_id, public_key: server_public_key, encoding })
+                },
+                Err(e) => Err((ErrorCode::Unauthorized, format!("open session failed, {e}"))),
+            }
+        },
+        (Command::ConfirmSession { session_id, proof }, Some(_)) => match sessions.confirm(client_key, session_id, proof) {
+            Some(session) => Ok(CommandData::ConfirmSession { proof: session.proof(Role::Server) }),
+            None => Err((ErrorCode::Unauthorized, "confirm session failed, password mismatch or session expired".to_string())),
+        },
+        (Command::OpenSession { .. } | Command::ConfirmSession { .. }, None) => {
+            Err((ErrorCode::Unauthorized, "share password not set".to_string()))
+        },
+        _ => Err((ErrorCode::Unauthorized, "encrypted session required".to_string())),
+    };
+    match result {
+        Ok(data) => CommandMessage { version: cmd.version, request_id: cmd.request_id, status: 0, data },
+        Err((code, message)) => CommandMessage::error(cmd.version, cmd.request_id, code, message),
+    }
+}
+
+/// 按共享的符号链接策略读取条目, 隐藏的符号链接按不存在处理
+fn build_item(share: &ShareConfig, path: &str, org_root: &str) -> io::Result<DirItem> {
+    if share.hides(Path::new(path)) {
+        return Err(io::Error::from(io::ErrorKind::NotFound));
+    }
+    let info = DirItemInfo::new(&path.to_string(), share.symlinks == SymlinkPolicy::Follow)?;
+    let mut path = FtPath::new_absolute(share.root_path.clone(), path.to_string());
+    path.reset_root(org_root);
+    Ok(DirItem { path, info })
+}
+
+#[cfg(test)]
+mod test_open_session {
+    use crate::features::{
+        commands::{ApiCommand, Command, CommandData, ErrorCode},
+        crypto::{KeyExchange, Role, SessionStore},
+    };
+
+    use super::open_session;
+
+    fn open(sessions: &mut SessionStore, password: &str) -> (String, String) {
+        let key_exchange = KeyExchange::new(password);
+        let cmd = ApiCommand::new(Command::OpenSession { public_key: key_exchange.public_key(), encodings: vec![] });
+        let message = open_session(&cmd, "client", Some("secret".to_string()), sessions);
+        let CommandData::OpenSession { session_id, public_key, .. } = message.data else {
+            panic!("unexpected message {:?}", message.data);
+        };
+        let session = key_exchange.establish(Role::Client, &public_key, &session_id).unwrap();
+        (session_id, session.proof(Role::Client))
+    }
+
+    #[test]
+    fn test_confirm_session() {
+        let mut sessions = SessionStore::default();
+        let (session_id, proof) = open(&mut sessions, "secret");
+        let cmd = ApiCommand::new(Command::ConfirmSession { session_id: session_id.clone(), proof });
+        let message = open_session(&cmd, "client", Some("secret".to_string()), &mut sessions);
+        assert_eq!(message.status, 0);
+        assert!(matches!(message.data, CommandData::ConfirmSession { .. }));
+        assert!(sessions.get("client", &session_id).is_some());
+    }
+
+    #[test]
+    fn test_confirm_session_wrong_proof() {
+        let mut sessions = SessionStore::default();
+        let (session_id, proof) = open(&mut sessions, "guess");
+        let cmd = ApiCommand::new(Command::ConfirmSession { session_id: session_id.clone(), proof });
+        let message = open_session(&cmd, "client", Some("secret".to_string()), &mut sessions);
+        // 只回复固定的错误信息, 不包含 server 的确认值, 无法据此离线猜测密码
+        let CommandData::Error { code: ErrorCode::Unauthorized, message } = message.data else {
+            panic!("unexpected message {:?}", message.data);
+        };
+        assert_eq!(message, "confirm session failed, password mismatch or session expired");
+        assert!(sessions.get("client", &session_id).is_none());
+    }
 }
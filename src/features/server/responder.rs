@@ -0,0 +1,338 @@
+use std::{
+    collections::HashMap,
+    sync::{atomic::{AtomicBool, Ordering}, mpsc::Sender, Arc, Condvar, Mutex},
+    time::{Duration, Instant},
+};
+
+use websocket::OwnedMessage;
+
+use crate::features::{
+    codec::Codec,
+    commands::CommandMessage,
+    crypto::{self, Session},
+    frame::{Frame, FrameType, FLOW_CONTROL_WINDOW},
+};
+
+/// 单个 Data frame 的最大字节数, 大的数据块拆分为多个 frame 发送
+pub const DATA_FRAME_SIZE: usize = 1 << 16;
+
+/// 未收到 WindowUpdate 的最长等待时间, 超时视为 tunnel 已放弃该请求
+const FLOW_CONTROL_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// 流式命令长时间没有发送时发送一次进度或空的一批, 避免 tunnel 等待超时(60 秒)
+pub const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// 每个请求占用一个处理线程, 同一 client 与所有 client 同时处理的请求数上限
+pub const MAX_CLIENT_IN_FLIGHT: usize = 32;
+pub const MAX_IN_FLIGHT: usize = 256;
+
+/// 单个请求的取消标记与发送窗口; credits 为 None 时不限制发送
+#[derive(Default)]
+struct RequestState {
+    cancelled: AtomicBool,
+    credits: Mutex<Option<u32>>,
+    credits_changed: Condvar,
+}
+
+impl RequestState {
+    fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+        self.credits_changed.notify_all();
+    }
+
+    fn grant(&self, credits: u32) {
+        if let Some(window) = self.credits.lock().unwrap().as_mut() {
+            *window = window.saturating_add(credits);
+        }
+        self.credits_changed.notify_all();
+    }
+
+    /// 等待并占用一个 frame 的发送窗口, 请求被取消或等待超时时返回 false
+    fn acquire(&self) -> bool {
+        let mut credits = self.credits.lock().unwrap();
+        let deadline = Instant::now() + FLOW_CONTROL_TIMEOUT;
+        loop {
+            if self.cancelled.load(Ordering::Relaxed) {
+                return false;
+            }
+            match credits.as_mut() {
+                None => return true,
+                Some(window) if *window > 0 => {
+                    *window -= 1;
+                    return true;
+                },
+                Some(_) => {},
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                eprintln!("waiting for window update time out");
+                self.cancelled.store(true, Ordering::Relaxed);
+                return false;
+            }
+            credits = self.credits_changed.wait_timeout(credits, deadline - now).unwrap().0;
+        }
+    }
+}
+
+/// 进行中的请求, 收到 tunnel 的 Cancel frame 时标记对应请求已取消,
+/// 收到 WindowUpdate frame 时增加对应请求的发送窗口
+#[derive(Default)]
+pub struct InFlight {
+    requests: Mutex<HashMap<(String, u64), Arc<RequestState>>>,
+}
+
+impl InFlight {
+    /// 进行中的请求数达到上限时返回 None
+    fn register(&self, client_key: &str, request_id: u64, flow_control: bool) -> Option<Arc<RequestState>> {
+        let mut requests = self.requests.lock().unwrap();
+        if requests.len() >= MAX_IN_FLIGHT || requests.keys().filter(|(key, _)| key == client_key).count() >= MAX_CLIENT_IN_FLIGHT {
+            return None;
+        }
+        let state = Arc::new(RequestState::default());
+        if flow_control {
+            *state.credits.lock().unwrap() = Some(FLOW_CONTROL_WINDOW);
+        }
+        requests.insert((client_key.to_string(), request_id), state.clone());
+        Some(state)
+    }
+
+    fn finish(&self, client_key: &str, request_id: u64) {
+        self.requests.lock().unwrap().remove(&(client_key.to_string(), request_id));
+    }
+
+    fn get(&self, client_key: &str, request_id: u64) -> Option<Arc<RequestState>> {
+        self.requests.lock().unwrap().get(&(client_key.to_string(), request_id)).cloned()
+    }
+
+    /// 请求已结束时返回 false
+    pub fn cancel(&self, client_key: &str, request_id: u64) -> bool {
+        self.get(client_key, request_id).map(|state| state.cancel()).is_some()
+    }
+
+    pub fn grant(&self, client_key: &str, request_id: u64, credits: u32) {
+        if let Some(state) = self.get(client_key, request_id) {
+            state.grant(credits);
+        }
+    }
+}
+
+/// 按 frame 发送一个请求的响应，以 end_of_stream 结束
+pub struct Responder {
+    tx: Sender<OwnedMessage>,
+    client_key: String,
+    request_id: u64,
+    codec: Codec,
+    session: Option<Arc<Session>>,
+    in_flight: Option<Arc<InFlight>>,
+    state: Arc<RequestState>,
+    last_sent: Mutex<Instant>,
+}
+
+impl Responder {
+    pub fn new(tx: &Sender<OwnedMessage>, client_key: &str, request_id: u64) -> Self {
+        Self {
+            tx: tx.clone(),
+            client_key: client_key.to_string(),
+            request_id,
+            codec: Codec::Identity,
+            session: None,
+            in_flight: None,
+            state: Arc::new(RequestState::default()),
+            last_sent: Mutex::new(Instant::now()),
+        }
+    }
+
+    pub fn client_key(&self) -> &str {
+        &self.client_key
+    }
+
+    /// 加密会话中的请求，响应 payload 使用同一会话加密
+    pub fn set_session(&mut self, session: Arc<Session>) {
+        self.session = Some(session);
+    }
+
+    /// 登记为进行中的请求, 以便 tunnel 取消; 响应结束时自动移除;
+    /// flow_control 为 true 时发送窗口用完后等待 tunnel 的 WindowUpdate; 进行中的请求过多时返回 false
+    pub fn track(&mut self, in_flight: Arc<InFlight>, flow_control: bool) -> bool {
+        match in_flight.register(&self.client_key, self.request_id, flow_control) {
+            Some(state) => {
+                self.state = state;
+                self.in_flight = Some(in_flight);
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// 请求被取消后不再发送任何 frame, 耗时的命令应及时检查并停止处理
+    pub fn is_cancelled(&self) -> bool {
+        self.state.cancelled.load(Ordering::Relaxed)
+    }
+
+    /// 根据 client 声明的 codec 选择响应使用的压缩方式
+    pub fn set_codecs(&mut self, accepted: &[Codec]) {
+        self.codec = Codec::select(accepted);
+    }
+}
+
+impl Responder {
+    pub fn message(&self, message: &CommandMessage) {
+        let encoding = self.session.as_ref().map(|session| session.encoding()).unwrap_or_default();
+        let payload = match encoding.encode(message) {
+            Ok(payload) => payload,
+            Err(e) => {
+                eprintln!("message encode failed, err: {e}");
+                return ;
+            }
+        };
+        self.send(self.compress(FrameType::Message, payload));
+    }
+
+    /// ready 为 false 时只在距上次发送超过 `KEEPALIVE_INTERVAL` 时发送, 返回是否已发送;
+    /// 流式命令按批发送结果时使用, message 只在发送时生成
+    pub fn message_or_keepalive(&self, ready: bool, message: impl FnOnce() -> CommandMessage) -> bool {
+        if !ready && self.last_sent.lock().unwrap().elapsed() < KEEPALIVE_INTERVAL {
+            return false;
+        }
+        self.message(&message());
+        true
+    }
+
+    /// 已压缩的文件格式 compressible 为 false, 原样发送
+    pub fn data(&self, data: Vec<u8>, compressible: bool) {
+        if compressible {
+            self.send(self.compress(FrameType::Data, data));
+        } else {
+            self.send(Frame::new(FrameType::Data, &self.client_key, self.request_id, data));
+        }
+    }
+
+    pub fn end(self) {
+        self.send(Frame::new(FrameType::Message, &self.client_key, self.request_id, vec![]).end_of_stream());
+    }
+
+    fn compress(&self, frame_type: FrameType, payload: Vec<u8>) -> Frame {
+        match self.codec.try_compress(&payload) {
+            Some(compressed) => Frame::new(frame_type, &self.client_key, self.request_id, compressed).with_codec(self.codec),
+            None => Frame::new(frame_type, &self.client_key, self.request_id, payload),
+        }
+    }
+
+    /// client 读取缓慢时在此阻塞, 命令处理随之暂停读取
+    fn send(&self, mut frame: Frame) {
+        if !self.state.acquire() {
+            return ;
+        }
+        if let Some(session) = &self.session {
+            let aad = crypto::aad(frame.request_id, frame.frame_type, frame.flags());
+            match session.seal(&aad, &frame.payload) {
+                Ok(sealed) => frame.payload = sealed,
+                Err(e) => {
+                    eprintln!("frame seal failed, err: {e}");
+                    return ;
+                }
+            }
+        }
+        match frame.encode() {
+            Ok(bin) => {
+                let _ = self.tx.send(OwnedMessage::Binary(bin));
+                *self.last_sent.lock().unwrap() = Instant::now();
+            },
+            Err(e) => eprintln!("frame encode failed, err: {e}"),
+        }
+    }
+}
+
+impl Drop for Responder {
+    fn drop(&mut self) {
+        if let Some(in_flight) = &self.in_flight {
+            in_flight.finish(&self.client_key, self.request_id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_responder {
+    use std::{sync::{mpsc::channel, Arc}, thread, time::Duration};
+
+    use crate::features::{commands::{CommandMessage, ErrorCode}, frame::FLOW_CONTROL_WINDOW};
+
+    use super::{InFlight, Responder, KEEPALIVE_INTERVAL, MAX_CLIENT_IN_FLIGHT};
+
+    #[test]
+    fn test_responder_cancel() {
+        let (tx, rx) = channel();
+        let in_flight = Arc::new(InFlight::default());
+        let mut responder = Responder::new(&tx, "client", 7);
+        assert!(responder.track(in_flight.clone(), false));
+        responder.data(vec![1, 2, 3], true);
+        assert!(rx.try_recv().is_ok());
+
+        assert!(!in_flight.cancel("client", 8));
+        assert!(in_flight.cancel("client", 7));
+        assert!(responder.is_cancelled());
+        responder.data(vec![1, 2, 3], true);
+        responder.end();
+        assert!(rx.try_recv().is_err());
+
+        // 响应结束后请求不再是进行中
+        assert!(!in_flight.cancel("client", 7));
+    }
+
+    #[test]
+    fn test_responder_flow_control() {
+        let (tx, rx) = channel();
+        let in_flight = Arc::new(InFlight::default());
+        let mut responder = Responder::new(&tx, "client", 7);
+        assert!(responder.track(in_flight.clone(), true));
+
+        let sender = thread::spawn(move || {
+            for _ in 0..FLOW_CONTROL_WINDOW + 2 {
+                responder.data(vec![1, 2, 3], true);
+            }
+        });
+        for _ in 0..FLOW_CONTROL_WINDOW {
+            assert!(rx.recv_timeout(Duration::from_secs(1)).is_ok());
+        }
+        // 发送窗口用完后等待 WindowUpdate
+        assert!(rx.recv_timeout(Duration::from_millis(100)).is_err());
+        in_flight.grant("client", 7, 1);
+        assert!(rx.recv_timeout(Duration::from_secs(1)).is_ok());
+        assert!(rx.recv_timeout(Duration::from_millis(100)).is_err());
+
+        // 取消后不再等待
+        assert!(in_flight.cancel("client", 7));
+        sender.join().unwrap();
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_message_or_keepalive() {
+        let (tx, rx) = channel();
+        let responder = Responder::new(&tx, "client", 7);
+        let message = || CommandMessage::error(1, 7, ErrorCode::Internal, String::new());
+        assert!(!responder.message_or_keepalive(false, || panic!("should not build the message")));
+        assert!(responder.message_or_keepalive(true, message));
+        assert!(rx.try_recv().is_ok());
+        *responder.last_sent.lock().unwrap() -= KEEPALIVE_INTERVAL;
+        assert!(responder.message_or_keepalive(false, message));
+        assert!(!responder.message_or_keepalive(false, message));
+    }
+
+    #[test]
+    fn test_in_flight_limit() {
+        let (tx, _rx) = channel();
+        let in_flight = Arc::new(InFlight::default());
+        let mut responders: Vec<_> = (0..MAX_CLIENT_IN_FLIGHT as u64).map(|request_id| {
+            let mut responder = Responder::new(&tx, "client", request_id);
+            assert!(responder.track(in_flight.clone(), false));
+            responder
+        }).collect();
+        let mut responder = Responder::new(&tx, "client", MAX_CLIENT_IN_FLIGHT as u64);
+        assert!(!responder.track(in_flight.clone(), false));
+        // 其他 client 不受影响, 结束一个请求后可以继续
+        assert!(Responder::new(&tx, "other", 0).track(in_flight.clone(), false));
+        responders.pop().unwrap().end();
+        assert!(responder.track(in_flight.clone(), false));
+    }
+}
@@ -0,0 +1,255 @@
+use std::{fs::{self, Metadata}, mem, path::{Path, PathBuf}};
+
+use globset::{Glob, GlobMatcher};
+use regex::Regex;
+
+use crate::features::commands::{ApiCommand, CommandData, CommandMessage, ErrorCode, FtPath, ItemFilter};
+
+use super::{build_item, command_handler::{content_path, io_error, HandlerResult}, file_ops, responder::Responder, upload, ShareConfig};
+
+/// 单次查找最多返回的条目数
+const MAX_SEARCH_RESULTS: usize = 1000;
+/// 每批返回的条目数, 长时间没有结果时发送空的一批
+const SEARCH_BATCH_SIZE: usize = 100;
+
+pub struct SearchQuery<'a> {
+    pub path: &'a FtPath,
+    pub pattern: &'a str,
+    pub regex: bool,
+    pub filter: &'a ItemFilter,
+    pub max_results: usize,
+}
+
+pub(super) enum Matcher {
+    /// 第二项为 true 时匹配相对路径, 否则只匹配名称
+    Glob(GlobMatcher, bool),
+    Regex(Regex),
+}
+
+impl Matcher {
+    pub(super) fn new(pattern: &str, regex: bool) -> HandlerResult<Self> {
+        if regex {
+            let regex = Regex::new(pattern).map_err(|e| (ErrorCode::BadRequest, format!("invalid regex, {e}")))?;
+            return Ok(Self::Regex(regex));
+        }
+        let glob = Glob::new(pattern).map_err(|e| (ErrorCode::BadRequest, format!("invalid glob, {e}")))?;
+        Ok(Self::Glob(glob.compile_matcher(), pattern.contains('/')))
+    }
+
+    pub(super) fn is_match(&self, relative_path: &str) -> bool {
+        match self {
+            Self::Glob(matcher, true) => matcher.is_match(relative_path),
+            Self::Glob(matcher, false) => matcher.is_match(relative_path.rsplit('/').next().unwrap_or_default()),
+            Self::Regex(regex) => regex.is_match(relative_path),
+        }
+    }
+}
+
+/// `ItemFilter` 与编译后的名称 glob
+pub(super) struct EntryFilter<'a> {
+    filter: &'a ItemFilter,
+    name: Option<Matcher>,
+}
+
+impl<'a> EntryFilter<'a> {
+    pub(super) fn new(filter: &'a ItemFilter) -> HandlerResult<Self> {
+        let name = filter.name.as_deref().map(|name| Matcher::new(name, false)).transpose()?;
+        Ok(Self { filter, name })
+    }
+
+    pub(super) fn matches(&self, path: &Path, meta: &Metadata) -> bool {
+        let name = path.file_name().map(|name| name.to_string_lossy()).unwrap_or_default();
+        self.filter.matches(&name, meta) && self.name.as_ref().is_none_or(|matcher| matcher.is_match(&name))
+    }
+}
+
+/// 遍历 dir 下的所有条目(不含 dir 本身), 同一目录按名称排序; 不跟随符号链接, 跳过上传临时文件与回收站;
+/// visit 返回 false 时停止
+pub(super) fn walk(root_path: &str, dir: &Path, mut visit: impl FnMut(&Path, &Metadata) -> bool) {
+    let mut dirs: Vec<PathBuf> = vec![dir.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        let mut entries: Vec<PathBuf> = match fs::read_dir(&dir) {
+            Ok(entries) => entries.flatten()
+                .map(|entry| entry.path())
+                .filter(|path| !upload::is_part_file(path) && !file_ops::is_trash(root_path, path))
+                .collect(),
+            Err(_) => continue,
+        };
+        entries.sort();
+        let mut sub_dirs = vec![];
+        for path in entries {
+            let meta = match fs::symlink_metadata(&path) {
+                Ok(meta) => meta,
+                Err(_) => continue,
+            };
+            if !visit(&path, &meta) {
+                return ;
+            }
+            if meta.is_dir() {
+                sub_dirs.push(path);
+            }
+        }
+        dirs.extend(sub_dirs.into_iter().rev());
+    }
+}
+
+pub fn search(share: &ShareConfig, cmd: &ApiCommand, query: SearchQuery, responder: &Responder) -> HandlerResult<CommandData> {
+    let max_results = match query.max_results {
+        0 => MAX_SEARCH_RESULTS,
+        max_results if max_results > MAX_SEARCH_RESULTS => {
+            return Err((ErrorCode::TooLarge, format!("max results {max_results} exceeds {MAX_SEARCH_RESULTS}")));
+        },
+        max_results => max_results,
+    };
+    let matcher = Matcher::new(query.pattern, query.regex)?;
+    let filter = EntryFilter::new(query.filter)?;
+    let org_root_path = query.path.root_path().clone();
+    let dir_path = content_path(share, query.path)?;
+    let dir = PathBuf::from(dir_path.full_path());
+    if !fs::metadata(&dir).map_err(|e| io_error(e, &dir_path))?.is_dir() {
+        return Err((ErrorCode::NotADirectory, format!("/{}: not a directory", dir_path.path())));
+    }
+
+    let batch = |items: Vec<_>| CommandMessage {
+        version: cmd.version,
+        request_id: cmd.request_id,
+        status: 0,
+        data: CommandData::SearchItems { items },
+    };
+    let (mut matched, mut truncated) = (0, false);
+    let mut items = vec![];
+    walk(&share.root_path, &dir, |path, meta| {
+        if responder.is_cancelled() {
+            return false;
+        }
+        if share.hides(path) {
+            return true;
+        }
+        let relative_path = path.strip_prefix(&dir).unwrap_or(path).to_string_lossy();
+        if filter.matches(path, meta) && matcher.is_match(&relative_path) {
+            if matched == max_results {
+                truncated = true;
+                return false;
+            }
+            match build_item(share, &path.to_string_lossy(), &org_root_path) {
+                Ok(item) => {
+                    items.push(item);
+                    matched += 1;
+                },
+                Err(e) => eprintln!("skip {}, err: {e}", path.display()),
+            }
+        }
+        responder.message_or_keepalive(items.len() >= SEARCH_BATCH_SIZE, || batch(mem::take(&mut items)));
+        true
+    });
+    if !items.is_empty() {
+        responder.message(&batch(items));
+    }
+    Ok(CommandData::Search { matched, truncated })
+}
+
+#[cfg(test)]
+mod test_search {
+    use std::{fs, sync::mpsc::{channel, Receiver}};
+
+    use websocket::OwnedMessage;
+
+    use crate::{
+        common::test_util::TempDir,
+        features::{
+            commands::{ApiCommand, Command, CommandData, CommandMessage, ErrorCode, FtPath, ItemFilter, ItemType},
+            frame::Frame,
+            server::{responder::Responder, ShareConfig},
+        },
+    };
+
+    use super::{search, Matcher, SearchQuery, MAX_SEARCH_RESULTS};
+
+    /// 返回 (匹配的相对路径, Search 的结果)
+    fn run_search(share: &ShareConfig, path: &str, pattern: &str, regex: bool, filter: ItemFilter, max_results: usize) -> (Vec<String>, CommandData) {
+        let path = FtPath::new_relative("/".to_string(), path.to_string());
+        let cmd = ApiCommand::new(Command::Search { path: path.clone(), pattern: pattern.to_string(), regex, filter: filter.clone(), max_results });
+        let (tx, rx) = channel();
+        let responder = Responder::new(&tx, "client", cmd.request_id);
+        let query = SearchQuery { path: &path, pattern, regex, filter: &filter, max_results };
+        let result = match search(share, &cmd, query, &responder) {
+            Ok(data) => data,
+            Err((code, message)) => CommandData::Error { code, message },
+        };
+        (received_paths(&rx), result)
+    }
+
+    fn received_paths(rx: &Receiver<OwnedMessage>) -> Vec<String> {
+        let mut paths = vec![];
+        while let Ok(OwnedMessage::Binary(bin)) = rx.try_recv() {
+            let frame = Frame::decode(&bin).unwrap();
+            match serde_json::from_slice::<CommandMessage>(&frame.payload).unwrap().data {
+                CommandData::SearchItems { items } => paths.extend(items.iter().map(|item| item.path().path())),
+                data => panic!("unexpected message {data:?}"),
+            }
+        }
+        paths
+    }
+
+    #[test]
+    fn test_search() {
+        let root = TempDir::new("search");
+        fs::create_dir_all(root.join("logs/2024")).unwrap();
+        fs::create_dir_all(root.join(".fttrash")).unwrap();
+        for path in ["logs/app.log", "logs/2024/app.log", ".fttrash/old.log", "readme.md"] {
+            fs::write(root.join(path), "log").unwrap();
+        }
+        fs::write(root.join("big.bin"), [0u8; 100]).unwrap();
+        fs::write(root.join(".up.log.0123456789abcdef.ftpart"), "part").unwrap();
+        let share = ShareConfig::new(&root);
+
+        // 跳过回收站与上传临时文件, 先返回同一目录的条目
+        let (paths, result) = run_search(&share, "/", "*.log", false, ItemFilter::default(), 0);
+        assert_eq!(paths, ["logs/app.log", "logs/2024/app.log"]);
+        assert!(matches!(result, CommandData::Search { matched: 2, truncated: false }));
+        let (paths, result) = run_search(&share, "/", "*", false, ItemFilter::default(), 0);
+        assert_eq!(paths, ["big.bin", "logs", "readme.md", "logs/2024", "logs/app.log", "logs/2024/app.log"]);
+        assert!(matches!(result, CommandData::Search { matched: 6, truncated: false }));
+
+        // 超过 max_results 时截断
+        let (paths, result) = run_search(&share, "/", "*.log", false, ItemFilter::default(), 1);
+        assert_eq!(paths, ["logs/app.log"]);
+        assert!(matches!(result, CommandData::Search { matched: 1, truncated: true }));
+
+        // 正则表达式匹配相对于 path 的路径
+        let dirs = ItemFilter { item_type: Some(ItemType::Dir), ..Default::default() };
+        let (paths, _) = run_search(&share, "/", r"^logs/\d+$", true, dirs, 0);
+        assert_eq!(paths, ["logs/2024"]);
+        let (paths, _) = run_search(&share, "/logs", r"^\d+/", true, ItemFilter::default(), 0);
+        assert_eq!(paths, ["logs/2024/app.log"]);
+        let large = ItemFilter { min_size: Some(50), ..Default::default() };
+        let (paths, _) = run_search(&share, "/", "*", false, large, 0);
+        assert_eq!(paths, ["big.bin"]);
+
+        let (_, result) = run_search(&share, "/", "*", false, ItemFilter::default(), MAX_SEARCH_RESULTS + 1);
+        assert!(matches!(result, CommandData::Error { code: ErrorCode::TooLarge, .. }));
+        let (_, result) = run_search(&share, "/readme.md", "*", false, ItemFilter::default(), 0);
+        assert!(matches!(result, CommandData::Error { code: ErrorCode::NotADirectory, .. }));
+        let (_, result) = run_search(&share, "../", "*", false, ItemFilter::default(), 0);
+        assert!(matches!(result, CommandData::Error { code: ErrorCode::PermissionDenied, .. }));
+        let (_, result) = run_search(&share, "/", "[", false, ItemFilter::default(), 0);
+        assert!(matches!(result, CommandData::Error { code: ErrorCode::BadRequest, .. }));
+    }
+
+    #[test]
+    fn test_matcher() {
+        let matcher = Matcher::new("*.log", false).unwrap();
+        assert!(matcher.is_match("app.log"));
+        assert!(matcher.is_match("logs/2024/app.log"));
+        assert!(!matcher.is_match("app.log.gz"));
+
+        let matcher = Matcher::new("logs/*.log", false).unwrap();
+        assert!(matcher.is_match("logs/app.log"));
+        assert!(!matcher.is_match("app.log"));
+
+        let matcher = Matcher::new(r"20\d{2}/.*\.log$", true).unwrap();
+        assert!(matcher.is_match("logs/2024/app.log"));
+        assert!(!matcher.is_match("logs/app.log"));
+        assert!(Matcher::new("(", true).is_err());
+    }
+}
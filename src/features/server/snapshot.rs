@@ -0,0 +1,112 @@
+use std::{collections::HashMap, path::PathBuf, sync::Arc, time::{Duration, Instant}};
+
+use rand::Rng;
+
+/// 目录快照在最后一次使用后的有效期
+pub const SNAPSHOT_EXPIRE: Duration = Duration::from_secs(5 * 60);
+/// 每个 client 最多保留的快照数, 超出时丢弃最久未使用的
+const MAX_SNAPSHOTS_PER_CLIENT: usize = 16;
+/// 快照 id 的随机字节数, 游标中包含快照 id, 不能被其他 client 猜到
+const SNAPSHOT_ID_SIZE: usize = 16;
+
+/// 一次分页列目录时的条目列表, 已完成过滤与排序
+pub struct Snapshot {
+    pub entries: Arc<Vec<PathBuf>>,
+    /// client 请求时的根目录, 返回的条目使用该根目录
+    pub org_root_path: String,
+}
+
+struct StoredSnapshot {
+    client_key: String,
+    snapshot: Snapshot,
+    used_at: Instant,
+}
+
+/// 按快照 id 保存目录快照, 只有创建快照的 client 可以使用
+#[derive(Default)]
+pub struct Snapshots {
+    snapshots: HashMap<String, StoredSnapshot>,
+}
+
+impl Snapshots {
+    pub fn create(&mut self, client_key: &str, snapshot: Snapshot) -> String {
+        let now = Instant::now();
+        self.snapshots.retain(|_, stored| now.duration_since(stored.used_at) < SNAPSHOT_EXPIRE);
+        let mut owned: Vec<(&String, Instant)> = self.snapshots.iter()
+            .filter(|(_, stored)| stored.client_key == client_key)
+            .map(|(id, stored)| (id, stored.used_at))
+            .collect();
+        if owned.len() >= MAX_SNAPSHOTS_PER_CLIENT {
+            owned.sort_by_key(|(_, used_at)| *used_at);
+            let evicted: Vec<String> = owned.iter()
+                .take(owned.len() + 1 - MAX_SNAPSHOTS_PER_CLIENT)
+                .map(|(id, _)| id.to_string())
+                .collect();
+            evicted.iter().for_each(|id| { self.snapshots.remove(id); });
+        }
+        let id = gen_snapshot_id();
+        self.snapshots.insert(id.clone(), StoredSnapshot { client_key: client_key.to_string(), snapshot, used_at: now });
+        id
+    }
+
+    /// 快照不存在、已过期或不属于该 client 时返回 None
+    pub fn get(&mut self, client_key: &str, id: &str) -> Option<(Arc<Vec<PathBuf>>, String)> {
+        let stored = self.snapshots.get_mut(id)
+            .filter(|stored| stored.client_key == client_key && stored.used_at.elapsed() < SNAPSHOT_EXPIRE)?;
+        stored.used_at = Instant::now();
+        Some((stored.snapshot.entries.clone(), stored.snapshot.org_root_path.clone()))
+    }
+
+    pub fn remove(&mut self, id: &str) {
+        self.snapshots.remove(id);
+    }
+}
+
+/// 十六进制的随机 id, 不包含游标的分隔符 `.`
+fn gen_snapshot_id() -> String {
+    hex::encode(rand::thread_rng().gen::<[u8; SNAPSHOT_ID_SIZE]>())
+}
+
+/// 游标为 `快照 id.下一页的起始位置`, 对 client 不透明
+pub fn encode_cursor(id: &str, offset: usize) -> String {
+    format!("{id}.{offset}")
+}
+
+pub fn decode_cursor(cursor: &str) -> Option<(&str, usize)> {
+    let (id, offset) = cursor.split_once('.')?;
+    Some((id, offset.parse().ok()?))
+}
+
+#[cfg(test)]
+mod test_snapshot {
+    use std::{path::PathBuf, sync::Arc};
+
+    use super::{decode_cursor, encode_cursor, Snapshot, Snapshots, MAX_SNAPSHOTS_PER_CLIENT, SNAPSHOT_ID_SIZE};
+
+    fn snapshot() -> Snapshot {
+        Snapshot { entries: Arc::new(vec![PathBuf::from("/a"), PathBuf::from("/b")]), org_root_path: "/save".to_string() }
+    }
+
+    #[test]
+    fn test_snapshots() {
+        let mut snapshots = Snapshots::default();
+        let id = snapshots.create("client", snapshot());
+        assert_eq!(id.len(), SNAPSHOT_ID_SIZE * 2);
+        assert!(id.chars().all(|c| c.is_ascii_hexdigit()));
+        let (entries, org_root_path) = snapshots.get("client", &id).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(org_root_path, "/save");
+        assert!(snapshots.get("other", &id).is_none());
+
+        let cursor = encode_cursor(&id, 1);
+        assert_eq!(decode_cursor(&cursor), Some((id.as_str(), 1)));
+        assert_eq!(decode_cursor("bad"), None);
+
+        // 超出上限时丢弃最久未使用的快照
+        let ids: Vec<String> = (0..MAX_SNAPSHOTS_PER_CLIENT).map(|_| snapshots.create("client", snapshot())).collect();
+        assert!(snapshots.get("client", &id).is_none());
+        assert!(ids.iter().all(|id| snapshots.get("client", id).is_some()));
+        snapshots.remove(&ids[0]);
+        assert!(snapshots.get("client", &ids[0]).is_none());
+    }
+}
@@ -0,0 +1,172 @@
+use std::{fs, mem, path::PathBuf};
+
+use crate::features::commands::{ApiCommand, CommandData, CommandMessage, ErrorCode, FtPath, TreeItem};
+
+use super::{build_item, command_handler::{content_path, io_error, HandlerResult}, file_ops, responder::Responder, upload, ShareConfig};
+
+/// 单次最多返回的条目数
+const MAX_TREE_ITEMS: usize = 10000;
+/// 每批返回的条目数, 长时间没有发送时发送已读取的部分
+const TREE_BATCH_SIZE: usize = 200;
+
+/// 深度优先读取目录树, 同一目录按名称排序; 不跟随子条目中的符号链接, path 本身按共享的策略处理
+pub fn read_tree(share: &ShareConfig, cmd: &ApiCommand, path: &FtPath, max_depth: Option<usize>, max_items: usize, responder: &Responder) -> HandlerResult<CommandData> {
+    let max_items = match max_items {
+        0 => MAX_TREE_ITEMS,
+        max_items if max_items > MAX_TREE_ITEMS => {
+            return Err((ErrorCode::TooLarge, format!("max items {max_items} exceeds {MAX_TREE_ITEMS}")));
+        },
+        max_items => max_items,
+    };
+    let org_root_path = path.root_path().clone();
+    let path = content_path(share, path)?;
+    let full_path = PathBuf::from(path.full_path());
+    let root = build_item(share, &path.full_path(), &org_root_path).map_err(|e| io_error(e, &path))?;
+
+    let batch = |items: Vec<TreeItem>| CommandMessage {
+        version: cmd.version,
+        request_id: cmd.request_id,
+        status: 0,
+        data: CommandData::TreeItems { items },
+    };
+    let mut items = vec![TreeItem { id: 0, parent: None, depth: 0, item: root }];
+    let (mut total, mut truncated) = (1, false);
+    // note: (目录, 目录的 id, 目录的层数)
+    let mut dirs = vec![(full_path, 0, 0)];
+    'walk: while let Some((dir, parent, depth)) = dirs.pop() {
+        // note: 不跟随符号链接时 content_path 已拒绝指向目录的链接, 此时 path 本身的链接只能是跟随的
+        if max_depth.is_some_and(|max_depth| depth >= max_depth) || !dir.is_dir() || (depth > 0 && dir.is_symlink()) {
+            continue ;
+        }
+        let mut entries: Vec<PathBuf> = match fs::read_dir(&dir) {
+            Ok(entries) => entries.flatten()
+                .map(|entry| entry.path())
+                .filter(|path| !upload::is_part_file(path) && !file_ops::is_trash(&share.root_path, path) && !share.hides(path))
+                .collect(),
+            Err(e) => {
+                eprintln!("skip {}, err: {e}", dir.display());
+                continue ;
+            },
+        };
+        entries.sort();
+        // note: 子目录逆序入栈, 保证按名称顺序展开
+        let mut sub_dirs = vec![];
+        for entry in entries {
+            if responder.is_cancelled() {
+                break 'walk;
+            }
+            if total == max_items {
+                truncated = true;
+                break 'walk;
+            }
+            let item = match build_item(share, &entry.to_string_lossy(), &org_root_path) {
+                Ok(item) => item,
+                Err(e) => {
+                    eprintln!("skip {}, err: {e}", entry.display());
+                    continue ;
+                },
+            };
+            let id = total;
+            total += 1;
+            items.push(TreeItem { id, parent: Some(parent), depth: depth + 1, item });
+            sub_dirs.push((entry, id, depth + 1));
+            responder.message_or_keepalive(items.len() >= TREE_BATCH_SIZE, || batch(mem::take(&mut items)));
+        }
+        dirs.extend(sub_dirs.into_iter().rev());
+    }
+    if !items.is_empty() {
+        responder.message(&batch(items));
+    }
+    Ok(CommandData::ReadTree { total, truncated })
+}
+
+#[cfg(test)]
+mod test_tree {
+    use std::{fs, os::unix::fs::symlink, sync::mpsc::channel};
+
+    use websocket::OwnedMessage;
+
+    use crate::{common::test_util::TempDir, features::{
+        commands::{ApiCommand, Command, CommandData, CommandMessage, ErrorCode, FtPath, TreeItem},
+        frame::Frame,
+        server::{responder::Responder, ShareConfig},
+    }};
+
+    use super::{read_tree, MAX_TREE_ITEMS};
+
+    /// 返回收到的条目与 ReadTree 的结果
+    fn run_read_tree(share: &ShareConfig, path: &str, max_depth: Option<usize>, max_items: usize) -> (Vec<TreeItem>, CommandData) {
+        let path = FtPath::new_relative("/".to_string(), path.to_string());
+        let cmd = ApiCommand::new(Command::ReadTree { path: path.clone(), max_depth, max_items });
+        let (tx, rx) = channel();
+        let responder = Responder::new(&tx, "client", cmd.request_id);
+        let result = match read_tree(share, &cmd, &path, max_depth, max_items, &responder) {
+            Ok(data) => data,
+            Err((code, message)) => CommandData::Error { code, message },
+        };
+        let mut items = vec![];
+        while let Ok(OwnedMessage::Binary(bin)) = rx.try_recv() {
+            let frame = Frame::decode(&bin).unwrap();
+            match serde_json::from_slice::<CommandMessage>(&frame.payload).unwrap().data {
+                CommandData::TreeItems { items: batch } => items.extend(batch),
+                data => panic!("unexpected message {data:?}"),
+            }
+        }
+        (items, result)
+    }
+
+    #[test]
+    fn test_depth_and_truncation() {
+        let root = TempDir::new("tree-depth");
+        fs::create_dir_all(root.join("a/b/c")).unwrap();
+        for path in ["a/b/c/d.txt", "a/e.txt", "f.txt"] {
+            fs::write(root.join(path), "x").unwrap();
+        }
+        let share = ShareConfig::new(&root);
+        let paths = |items: &[TreeItem]| items.iter().map(|item| item.item.path().path()).collect::<Vec<_>>();
+
+        // 父目录总在子条目之前, 同一目录按名称排序
+        let (items, result) = run_read_tree(&share, "/", None, 0);
+        assert_eq!(paths(&items), ["", "a", "f.txt", "a/b", "a/e.txt", "a/b/c", "a/b/c/d.txt"]);
+        assert!(matches!(result, CommandData::ReadTree { total: 7, truncated: false }));
+        for item in &items[1..] {
+            let parent = &items[item.parent.unwrap()];
+            assert_eq!(parent.depth + 1, item.depth);
+            assert!(item.item.path().path().starts_with(&parent.item.path().path()));
+        }
+
+        let (items, result) = run_read_tree(&share, "/", Some(1), 0);
+        assert_eq!(paths(&items), ["", "a", "f.txt"]);
+        assert!(matches!(result, CommandData::ReadTree { total: 3, truncated: false }));
+        let (items, _) = run_read_tree(&share, "/a", Some(2), 0);
+        assert_eq!(paths(&items), ["a", "a/b", "a/e.txt", "a/b/c"]);
+        assert_eq!(items.iter().map(|item| item.depth).max(), Some(2));
+
+        // 达到条目上限时截断, path 本身也计入
+        let (items, result) = run_read_tree(&share, "/", None, 4);
+        assert_eq!(paths(&items), ["", "a", "f.txt", "a/b"]);
+        assert!(matches!(result, CommandData::ReadTree { total: 4, truncated: true }));
+        let (_, result) = run_read_tree(&share, "/", None, 7);
+        assert!(matches!(result, CommandData::ReadTree { total: 7, truncated: false }));
+        let (_, result) = run_read_tree(&share, "/", None, MAX_TREE_ITEMS + 1);
+        assert!(matches!(result, CommandData::Error { code: ErrorCode::TooLarge, .. }));
+    }
+
+    #[test]
+    fn test_symlinked_root() {
+        let root = TempDir::new("tree");
+        fs::create_dir_all(root.join("a")).unwrap();
+        fs::write(root.join("a/b.txt"), "b").unwrap();
+        symlink(root.join("a"), root.join("linked")).unwrap();
+        symlink(root.join("a"), root.join("a/loop")).unwrap();
+        let share = ShareConfig::new(&root);
+        let (tx, _rx) = channel();
+        let responder = Responder::new(&tx, "client", 1);
+        let cmd = ApiCommand::new(Command::ReadConfig { with_stats: false });
+        let path = |path: &str| FtPath::new_relative("/".to_string(), path.to_string());
+
+        // 请求的路径本身是跟随的链接时展开, 子条目中的链接不展开
+        assert!(matches!(read_tree(&share, &cmd, &path("/linked"), None, 0, &responder), Ok(CommandData::ReadTree { total: 3, truncated: false })));
+        assert!(matches!(read_tree(&share, &cmd, &path("/a"), None, 0, &responder), Ok(CommandData::ReadTree { total: 3, truncated: false })));
+    }
+}
@@ -0,0 +1,325 @@
+use std::{fs, io::{self, Read as _, Seek as _, SeekFrom, Write as _}, os::unix::fs::MetadataExt as _, path::{Path, PathBuf}, sync::Mutex};
+
+use crate::{common::utils, features::{codec::Codec, commands::{CommandData, ErrorCode, FtPath}}};
+
+use super::{command_handler::{free_space, io_error, mutable_path, HandlerResult}, ShareConfig};
+
+/// 未完成上传的临时文件后缀, 列目录时不返回
+const UPLOAD_PART_SUFFIX: &str = ".ftpart";
+/// 记录已接收块的文件后缀, 内容为 block_size(u64 小端) 与按块的位图
+const UPLOAD_BLOCKS_SUFFIX: &str = ".ftblocks";
+/// 单个上传块的最大字节数(解压后)
+const MAX_UPLOAD_BLOCK_SIZE: usize = 16 << 20;
+
+/// 同一文件的块可能由不同线程同时写入, 更新块记录时加锁
+static BLOCKS_LOCK: Mutex<()> = Mutex::new(());
+/// 同时收齐所有块的请求依次校验, 避免重复校验与移动
+static VERIFY_LOCK: Mutex<()> = Mutex::new(());
+
+pub struct UploadBlock<'a> {
+    pub block_idx: usize,
+    pub block_size: usize,
+    pub codec: Codec,
+    pub data: &'a [u8],
+}
+
+pub fn is_part_file(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .is_some_and(|name| name.starts_with('.') && (name.ends_with(UPLOAD_PART_SUFFIX) || name.ends_with(UPLOAD_BLOCKS_SUFFIX)))
+}
+
+/// 临时文件与目标文件在同一目录, 完成后 rename 即可; 文件名包含 chksum, 内容变化后不会续传旧数据
+pub(super) fn part_path(target: &Path, chksum: &str) -> PathBuf {
+    temp_path(target, chksum, UPLOAD_PART_SUFFIX)
+}
+
+fn blocks_path(target: &Path, chksum: &str) -> PathBuf {
+    temp_path(target, chksum, UPLOAD_BLOCKS_SUFFIX)
+}
+
+fn temp_path(target: &Path, chksum: &str, suffix: &str) -> PathBuf {
+    let name = target.file_name().and_then(|name| name.to_str()).unwrap_or_default();
+    target.with_file_name(format!(".{}.{}{}", name, &chksum[..16], suffix))
+}
+
+fn block_count(file_size: u64, block_size: usize) -> usize {
+    file_size.div_ceil(block_size as u64).max(1) as usize
+}
+
+/// 已接收的块, 与临时文件一起保存, 续传时据此返回缺少的块
+struct BlockRecord {
+    block_size: usize,
+    bitmap: Vec<u8>,
+}
+
+impl BlockRecord {
+    fn new(file_size: u64, block_size: usize) -> Self {
+        Self { block_size, bitmap: vec![0; block_count(file_size, block_size).div_ceil(8)] }
+    }
+
+    /// 记录不存在或与 file_size 不符时返回 None
+    fn load(path: &Path, file_size: u64) -> io::Result<Option<Self>> {
+        let mut buf = vec![];
+        match fs::File::open(path) {
+            Ok(mut f) => f.read_to_end(&mut buf)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let Some((size, bitmap)) = buf.split_first_chunk::<8>() else {
+            return Ok(None);
+        };
+        let block_size = u64::from_le_bytes(*size) as usize;
+        if block_size == 0 || bitmap.len() != block_count(file_size, block_size).div_ceil(8) {
+            return Ok(None);
+        }
+        Ok(Some(Self { block_size, bitmap: bitmap.to_vec() }))
+    }
+
+    fn save(&self, path: &Path) -> io::Result<()> {
+        let mut buf = (self.block_size as u64).to_le_bytes().to_vec();
+        buf.extend_from_slice(&self.bitmap);
+        fs::write(path, buf)
+    }
+
+    fn has(&self, block_idx: usize) -> bool {
+        self.bitmap[block_idx / 8] & (1 << (block_idx % 8)) != 0
+    }
+
+    fn set(&mut self, block_idx: usize) {
+        self.bitmap[block_idx / 8] |= 1 << (block_idx % 8);
+    }
+
+    fn missing_blocks(&self, file_size: u64) -> Vec<usize> {
+        (0..block_count(file_size, self.block_size)).filter(|idx| !self.has(*idx)).collect()
+    }
+
+    fn received_size(&self, file_size: u64) -> u64 {
+        (0..block_count(file_size, self.block_size))
+            .filter(|idx| self.has(*idx))
+            .map(|idx| (file_size - (idx as u64 * self.block_size as u64).min(file_size)).min(self.block_size as u64))
+            .sum()
+    }
+}
+
+/// 检查是否允许上传到 file_path, 返回目标文件在 server 上的路径
+fn check_target(share: &ShareConfig, file_path: &FtPath, chksum: &str, overwrite: bool) -> HandlerResult<PathBuf> {
+    if share.read_only {
+        return Err((ErrorCode::ReadOnly, "share is read only".to_string()));
+    }
+    if chksum.len() != 64 || !chksum.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err((ErrorCode::BadRequest, format!("invalid chksum {chksum}")));
+    }
+    if file_path.path().trim_end_matches('/').is_empty() {
+        return Err((ErrorCode::BadRequest, "file path is empty".to_string()));
+    }
+    let full_path = mutable_path(share, file_path)?;
+    let target = PathBuf::from(full_path.full_path());
+    match fs::symlink_metadata(&target) {
+        Ok(meta) if meta.is_dir() => {
+            return Err((ErrorCode::BadRequest, format!("/{}: is a directory", file_path.path())));
+        },
+        Ok(_) if !overwrite => {
+            return Err((ErrorCode::AlreadyExists, format!("/{}: already exists", file_path.path())));
+        },
+        _ => {},
+    }
+    let parent = target.parent().unwrap_or(Path::new(&share.root_path));
+    match fs::metadata(parent) {
+        Ok(meta) if !meta.is_dir() => {
+            Err((ErrorCode::NotADirectory, format!("/{}: parent is not a directory", file_path.path())))
+        },
+        Ok(_) => Ok(target),
+        Err(e) => Err(io_error(e, file_path)),
+    }
+}
+
+/// 剩余空间不足以写入文件时拒绝, 已写入临时文件的部分不重复计算
+fn check_free_space(target: &Path, file_path: &FtPath, file_size: u64, chksum: &str) -> HandlerResult<()> {
+    // note: 块可以乱序写入, 临时文件可能是稀疏文件, 按实际占用的空间计算
+    let allocated = fs::metadata(part_path(target, chksum)).map(|meta| meta.blocks() * 512).unwrap_or(0);
+    let parent = target.parent().unwrap_or(Path::new("/"));
+    let available = free_space(&parent.to_string_lossy());
+    if file_size.saturating_sub(allocated) > available {
+        return Err((ErrorCode::TooLarge, format!("/{}: file size {file_size} exceeds free space {available}", file_path.path())));
+    }
+    Ok(())
+}
+
+pub fn upload_status(share: &ShareConfig, file_path: &FtPath, file_size: u64, chksum: &str, overwrite: bool) -> HandlerResult<CommandData> {
+    let target = check_target(share, file_path, chksum, overwrite)?;
+    check_free_space(&target, file_path, file_size, chksum)?;
+    let _lock = BLOCKS_LOCK.lock().unwrap();
+    let record = match part_path(&target, chksum).exists() {
+        true => BlockRecord::load(&blocks_path(&target, chksum), file_size).map_err(|e| io_error(e, file_path))?,
+        false => None,
+    };
+    Ok(match record {
+        Some(record) => CommandData::UploadStatus {
+            received_size: record.received_size(file_size),
+            missing_blocks: Some(record.missing_blocks(file_size)),
+        },
+        None => CommandData::UploadStatus { received_size: 0, missing_blocks: None },
+    })
+}
+
+pub fn upload_file(share: &ShareConfig, file_path: &FtPath, file_size: u64, chksum: &str, overwrite: bool, block: UploadBlock) -> HandlerResult<CommandData> {
+    let target = check_target(share, file_path, chksum, overwrite)?;
+    if block.block_size == 0 || block.block_size > MAX_UPLOAD_BLOCK_SIZE {
+        return Err((ErrorCode::TooLarge, format!("block size must be between 1 and {MAX_UPLOAD_BLOCK_SIZE}")));
+    }
+    if block.block_idx >= block_count(file_size, block.block_size) {
+        return Err((ErrorCode::BadRequest, format!("block {} out of file size {file_size}", block.block_idx)));
+    }
+    // note: 不经过 UploadStatus 直接上传时, 在创建临时文件前检查
+    if !part_path(&target, chksum).exists() {
+        check_free_space(&target, file_path, file_size, chksum)?;
+    }
+    // note: 除最后一块外都必须是完整的块, 记录为已接收的块不会缺少数据
+    let offset = block.block_idx as u64 * block.block_size as u64;
+    let expect_len = (file_size - offset).min(block.block_size as u64);
+    // note: 先确定块的长度再解压, 解压超过该长度时拒绝, 不会解压出大量数据
+    let data = block.codec.decompress_limited(block.data, expect_len as usize)
+        .map_err(|e| (ErrorCode::BadRequest, format!("block {} {} decompress failed, err: {e}", block.block_idx, block.codec)))?;
+    if data.len() as u64 != expect_len {
+        return Err((ErrorCode::BadRequest, format!("block {} should be {expect_len} bytes, got {}", block.block_idx, data.len())));
+    }
+
+    let part = part_path(&target, chksum);
+    let blocks = blocks_path(&target, chksum);
+    let mut f = fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&part)
+        .map_err(|e| io_error(e, file_path))?;
+    f.seek(SeekFrom::Start(offset))
+        .and_then(|_| f.write_all(&data))
+        .map_err(|e| io_error(e, file_path))?;
+
+    let (received_size, completed) = {
+        let _lock = BLOCKS_LOCK.lock().unwrap();
+        // note: block_size 变化时之前的块无法对应, 重新开始
+        let mut record = BlockRecord::load(&blocks, file_size)
+            .map_err(|e| io_error(e, file_path))?
+            .filter(|record| record.block_size == block.block_size)
+            .unwrap_or_else(|| BlockRecord::new(file_size, block.block_size));
+        record.set(block.block_idx);
+        record.save(&blocks).map_err(|e| io_error(e, file_path))?;
+        // note: 之前收齐后校验时出错或 server 中断时, 重新发送任意一块即可再次校验
+        (record.received_size(file_size), record.missing_blocks(file_size).is_empty())
+    };
+    if completed {
+        let _lock = VERIFY_LOCK.lock().unwrap();
+        // note: 同时收齐的其他请求已完成校验并移动
+        if !part.exists() {
+            return Ok(CommandData::UploadFile { received_size, completed: target.exists() });
+        }
+        f.set_len(file_size)
+            .and_then(|_| f.sync_all())
+            .map_err(|e| io_error(e, file_path))?;
+        drop(f);
+        let part_chksum = utils::file_sha256(&part).map_err(|e| io_error(e, file_path))?;
+        if !part_chksum.eq_ignore_ascii_case(chksum) {
+            // note: 无法确定哪些块有误, 保留临时文件并清空块记录, client 重新发送所有块覆盖写入
+            let _lock = BLOCKS_LOCK.lock().unwrap();
+            BlockRecord::new(file_size, block.block_size).save(&blocks).map_err(|e| io_error(e, file_path))?;
+            return Err((ErrorCode::ChecksumMismatch, format!("/{}: chksum mismatch, expect {chksum}, got {part_chksum}, all blocks need to be sent again", file_path.path())));
+        }
+        fs::rename(&part, &target).map_err(|e| io_error(e, file_path))?;
+        let _ = fs::remove_file(&blocks);
+    }
+    Ok(CommandData::UploadFile { received_size, completed })
+}
+
+#[cfg(test)]
+mod test_upload {
+    use std::{fs, os::unix::fs::symlink, path::Path};
+
+    use sha2::{Digest, Sha256};
+
+    use crate::{common::test_util::TempDir, features::{codec::Codec, commands::{CommandData, ErrorCode, FtPath}, server::ShareConfig}};
+
+    use super::{blocks_path, is_part_file, part_path, upload_file, upload_status, UploadBlock};
+
+    #[test]
+    fn test_upload_file() {
+        let root = TempDir::new("upload");
+        let mut share = ShareConfig::new(&root);
+        let file_path = FtPath::new_relative("/".to_string(), "/up.txt".to_string());
+        let content = b"hello file-tunnel".to_vec();
+        let chksum = hex::encode(Sha256::digest(&content));
+        let block = |block_idx: usize, data: &'static [u8]| UploadBlock { block_idx, block_size: 8, codec: Codec::Identity, data };
+
+        assert!(matches!(upload_status(&share, &file_path, 17, &chksum, false), Err((ErrorCode::ReadOnly, _))));
+        share.read_only = false;
+        assert!(matches!(upload_status(&share, &file_path, 17, "abc", false), Err((ErrorCode::BadRequest, _))));
+        let outside = FtPath::new_relative("/".to_string(), "../up.txt".to_string());
+        assert!(matches!(upload_status(&share, &outside, 17, &chksum, false), Err((ErrorCode::PermissionDenied, _))));
+
+        // 超过剩余空间
+        assert!(matches!(upload_status(&share, &file_path, u64::MAX, &chksum, false), Err((ErrorCode::TooLarge, _))));
+        assert!(matches!(upload_file(&share, &file_path, u64::MAX, &chksum, false, block(0, b"hello fi")), Err((ErrorCode::TooLarge, _))));
+        assert!(!part_path(&root.join("up.txt"), &chksum).exists());
+
+        assert!(matches!(upload_status(&share, &file_path, 17, &chksum, false), Ok(CommandData::UploadStatus { received_size: 0, missing_blocks: None })));
+        // 最后一块先到达, 不提前校验
+        assert!(matches!(upload_file(&share, &file_path, 17, &chksum, false, block(2, b"l")),
+            Ok(CommandData::UploadFile { received_size: 1, completed: false })));
+        assert!(matches!(upload_file(&share, &file_path, 17, &chksum, false, block(0, b"hello fi")),
+            Ok(CommandData::UploadFile { received_size: 9, completed: false })));
+        // 断点续传
+        assert!(matches!(upload_status(&share, &file_path, 17, &chksum, false),
+            Ok(CommandData::UploadStatus { received_size: 9, missing_blocks: Some(missing_blocks) }) if missing_blocks == [1]));
+        assert!(is_part_file(&part_path(&root.join("up.txt"), &chksum)));
+        assert!(is_part_file(&blocks_path(&root.join("up.txt"), &chksum)));
+        assert!(matches!(upload_file(&share, &file_path, 17, &chksum, false, block(3, b"x")), Err((ErrorCode::BadRequest, _))));
+        assert!(matches!(upload_file(&share, &file_path, 17, &chksum, false, block(1, b"le")), Err((ErrorCode::BadRequest, _))));
+        // 解压超过块的长度时拒绝
+        let bomb = Codec::Zstd.compress(&[b'x'; 1 << 20]).unwrap();
+        let bomb_block = UploadBlock { block_idx: 1, block_size: 8, codec: Codec::Zstd, data: &bomb };
+        assert!(matches!(upload_file(&share, &file_path, 17, &chksum, false, bomb_block), Err((ErrorCode::BadRequest, _))));
+        assert!(matches!(upload_file(&share, &file_path, 17, &chksum, false, block(1, b"le-tunne")),
+            Ok(CommandData::UploadFile { received_size: 17, completed: true })));
+        assert_eq!(fs::read(root.join("up.txt")).unwrap(), content);
+        assert!(!Path::new(&part_path(&root.join("up.txt"), &chksum)).exists());
+        assert!(!Path::new(&blocks_path(&root.join("up.txt"), &chksum)).exists());
+
+        // 已存在且不覆盖
+        assert!(matches!(upload_status(&share, &file_path, 17, &chksum, false), Err((ErrorCode::AlreadyExists, _))));
+        // 校验失败时保留临时文件, 所有块需要重新发送
+        let chksum = hex::encode(Sha256::digest("other"));
+        assert!(matches!(upload_file(&share, &file_path, 1, &chksum, true, block(0, b"o")), Err((ErrorCode::ChecksumMismatch, _))));
+        assert!(part_path(&root.join("up.txt"), &chksum).exists());
+        assert!(matches!(upload_status(&share, &file_path, 1, &chksum, true),
+            Ok(CommandData::UploadStatus { received_size: 0, missing_blocks: Some(missing_blocks) }) if missing_blocks == [0]));
+        assert_eq!(fs::read(root.join("up.txt")).unwrap(), content);
+
+        // 所有块都已记录但未完成校验(校验期间中断), 重新发送任意一块时再次校验
+        let chksum = hex::encode(Sha256::digest(&content));
+        let file_path = FtPath::new_relative("/".to_string(), "/resume.txt".to_string());
+        assert!(matches!(upload_file(&share, &file_path, 17, &chksum, false, block(0, b"hello fi")),
+            Ok(CommandData::UploadFile { completed: false, .. })));
+        assert!(matches!(upload_file(&share, &file_path, 17, &chksum, false, block(1, b"le-tunne")),
+            Ok(CommandData::UploadFile { completed: false, .. })));
+        let part = part_path(&root.join("resume.txt"), &chksum);
+        fs::write(&part, &content[..16]).unwrap();
+        let mut bitmap = 8u64.to_le_bytes().to_vec();
+        bitmap.push(0b111);
+        fs::write(blocks_path(&root.join("resume.txt"), &chksum), bitmap).unwrap();
+        assert!(matches!(upload_status(&share, &file_path, 17, &chksum, false),
+            Ok(CommandData::UploadStatus { received_size: 17, missing_blocks: Some(missing_blocks) }) if missing_blocks.is_empty()));
+        assert!(matches!(upload_file(&share, &file_path, 17, &chksum, false, block(2, b"l")),
+            Ok(CommandData::UploadFile { received_size: 17, completed: true })));
+        assert_eq!(fs::read(root.join("resume.txt")).unwrap(), content);
+        assert!(!part.exists());
+
+        // 经过指向共享目录以外的符号链接目录
+        let outside_dir = TempDir::new("upload-outside");
+        symlink(&outside_dir, root.join("linkdir")).unwrap();
+        let linked = FtPath::new_relative("/".to_string(), "/linkdir/up.txt".to_string());
+        assert!(matches!(upload_status(&share, &linked, 1, &chksum, false), Err((ErrorCode::PermissionDenied, _))));
+        assert!(matches!(upload_file(&share, &linked, 1, &chksum, false, block(0, b"o")), Err((ErrorCode::PermissionDenied, _))));
+        assert_eq!(fs::read_dir(&outside_dir).unwrap().count(), 0);
+    }
+}
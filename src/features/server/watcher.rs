@@ -0,0 +1,659 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs, io, mem,
+    os::fd::AsFd,
+    path::{Path, PathBuf},
+    sync::{atomic::{AtomicBool, Ordering}, mpsc::{sync_channel, Receiver, RecvTimeoutError, SyncSender, TrySendError}, Arc, Condvar, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use nix::{
+    errno::Errno,
+    poll::{poll, PollFd, PollFlags, PollTimeout},
+    sys::inotify::{AddWatchFlags, InitFlags, Inotify, WatchDescriptor},
+};
+
+use crate::features::commands::{ApiCommand, CommandData, CommandMessage, ErrorCode, FtPath, ModfiedType};
+
+use super::{command_handler::{content_path, io_error, HandlerResult}, file_ops, responder::Responder, search::walk, upload, ShareConfig};
+
+/// 最后一个事件之后该时间内没有新事件才推送, 合并连续写入等突发事件
+const COALESCE_QUIET: Duration = Duration::from_millis(200);
+/// 事件持续不断时, 最多延迟该时间推送
+const COALESCE_MAX_DELAY: Duration = Duration::from_secs(1);
+/// 等待变化的间隔, 期间检查请求是否已取消
+const RECV_INTERVAL: Duration = Duration::from_secs(1);
+/// 每个订阅最多缓存的变化数, client 读取过慢时丢弃并通知 Overflow
+const SUBSCRIBER_QUEUE_SIZE: usize = 1024;
+/// 等待 inotify 事件的间隔(毫秒), 期间检查是否已停止
+const POLL_INTERVAL_MS: u16 = 100;
+
+fn watch_mask() -> AddWatchFlags {
+    AddWatchFlags::IN_CREATE
+        | AddWatchFlags::IN_DELETE
+        | AddWatchFlags::IN_MODIFY
+        | AddWatchFlags::IN_ATTRIB
+        | AddWatchFlags::IN_MOVED_FROM
+        | AddWatchFlags::IN_MOVED_TO
+}
+
+/// 合并后的一次变化, 路径为 server 上的完整路径
+#[derive(Debug, Clone, PartialEq)]
+pub struct Change {
+    pub path: PathBuf,
+    pub m_type: ModfiedType,
+    /// Rename 的原路径
+    pub from: Option<PathBuf>,
+}
+
+impl Change {
+    fn new(path: PathBuf, m_type: ModfiedType) -> Self {
+        Self { path, m_type, from: None }
+    }
+}
+
+/// 从 inotify 事件转换得到, 由 `Coalescer` 合并
+#[derive(Debug)]
+enum Event {
+    Changed(PathBuf, ModfiedType),
+    MovedFrom(PathBuf, u32),
+    MovedTo(PathBuf, u32),
+    Overflow,
+}
+
+/// 合并一段时间内的事件: 同一路径的多次修改只通知一次, 新建后又删除的路径不通知,
+/// MOVED_FROM 与 MOVED_TO 按 cookie 配对为 Rename, 没有配对的分别视为删除与新建
+#[derive(Default)]
+struct Coalescer {
+    /// 按首次出现的顺序保存
+    pending: Vec<Change>,
+    /// 等待配对的 MOVED_FROM, cookie -> 原路径
+    moves: HashMap<u32, PathBuf>,
+    overflowed: bool,
+    first_at: Option<Instant>,
+    last_at: Option<Instant>,
+}
+
+impl Coalescer {
+    fn push(&mut self, event: Event, now: Instant) {
+        self.first_at.get_or_insert(now);
+        self.last_at = Some(now);
+        match event {
+            Event::Changed(path, m_type) => self.merge(path, m_type, None),
+            Event::MovedFrom(path, cookie) => {
+                self.moves.insert(cookie, path);
+            },
+            Event::MovedTo(path, cookie) => match self.moves.remove(&cookie) {
+                Some(from) => self.rename(from, path),
+                None => self.merge(path, ModfiedType::Create, None),
+            },
+            Event::Overflow => self.overflowed = true,
+        }
+    }
+
+    fn merge(&mut self, path: PathBuf, m_type: ModfiedType, from: Option<PathBuf>) {
+        use ModfiedType::*;
+        let Some(idx) = self.pending.iter().position(|change| change.path == path) else {
+            self.pending.push(Change { path, m_type, from });
+            return ;
+        };
+        let change = &mut self.pending[idx];
+        match (change.m_type, m_type) {
+            (Create, Delete) => {
+                self.pending.remove(idx);
+            },
+            // 改名后又删除, 相当于删除了原路径
+            (Rename, Delete) => {
+                if let Some(from) = self.pending.remove(idx).from {
+                    self.merge(from, Delete, None);
+                }
+            },
+            (Create | Rename, Content | Meta) | (Content, Meta) => {},
+            // 删除后又新建, 视为内容被替换
+            (Delete, Create) => change.m_type = Content,
+            (_, m_type) => {
+                change.m_type = m_type;
+                change.from = from;
+            },
+        }
+    }
+
+    fn rename(&mut self, from: PathBuf, to: PathBuf) {
+        let from = match self.pending.iter().position(|change| change.path == from) {
+            Some(idx) => {
+                let previous = self.pending.remove(idx);
+                match previous.m_type {
+                    // 新建后改名, 对 client 而言只新建了 to
+                    ModfiedType::Create => None,
+                    ModfiedType::Rename => previous.from,
+                    _ => Some(from),
+                }
+            },
+            None => Some(from),
+        };
+        match from {
+            Some(from) => self.merge(to, ModfiedType::Rename, Some(from)),
+            None => self.merge(to, ModfiedType::Create, None),
+        }
+    }
+
+    /// 距最后一个事件超过 `COALESCE_QUIET`, 或距第一个事件超过 `COALESCE_MAX_DELAY` 时取出合并后的变化;
+    /// 丢失过事件时只返回一个 Overflow
+    fn flush(&mut self, now: Instant, root_path: &Path) -> Vec<Change> {
+        let (Some(first_at), Some(last_at)) = (self.first_at, self.last_at) else {
+            return vec![];
+        };
+        if now.duration_since(last_at) < COALESCE_QUIET && now.duration_since(first_at) < COALESCE_MAX_DELAY {
+            return vec![];
+        }
+        // note: 到推送时仍没有配对的是移出了共享目录
+        for (_, from) in mem::take(&mut self.moves) {
+            self.merge(from, ModfiedType::Delete, None);
+        }
+        self.first_at = None;
+        self.last_at = None;
+        if mem::take(&mut self.overflowed) {
+            self.pending.clear();
+            return vec![Change::new(root_path.to_path_buf(), ModfiedType::Overflow)];
+        }
+        mem::take(&mut self.pending)
+    }
+}
+
+/// 监视一个共享目录下的所有目录, 新出现的目录随即加入监视
+struct Watcher {
+    root_path: String,
+    inotify: Inotify,
+    /// watch descriptor -> 目录
+    dirs: HashMap<WatchDescriptor, PathBuf>,
+    /// 移走的目录, cookie -> 原路径; 到合并推送时仍没有配对的 MOVED_TO 视为移出了共享目录
+    moved_dirs: HashMap<u32, PathBuf>,
+}
+
+impl Watcher {
+    /// 只监视共享目录本身, 子目录在 `run` 中加入, 不阻塞订阅
+    fn new(root_path: &str) -> io::Result<Self> {
+        let inotify = Inotify::init(InitFlags::IN_NONBLOCK | InitFlags::IN_CLOEXEC)?;
+        let mut watcher = Self { root_path: root_path.to_string(), inotify, dirs: HashMap::new(), moved_dirs: HashMap::new() };
+        watcher.add_dir(Path::new(root_path))?;
+        Ok(watcher)
+    }
+
+    fn add_dir(&mut self, dir: &Path) -> nix::Result<()> {
+        let wd = self.inotify.add_watch(dir, watch_mask())?;
+        self.dirs.insert(wd, dir.to_path_buf());
+        Ok(())
+    }
+
+    /// 监视 dir 下的所有子目录(不含 dir 本身), 返回 dir 下已有的条目
+    fn add_tree(&mut self, dir: &Path) -> Vec<PathBuf> {
+        let root_path = self.root_path.clone();
+        let mut entries = vec![];
+        walk(&root_path, dir, |path, meta| {
+            entries.push((path.to_path_buf(), meta.is_dir()));
+            true
+        });
+        for (path, _) in entries.iter().filter(|(_, is_dir)| *is_dir) {
+            // note: 超过 max_user_watches 时该目录下的变化不再通知
+            if let Err(e) = self.add_dir(path) {
+                eprintln!("watch {} failed, err: {e}", path.display());
+            }
+        }
+        entries.into_iter().map(|(path, _)| path).collect()
+    }
+
+    /// 新出现的目录加入监视, 其中早于监视出现的条目作为新建通知
+    fn add_new_dir(&mut self, dir: &Path, coalescer: &mut Coalescer, now: Instant) {
+        if let Err(e) = self.add_dir(dir) {
+            eprintln!("watch {} failed, err: {e}", dir.display());
+            return ;
+        }
+        for path in self.add_tree(dir) {
+            coalescer.push(Event::Changed(path, ModfiedType::Create), now);
+        }
+    }
+
+    /// 目录在共享目录内移动后, 更新其下所有监视的路径
+    fn move_dirs(&mut self, from: &Path, to: &Path) {
+        for dir in self.dirs.values_mut() {
+            if let Ok(relative_path) = dir.strip_prefix(from) {
+                *dir = to.join(relative_path);
+            }
+        }
+    }
+
+    /// 目录移出共享目录后, 不再监视其下的目录
+    fn remove_dirs(&mut self, from: &Path) {
+        let wds: Vec<WatchDescriptor> = self.dirs.iter()
+            .filter(|(_, dir)| dir.starts_with(from))
+            .map(|(wd, _)| *wd)
+            .collect();
+        for wd in wds {
+            let _ = self.inotify.rm_watch(wd);
+            self.dirs.remove(&wd);
+        }
+    }
+
+    fn read(&mut self, coalescer: &mut Coalescer) -> nix::Result<()> {
+        let events = match self.inotify.read_events() {
+            Ok(events) => events,
+            Err(Errno::EAGAIN) => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        let now = Instant::now();
+        for event in events {
+            let mask = event.mask;
+            if mask.contains(AddWatchFlags::IN_Q_OVERFLOW) {
+                coalescer.push(Event::Overflow, now);
+                continue ;
+            }
+            if mask.contains(AddWatchFlags::IN_IGNORED) {
+                self.dirs.remove(&event.wd);
+                continue ;
+            }
+            let (Some(dir), Some(name)) = (self.dirs.get(&event.wd), event.name) else {
+                continue ;
+            };
+            let path = dir.join(name);
+            if upload::is_part_file(&path) || file_ops::is_trash(&self.root_path, &path) {
+                continue ;
+            }
+            let is_dir = mask.contains(AddWatchFlags::IN_ISDIR);
+            let mut new_dir = false;
+            let event = if mask.contains(AddWatchFlags::IN_CREATE) {
+                new_dir = is_dir;
+                Event::Changed(path.clone(), ModfiedType::Create)
+            } else if mask.contains(AddWatchFlags::IN_DELETE) {
+                Event::Changed(path.clone(), ModfiedType::Delete)
+            } else if mask.contains(AddWatchFlags::IN_MODIFY) {
+                Event::Changed(path.clone(), ModfiedType::Content)
+            } else if mask.contains(AddWatchFlags::IN_ATTRIB) {
+                Event::Changed(path.clone(), ModfiedType::Meta)
+            } else if mask.contains(AddWatchFlags::IN_MOVED_FROM) {
+                if is_dir {
+                    self.moved_dirs.insert(event.cookie, path.clone());
+                }
+                Event::MovedFrom(path.clone(), event.cookie)
+            } else if mask.contains(AddWatchFlags::IN_MOVED_TO) {
+                if is_dir {
+                    match self.moved_dirs.remove(&event.cookie) {
+                        Some(from) => self.move_dirs(&from, &path),
+                        None => new_dir = true,
+                    }
+                }
+                Event::MovedTo(path.clone(), event.cookie)
+            } else {
+                continue ;
+            };
+            coalescer.push(event, now);
+            if new_dir {
+                self.add_new_dir(&path, coalescer, now);
+            }
+        }
+        Ok(())
+    }
+
+    /// 取出合并后的变化; 合并推送时配对的 MOVED_FROM 与 MOVED_TO 可能分在多次读取中,
+    /// 与 `Coalescer` 一样到推送时才把没有配对的目录移出监视
+    fn flush(&mut self, coalescer: &mut Coalescer, now: Instant, root_path: &Path) -> Vec<Change> {
+        let changes = coalescer.flush(now, root_path);
+        if coalescer.first_at.is_none() {
+            for (_, from) in mem::take(&mut self.moved_dirs) {
+                self.remove_dirs(&from);
+            }
+        }
+        changes
+    }
+
+    fn run(mut self, hub: &WatchHub, stop: &AtomicBool) {
+        let root_path = PathBuf::from(&self.root_path);
+        self.add_tree(&root_path);
+        hub.ready(&self.root_path, stop);
+        let mut coalescer = Coalescer::default();
+        while !stop.load(Ordering::Relaxed) {
+            let ready = poll(&mut [PollFd::new(self.inotify.as_fd(), PollFlags::POLLIN)], PollTimeout::from(POLL_INTERVAL_MS));
+            let result = match ready {
+                Ok(0) | Err(Errno::EINTR) => Ok(()),
+                Ok(_) => self.read(&mut coalescer),
+                Err(e) => Err(e),
+            };
+            if let Err(e) = result {
+                eprintln!("watch {} failed, err: {e}", self.root_path);
+                break ;
+            }
+            let changes = self.flush(&mut coalescer, Instant::now(), &root_path);
+            if !changes.is_empty() {
+                hub.dispatch(&self.root_path, &changes);
+            }
+        }
+        hub.stopped(&self.root_path, stop);
+    }
+}
+
+struct Subscriber {
+    id: u64,
+    root_path: String,
+    path: PathBuf,
+    recursive: bool,
+    tx: SyncSender<Change>,
+    overflowed: Arc<AtomicBool>,
+}
+
+impl Subscriber {
+    fn contains(&self, path: &Path) -> bool {
+        path == self.path || match self.recursive {
+            true => path.starts_with(&self.path),
+            false => path.parent() == Some(&self.path),
+        }
+    }
+
+    /// 该订阅看到的变化; 只有一端在订阅范围内的 Rename 视为删除或新建, 订阅的路径随上级目录移走时视为删除
+    fn view(&self, change: &Change) -> Option<Change> {
+        if change.m_type == ModfiedType::Overflow {
+            return Some(Change::new(self.path.clone(), ModfiedType::Overflow));
+        }
+        let Some(from) = &change.from else {
+            return self.contains(&change.path).then(|| change.clone());
+        };
+        match (self.contains(from), self.contains(&change.path)) {
+            (true, true) => Some(change.clone()),
+            (true, false) => Some(Change::new(from.clone(), ModfiedType::Delete)),
+            (false, true) => Some(Change::new(change.path.clone(), ModfiedType::Create)),
+            (false, false) if self.path.starts_with(from) => Some(Change::new(self.path.clone(), ModfiedType::Delete)),
+            (false, false) => None,
+        }
+    }
+}
+
+#[derive(Default)]
+struct HubState {
+    subscribers: Vec<Subscriber>,
+    /// 共享目录 -> 对应 watcher 的停止标记
+    watchers: HashMap<String, Arc<AtomicBool>>,
+    /// 已监视全部子目录的共享目录
+    ready: HashSet<String>,
+    next_id: u64,
+}
+
+/// 按共享目录分发变化; 共享目录的第一个订阅启动 watcher, 最后一个订阅取消时停止,
+/// 修改共享目录后新的订阅使用新目录, 无需重启 server
+#[derive(Default)]
+pub struct WatchHub {
+    state: Mutex<HubState>,
+    /// watcher 监视全部子目录后通知
+    ready: Condvar,
+}
+
+impl WatchHub {
+    pub fn subscribe(self: &Arc<Self>, root_path: &str, path: PathBuf, recursive: bool) -> io::Result<Subscription> {
+        let mut state = self.state.lock().unwrap();
+        if !state.watchers.contains_key(root_path) {
+            let watcher = Watcher::new(root_path)?;
+            let stop = Arc::new(AtomicBool::new(false));
+            state.watchers.insert(root_path.to_string(), stop.clone());
+            let hub = self.clone();
+            thread::spawn(move || watcher.run(&hub, &stop));
+        }
+        state.next_id += 1;
+        let id = state.next_id;
+        let (tx, rx) = sync_channel(SUBSCRIBER_QUEUE_SIZE);
+        let overflowed = Arc::new(AtomicBool::new(false));
+        state.subscribers.push(Subscriber {
+            id,
+            root_path: root_path.to_string(),
+            path: path.clone(),
+            recursive,
+            tx,
+            overflowed: overflowed.clone(),
+        });
+        Ok(Subscription { hub: self.clone(), id, path, rx, overflowed })
+    }
+
+    fn unsubscribe(&self, id: u64) {
+        let mut state = self.state.lock().unwrap();
+        let Some(idx) = state.subscribers.iter().position(|subscriber| subscriber.id == id) else {
+            return ;
+        };
+        let root_path = state.subscribers.remove(idx).root_path;
+        if !state.subscribers.iter().any(|subscriber| subscriber.root_path == root_path) {
+            if let Some(stop) = state.watchers.remove(&root_path) {
+                stop.store(true, Ordering::Relaxed);
+                state.ready.remove(&root_path);
+            }
+        }
+    }
+
+    /// 订阅的队列已满时丢弃之后的变化, 由订阅者通知 Overflow
+    fn dispatch(&self, root_path: &str, changes: &[Change]) {
+        let state = self.state.lock().unwrap();
+        for subscriber in state.subscribers.iter().filter(|subscriber| subscriber.root_path == root_path) {
+            for change in changes.iter().filter_map(|change| subscriber.view(change)) {
+                if let Err(TrySendError::Full(_)) = subscriber.tx.try_send(change) {
+                    subscriber.overflowed.store(true, Ordering::Relaxed);
+                    break ;
+                }
+            }
+        }
+    }
+
+    fn ready(&self, root_path: &str, stop: &AtomicBool) {
+        let mut state = self.state.lock().unwrap();
+        if state.watchers.get(root_path).is_some_and(|current| std::ptr::eq(current.as_ref(), stop)) {
+            state.ready.insert(root_path.to_string());
+            self.ready.notify_all();
+        }
+    }
+
+    /// 等待共享目录的 watcher 监视全部子目录, 超时返回 false
+    #[cfg(test)]
+    fn wait_ready(&self, root_path: &str, timeout: Duration) -> bool {
+        let state = self.state.lock().unwrap();
+        let (state, _) = self.ready.wait_timeout_while(state, timeout, |state| !state.ready.contains(root_path)).unwrap();
+        state.ready.contains(root_path)
+    }
+
+    /// watcher 出错退出时移除, 同时移除该共享目录的订阅, 订阅者收到 Disconnected 后结束; 之后的订阅重新启动
+    fn stopped(&self, root_path: &str, stop: &AtomicBool) {
+        let mut state = self.state.lock().unwrap();
+        if state.watchers.get(root_path).is_some_and(|current| std::ptr::eq(current.as_ref(), stop)) {
+            state.watchers.remove(root_path);
+            state.ready.remove(root_path);
+            state.subscribers.retain(|subscriber| subscriber.root_path != root_path);
+        }
+    }
+}
+
+/// drop 时取消订阅
+pub struct Subscription {
+    hub: Arc<WatchHub>,
+    id: u64,
+    path: PathBuf,
+    rx: Receiver<Change>,
+    overflowed: Arc<AtomicBool>,
+}
+
+impl Subscription {
+    /// 等待下一个变化; 队列写满过时丢弃缓存的变化, 返回 Overflow; watcher 出错停止后返回 Disconnected
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<Change, RecvTimeoutError> {
+        if self.overflowed.swap(false, Ordering::Relaxed) {
+            while self.rx.try_recv().is_ok() {}
+            return Ok(Change::new(self.path.clone(), ModfiedType::Overflow));
+        }
+        self.rx.recv_timeout(timeout)
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        self.hub.unsubscribe(self.id);
+    }
+}
+
+pub fn watch(share: &ShareConfig, cmd: &ApiCommand, path: &FtPath, recursive: bool, hub: &Arc<WatchHub>, responder: &Responder) -> HandlerResult<CommandData> {
+    let org_root_path = path.root_path().clone();
+    let watch_path = content_path(share, path)?;
+    let full_path = PathBuf::from(watch_path.full_path());
+    if share.hides(&full_path) {
+        return Err(io_error(io::Error::from(io::ErrorKind::NotFound), &watch_path));
+    }
+    fs::metadata(&full_path).map_err(|e| io_error(e, &watch_path))?;
+    let subscription = hub.subscribe(&share.root_path, full_path.clone(), recursive)
+        .map_err(|e| (ErrorCode::Unavailable, format!("start watcher failed, {e}")))?;
+
+    let to_ft_path = |path: &Path| {
+        let mut path = FtPath::new_absolute(share.root_path.clone(), path.to_string_lossy().to_string());
+        path.reset_root(&org_root_path);
+        path
+    };
+    let send = |data| {
+        responder.message(&CommandMessage { version: cmd.version, request_id: cmd.request_id, status: 0, data });
+    };
+    let watching = to_ft_path(&full_path);
+    send(CommandData::Watching { path: watching.clone() });
+    while !responder.is_cancelled() {
+        match subscription.recv_timeout(RECV_INTERVAL) {
+            Ok(change) if share.hides(&change.path) => {},
+            Ok(change) => {
+                send(CommandData::ModifiedFile {
+                    path: to_ft_path(&change.path),
+                    m_type: change.m_type,
+                    from: change.from.as_deref().map(to_ft_path),
+                });
+            },
+            // note: 没有变化时定期发送 `Watching`
+            Err(RecvTimeoutError::Timeout) => {
+                responder.message_or_keepalive(false, || CommandMessage {
+                    version: cmd.version,
+                    request_id: cmd.request_id,
+                    status: 0,
+                    data: CommandData::Watching { path: watching.clone() },
+                });
+            },
+            Err(RecvTimeoutError::Disconnected) => {
+                return Err((ErrorCode::Unavailable, "watcher stopped, watch again".to_string()));
+            },
+        }
+    }
+    Ok(CommandData::Watching { path: watching })
+}
+
+#[cfg(test)]
+mod test_watcher {
+    use std::{fs, path::PathBuf, sync::{atomic::Ordering, mpsc::RecvTimeoutError, Arc}, time::{Duration, Instant}};
+
+    use crate::{common::test_util::TempDir, features::commands::ModfiedType};
+
+    use super::{Change, Coalescer, Event, WatchHub, Watcher, COALESCE_MAX_DELAY, COALESCE_QUIET};
+
+    #[test]
+    fn test_coalescer() {
+        let root = PathBuf::from("/share");
+        let path = |name: &str| root.join(name);
+        let now = Instant::now();
+        let mut coalescer = Coalescer::default();
+        coalescer.push(Event::Changed(path("a"), ModfiedType::Create), now);
+        coalescer.push(Event::Changed(path("a"), ModfiedType::Content), now);
+        coalescer.push(Event::Changed(path("b"), ModfiedType::Meta), now);
+        coalescer.push(Event::Changed(path("b"), ModfiedType::Content), now);
+        coalescer.push(Event::Changed(path("tmp"), ModfiedType::Create), now);
+        coalescer.push(Event::Changed(path("tmp"), ModfiedType::Delete), now);
+        coalescer.push(Event::MovedFrom(path("c"), 1), now);
+        coalescer.push(Event::MovedTo(path("d"), 1), now);
+        coalescer.push(Event::MovedFrom(path("gone"), 2), now);
+        coalescer.push(Event::MovedTo(path("came"), 3), now);
+        // 未到合并时间时不返回
+        assert!(coalescer.flush(now, &root).is_empty());
+
+        let changes = coalescer.flush(now + COALESCE_QUIET, &root);
+        assert_eq!(changes, vec![
+            Change::new(path("a"), ModfiedType::Create),
+            Change::new(path("b"), ModfiedType::Content),
+            Change { path: path("d"), m_type: ModfiedType::Rename, from: Some(path("c")) },
+            Change::new(path("came"), ModfiedType::Create),
+            Change::new(path("gone"), ModfiedType::Delete),
+        ]);
+
+        // 持续有事件时最多延迟 COALESCE_MAX_DELAY
+        coalescer.push(Event::Changed(path("e"), ModfiedType::Create), now);
+        coalescer.push(Event::MovedFrom(path("e"), 4), now);
+        coalescer.push(Event::MovedTo(path("f"), 4), now + COALESCE_MAX_DELAY);
+        assert_eq!(coalescer.flush(now + COALESCE_MAX_DELAY, &root), vec![Change::new(path("f"), ModfiedType::Create)]);
+
+        coalescer.push(Event::Changed(path("g"), ModfiedType::Content), now);
+        coalescer.push(Event::Overflow, now);
+        assert_eq!(coalescer.flush(now + COALESCE_QUIET, &root), vec![Change::new(root.clone(), ModfiedType::Overflow)]);
+    }
+
+    #[test]
+    fn test_moved_out_dir() {
+        let dir = TempDir::new("watch_moved");
+        let root = dir.join("share");
+        fs::create_dir_all(root.join("sub/deep")).unwrap();
+        let mut watcher = Watcher::new(root.to_str().unwrap()).unwrap();
+        watcher.add_tree(&root);
+        assert_eq!(watcher.dirs.len(), 3);
+
+        fs::rename(root.join("sub"), dir.join("sub")).unwrap();
+        let mut coalescer = Coalescer::default();
+        watcher.read(&mut coalescer).unwrap();
+        let now = Instant::now();
+        // 可能在下一次读取中配对, 合并推送前仍然监视
+        assert!(watcher.flush(&mut coalescer, now, &root).is_empty());
+        assert_eq!(watcher.moved_dirs.len(), 1);
+        assert_eq!(watcher.dirs.len(), 3);
+
+        assert_eq!(watcher.flush(&mut coalescer, now + COALESCE_QUIET, &root), vec![Change::new(root.join("sub"), ModfiedType::Delete)]);
+        assert!(watcher.moved_dirs.is_empty());
+        assert_eq!(watcher.dirs.len(), 1);
+    }
+
+    #[test]
+    fn test_watch_hub() {
+        let dir = TempDir::new("watch");
+        let root = dir.to_path_buf();
+        fs::create_dir(root.join("sub")).unwrap();
+        let root_path = dir.to_str();
+        let hub = Arc::new(WatchHub::default());
+        let recursive = hub.subscribe(root_path, root.clone(), true).unwrap();
+        let direct = hub.subscribe(root_path, root.clone(), false).unwrap();
+        // note: 子目录在 watcher 线程中加入监视
+        assert!(hub.wait_ready(root_path, Duration::from_secs(3)));
+
+        fs::write(root.join("sub/a.txt"), "a").unwrap();
+        fs::create_dir(root.join("new")).unwrap();
+        fs::write(root.join("new/b.txt"), "b").unwrap();
+        let mut changes = vec![];
+        while let Ok(change) = recursive.recv_timeout(Duration::from_secs(3)) {
+            changes.push(change);
+            if changes.len() == 3 {
+                break ;
+            }
+        }
+        assert!(changes.contains(&Change::new(root.join("sub/a.txt"), ModfiedType::Create)));
+        assert!(changes.contains(&Change::new(root.join("new"), ModfiedType::Create)));
+        assert!(changes.contains(&Change::new(root.join("new/b.txt"), ModfiedType::Create)));
+        // 非递归订阅只收到直接子条目的变化
+        assert_eq!(direct.recv_timeout(Duration::from_secs(1)), Ok(Change::new(root.join("new"), ModfiedType::Create)));
+        assert_eq!(direct.recv_timeout(Duration::from_millis(100)), Err(RecvTimeoutError::Timeout));
+
+        fs::rename(root.join("sub/a.txt"), root.join("new/a.txt")).unwrap();
+        assert_eq!(recursive.recv_timeout(Duration::from_secs(3)), Ok(Change {
+            path: root.join("new/a.txt"),
+            m_type: ModfiedType::Rename,
+            from: Some(root.join("sub/a.txt")),
+        }));
+
+        drop(recursive);
+        drop(direct);
+        assert!(hub.state.lock().unwrap().watchers.is_empty());
+
+        // watcher 出错退出后订阅随之结束, 不会一直等待
+        let subscription = hub.subscribe(root_path, root.clone(), true).unwrap();
+        let stop = hub.state.lock().unwrap().watchers[root_path].clone();
+        hub.stopped(root_path, &stop);
+        assert_eq!(subscription.recv_timeout(Duration::from_secs(1)), Err(RecvTimeoutError::Disconnected));
+        assert!(hub.state.lock().unwrap().watchers.is_empty());
+        stop.store(true, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,58 @@
+use async_std::{channel::Receiver, io, stream::Stream};
+use std::{pin::Pin, task::{Context, Poll}};
+
+/// 将 frame 字节流转为 http 响应体, 收到多少转发多少, 不缓存整个响应
+pub struct FrameBody {
+    receiver: Receiver<Vec<u8>>,
+    chunk: Vec<u8>,
+    pos: usize,
+}
+
+impl FrameBody {
+    pub fn new(receiver: Receiver<Vec<u8>>) -> Self {
+        Self {
+            receiver,
+            chunk: vec![],
+            pos: 0,
+        }
+    }
+}
+
+impl io::Read for FrameBody {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        while self.pos >= self.chunk.len() {
+            match Pin::new(&mut self.receiver).poll_next(cx) {
+                Poll::Ready(Some(chunk)) => {
+                    self.chunk = chunk;
+                    self.pos = 0;
+                },
+                Poll::Ready(None) => return Poll::Ready(Ok(0)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+        let size = buf.len().min(self.chunk.len() - self.pos);
+        buf[..size].copy_from_slice(&self.chunk[self.pos..self.pos + size]);
+        self.pos += size;
+        Poll::Ready(Ok(size))
+    }
+}
+
+#[cfg(test)]
+mod test_body {
+    use async_std::{channel, io::ReadExt as _};
+    use super::FrameBody;
+
+    #[async_std::test]
+    async fn test_frame_body() {
+        let (sender, receiver) = channel::bounded(1);
+        async_std::task::spawn(async move {
+            for chunk in [b"ab".to_vec(), vec![], b"cde".to_vec()] {
+                sender.send(chunk).await.unwrap();
+            }
+        });
+        let mut body = FrameBody::new(receiver);
+        let mut buf = vec![];
+        body.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf, b"abcde");
+    }
+}
@@ -1,55 +1,126 @@
-use tokio::time::{timeout_at, Instant};
-use tide::Request;
+use async_std::{channel::{self, Receiver, Sender}, future::timeout, io::BufReader, task};
+use tide::{http::mime, Body, Request};
 use std::time::Duration;
 
-use crate::features::commands::{CommandData, CommandMessage};
+use crate::features::{
+    commands::{CommandMessage, ErrorCode},
+    frame::{Frame, FrameType, FLOW_CONTROL_WINDOW},
+    handshake::{Handshake, HandshakeResult, FEATURE_FLOW_CONTROL},
+};
+
+use body::FrameBody;
 
 use super::websocket_channel;
 
+mod body;
+
+const RECV_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+/// 已转发但 client 尚未读取的 frame 数上限, 写满后暂停转发, 进而暂停发送 WindowUpdate
+const BODY_BUFFER_FRAMES: usize = 4;
+
 pub fn binding(app: &mut tide::Server<()>) {
     app.at("/client").nest({
         let mut client = tide::new();
+        client.at("/handshake").post(handshake);
         client.at("/data").post(receive_data);
+        client.at("/cancel").post(cancel);
         client
     });
 }
 
+fn header_value(req: &Request<()>, name: &str) -> String {
+    if let Some(values) = req.header(name) {
+        values.get(0).unwrap().to_string()
+    } else { "".to_string() }
+}
+
+async fn handshake(mut req: Request<()>) -> tide::Result {
+    let server_key = header_value(&req, "X-Server-Key");
+    let client_key = header_value(&req, "X-Client-Key");
+
+    let mut res = tide::Response::new(401);
+    if server_key.is_empty() || client_key.is_empty() {
+        res.set_body("server or client key required");
+        return Ok(res);
+    }
+    let peer: Handshake = match req.body_json().await {
+        Ok(peer) => peer,
+        Err(e) => {
+            res.set_status(400);
+            res.set_body(format!("handshake parse failed, {e}"));
+            return Ok(res);
+        }
+    };
+    let result = match websocket_channel::protocol(&server_key).await {
+        Some(protocol) => match protocol.negotiate(&peer) {
+            Ok(agreed) => {
+                res.set_status(200);
+                HandshakeResult::from(&agreed)
+            },
+            Err(message) => {
+                res.set_status(426);
+                HandshakeResult::Rejected { message }
+            },
+        },
+        None => {
+            res.set_status(403);
+            HandshakeResult::Rejected { message: "share key may be off line".to_string() }
+        },
+    };
+    res.set_body(serde_json::to_string(&result).unwrap());
+    Ok(res)
+}
+
 async fn receive_data(mut req: Request<()>) -> tide::Result {
-    let server_key = if let Some(server_keys) = req.header("X-Server-Key") {
-         server_keys.get(0).unwrap().to_string()
-    } else { "".to_string() };
-    let client_key = if let Some(client_keys) = req.header("X-Client-Key") {
-         client_keys.get(0).unwrap().to_string()
-    } else { "".to_string() };
+    let server_key = header_value(&req, "X-Server-Key");
+    let client_key = header_value(&req, "X-Client-Key");
+    let request_id: u64 = header_value(&req, "X-Request-Id").parse().unwrap_or(0);
+    let version: u16 = header_value(&req, "X-Protocol-Version").parse().unwrap_or(0);
     
     let mut res = tide::Response::new(401);
     match (server_key.as_str(), client_key.as_str()) {
         ("", "") | ("", _) | (_, "") => res.set_body("server or client key required"),
+        _ if request_id == 0 => {
+            res.set_status(400);
+            res.set_body("request id required");
+        },
         keys => {
-            let ws_cmd = req.body_string().await.unwrap();
-            if let Some(_) = websocket_channel::get(keys.0).await {
-                websocket_channel::websocket_send_text(keys.0, keys.1, ws_cmd).await;
-                websocket_channel::proxy_open(keys.0, keys.1).await;
-                if let Err(_)  = timeout_at(
-                    Instant::now() + Duration::from_secs(60), 
-                    recv_loop(&mut res, keys.0, keys.1)
-                ).await {
+            let ws_cmd = req.body_bytes().await.unwrap();
+            let protocol = websocket_channel::protocol(keys.0).await;
+            if protocol.as_ref().is_some_and(|protocol| !protocol.supports(version)) {
+                res.set_status(426);
+                res.set_body(error_body(request_id, ErrorCode::UnsupportedVersion, format!("unsupported protocol version {version}, handshake required")));
+            } else if let Some(protocol) = protocol {
+                // note: 先打开 proxy 再发送命令，避免 server 响应早于 proxy 创建而被丢弃
+                if !websocket_channel::proxy_open(keys.0, keys.1, request_id).await {
+                    res.set_status(503);
+                    res.set_body(error_body(request_id, ErrorCode::Unavailable, "open proxy failed, too many requests or request id in use".to_string()));
+                    return Ok(res);
+                }
+                let frame = Frame::new(FrameType::Command, keys.1, request_id, ws_cmd);
+                if let Err(e) = websocket_channel::websocket_send(keys.0, frame).await.map_err(|e| e.to_string()) {
+                    websocket_channel::proxy_close(keys.0, keys.1, request_id).await;
                     res.set_status(502);
-                        let data = CommandMessage {
-                        version: 1,
-                        status: 403,
-                        data: CommandData::Error { message: "receving data from server time out".to_string()},
-                    };
-                    res.set_body(serde_json::to_string(&data).unwrap());    
+                    res.set_body(error_body(request_id, ErrorCode::Unavailable, format!("sending command to server failed, {e}")));
+                    return Ok(res);
+                }
+                match websocket_channel::proxy_receive(keys.0, keys.1, request_id).await {
+                    Some(receiver) => {
+                        let (sender, body) = channel::bounded(BODY_BUFFER_FRAMES);
+                        let flow_control = protocol.has_feature(FEATURE_FLOW_CONTROL);
+                        task::spawn(recv_loop(receiver, sender, keys.0.to_string(), keys.1.to_string(), request_id, flow_control));
+                        res.set_status(200);
+                        res.set_content_type(mime::BYTE_STREAM);
+                        res.set_body(Body::from_reader(BufReader::new(FrameBody::new(body)), None));
+                    },
+                    None => {
+                        res.set_status(403);
+                        res.set_body(error_body(request_id, ErrorCode::Unavailable, "open proxy failed".to_string()));
+                    },
                 }
             } else {
                 res.set_status(403);
-                let data = CommandMessage {
-                    version: 1,
-                    status: 403,
-                    data: CommandData::Error { message: "share key may be off line".to_string()},
-                };
-                res.set_body(serde_json::to_string(&data).unwrap());
+                res.set_body(error_body(request_id, ErrorCode::Unavailable, "share key may be off line".to_string()));
             }
         }
     }
@@ -57,38 +128,81 @@ async fn receive_data(mut req: Request<()>) -> tide::Result {
     Ok(res)
 }
 
-async fn recv_loop(res: &mut tide::Response, server_key: &str, client_key: &str) {
-    let mut body: Vec<char> = vec![];
-    if let Some(receiver) = websocket_channel::proxy_receive(server_key, client_key).await {
-        let postfix = [0u8; 4];
-        let mut status = 200;
-        loop {
-            match receiver.recv().await {
-                Ok(data) => {
-                    let chars: Vec<char> = data.iter().map(|b| *b as char).collect();
-                    if data.ends_with(&postfix) {
-                        body.extend(chars[..(chars.len() - postfix.len())].iter());
-                        break ;
-                    } else {
-                        body.extend(chars.iter());
-                    }
-                },
-                Err(_e) => {
-                    status = 500;
-                    let msg = "receive msg failed";
-                    body.extend(msg.chars());
-                    eprintln!("{}{}", msg, _e.to_string());
-                    break ;
-                }
+/// client 放弃请求时调用, 例如 Ctrl-C 或读取响应出错
+async fn cancel(req: Request<()>) -> tide::Result {
+    let server_key = header_value(&req, "X-Server-Key");
+    let client_key = header_value(&req, "X-Client-Key");
+    let request_id: u64 = header_value(&req, "X-Request-Id").parse().unwrap_or(0);
+
+    let mut res = tide::Response::new(401);
+    if server_key.is_empty() || client_key.is_empty() {
+        res.set_body("server or client key required");
+        return Ok(res);
+    }
+    if request_id == 0 {
+        res.set_status(400);
+        res.set_body("request id required");
+        return Ok(res);
+    }
+    match websocket_channel::cancel(&server_key, &client_key, request_id).await {
+        Ok(true) => {
+            res.set_status(200);
+            res.set_body("cancelled");
+        },
+        Ok(false) => {
+            res.set_status(404);
+            res.set_body("request not found or finished");
+        },
+        Err(e) => {
+            res.set_status(502);
+            res.set_body(format!("sending cancel to server failed, {e}"));
+        },
+    }
+    Ok(res)
+}
+
+fn error_body(request_id: u64, code: ErrorCode, message: String) -> Vec<u8> {
+    let data = CommandMessage::error(1, request_id, code, message);
+    let payload = serde_json::to_vec(&data).unwrap();
+    Frame::new(FrameType::Message, "", request_id, payload).end_of_stream().encode().unwrap()
+}
+
+/// 逐个转发 server 的 frame, 超过 `RECV_IDLE_TIMEOUT` 未收到 frame 视为超时;
+/// 超时或 client 断开时取消请求, server 随即停止处理, 不再发送无人接收的数据;
+/// flow_control 时 client 每取走半个窗口的 frame, 通知 server 可以继续发送
+async fn recv_loop(receiver: Receiver<Frame>, sender: Sender<Vec<u8>>, server_key: String, client_key: String, request_id: u64, flow_control: bool) {
+    let mut consumed = 0;
+    let cancelled = loop {
+        let (bin, end_of_stream, timed_out) = match timeout(RECV_IDLE_TIMEOUT, receiver.recv()).await {
+            Ok(Ok(frame)) => (frame.encode().unwrap(), frame.end_of_stream, false),
+            Ok(Err(e)) => {
+                let msg = "receive msg failed";
+                eprintln!("{}{}", msg, e);
+                (error_body(request_id, ErrorCode::Internal, msg.to_string()), true, false)
+            },
+            Err(_) => (error_body(request_id, ErrorCode::Unavailable, "receving data from server time out".to_string()), true, true),
+        };
+        if sender.send(bin).await.is_err() {
+            eprintln!("client {:?} closed request {}", client_key, request_id);
+            break true;
+        }
+        if end_of_stream {
+            break timed_out;
+        }
+        consumed += 1;
+        if flow_control && consumed >= FLOW_CONTROL_WINDOW / 2 {
+            let frame = Frame::window_update(&client_key, request_id, consumed);
+            if let Err(e) = websocket_channel::websocket_send(&server_key, frame).await {
+                eprintln!("window update of request {} for {:?} failed, err: {}", request_id, client_key, e);
             }
-            
+            consumed = 0;
+        }
+    };
+    if cancelled {
+        if let Err(e) = websocket_channel::cancel(&server_key, &client_key, request_id).await {
+            eprintln!("cancel request {} of {:?} failed, err: {}", request_id, client_key, e);
         }
-        websocket_channel::proxy_close(server_key, client_key).await;
-        res.set_status(status);
-        println!("body: {:?}", String::from_iter(body[..50].iter()));
-        
     } else {
-        res.set_status(403);
+        websocket_channel::proxy_close(&server_key, &client_key, request_id).await;
     }
-    res.set_body(String::from_iter(body));
-}
\ No newline at end of file
+}
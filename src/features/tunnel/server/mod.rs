@@ -1,6 +1,12 @@
-use async_std::{io::ReadExt as _, stream::StreamExt as _};
+use async_std::{future::timeout, io::ReadExt as _, stream::StreamExt as _};
 use tide_websockets::{Message, WebSocket, WebSocketConnection};
 use tide::Request;
+use std::time::Duration;
+
+use crate::features::{
+    frame::{Frame, FrameType},
+    handshake::{Handshake, HandshakeResult},
+};
 
 use super::websocket_channel;
 
@@ -9,7 +15,7 @@ pub fn binding(app: &mut tide::Server<()>) {
         let mut srv = tide::new();
         srv.at("/registe").post(registe_share_key);
         srv.at("/ws").with(WebSocket::new(srv_ws_handler))
-                    .get(|_| async move { Ok(format!("request as websocket")) });
+                    .get(|_| async move { Ok("request as websocket".to_string()) });
         srv
     });
 }
@@ -17,71 +23,99 @@ pub fn binding(app: &mut tide::Server<()>) {
 async fn registe_share_key(mut req: Request<()>) -> tide::Result {
     let _share_key = req.header("X-Share-Key").unwrap();
     let share_key = _share_key.get(0).unwrap().to_string();
-    match websocket_channel::get(&share_key).await {
-        Some(conn) => {
-            conn.send(Message::from("hello registe")).await.unwrap();
-        },
-        None => {},
+    if let Some(conn) = websocket_channel::get(&share_key).await {
+        conn.send(Message::from("hello registe")).await.unwrap();
     };
     let mut buf: [u8; 12] = [0; 12];
-    req.read(&mut buf).await.unwrap();
+    let _ = req.read(&mut buf).await.unwrap();
     Ok(format!("demo {share_key}").into())
 }
 
 async fn srv_ws_handler(req: Request<()>, mut stream: WebSocketConnection) -> tide::Result<()> {
     let mut share_key = "";
-    match req.header("X-Share-Key") {
-        Some(_share_key) => {
-            share_key = _share_key.get(0).unwrap().as_str();
-            websocket_channel::add(share_key, stream.clone()).await;
-        },
-        None => {}
+    if let Some(_share_key) = req.header("X-Share-Key") {
+        share_key = _share_key.get(0).unwrap().as_str();
     };
-    if share_key != "" {
-        println!("online: {}", share_key);
-        stream.send_string(format!("hi {}", share_key).into()).await.unwrap();
-        loop {
-            match stream.next().await {
-                Some(result) => {
-                    match result {
-                        Ok(message) => {
-                            match message {
-                                Message::Text(input) => {
-                                    let (client_key_size_str, next_data) = input.split_once(":").unwrap();
-                                    let client_key_size = client_key_size_str.to_string().parse::<usize>().unwrap();
-                                    let (client_key, next_data) = next_data.split_at(client_key_size);
-                                    println!("transfer text: {},{}:{}", share_key, client_key, &next_data);
-                                    websocket_channel::proxy_send(share_key, client_key, next_data.as_bytes().to_vec()).await.unwrap();
-                                },
-                                Message::Binary(input) => {
-                                    println!("raw_msg: {:?}", input);
-                                    let client_key_size = input[0] as usize;
-                                    let client_key_chars: Vec<char> = input[1..(client_key_size + 1)].iter().map(|c| *c as char).collect();
-                                    let client_key = String::from_iter(client_key_chars);
-                                    let msg = input[(client_key_size + 1)..].to_vec();
-                                    println!("transfer: {},{}:{}", share_key, client_key, String::from_utf8(msg.clone()).unwrap());
-                                    websocket_channel::proxy_send(&share_key, &client_key, msg).await.unwrap();
+    if !share_key.is_empty() {
+        let protocol = match server_handshake(&mut stream).await {
+            Ok(protocol) => protocol,
+            Err(e) => {
+                eprintln!("handshake with {} failed, {}", share_key, e);
+                return Ok(());
+            }
+        };
+        println!("online: {}, protocol: {:?}", share_key, protocol);
+        websocket_channel::add(share_key, stream.clone(), protocol).await;
+        stream.send_string(format!("hi {}", share_key)).await.unwrap();
+        while let Some(result) = stream.next().await {
+            match result {
+                Ok(message) => {
+                    match message {
+                        Message::Binary(input) => {
+                            let frame = match Frame::decode(&input) {
+                                Ok(frame) => frame,
+                                Err(e) => {
+                                    eprintln!("frame decode failed from {}, err: {}", share_key, e);
+                                    continue ;
                                 }
-                                Message::Close(_static) => {
-                                    println!("exit {}", &share_key);
-                                    break ;
+                            };
+                            if !matches!(frame.frame_type, FrameType::Message | FrameType::Data) {
+                                eprintln!("unexpected frame type from {}: {:?}", share_key, frame.frame_type);
+                                continue ;
+                            }
+                            let client_key = frame.client_key.clone();
+                            let request_id = frame.request_id;
+                            let transferred = match websocket_channel::proxy_send(share_key, &client_key, frame).await {
+                                Ok(()) => true,
+                                Err(e) => {
+                                    eprintln!("transfer to {},{} failed, err: {}", share_key, client_key, e);
+                                    false
                                 }
-                                _ => {}
+                            };
+                            if !transferred {
+                                // note: 丢弃 frame 后响应已不完整, 取消该请求
+                                let _ = websocket_channel::cancel(share_key, &client_key, request_id).await;
                             }
-                        },
-                        Err(e) => {
-                            eprintln!("message handler error, {}", e);
+                        }
+                        Message::Close(_static) => {
+                            println!("exit {}", share_key);
                             break ;
                         }
+                        _ => {}
                     }
                 },
-                None => break
+                Err(e) => {
+                    eprintln!("message handler error, {}", e);
+                    break ;
+                }
             }
         }
-        websocket_channel::del(&share_key).await;
+        websocket_channel::del(share_key).await;
     }
     else {
         stream.send_string("share key has be required".into()).await.unwrap();
     }
     Ok(())
 }
+
+async fn server_handshake(stream: &mut WebSocketConnection) -> Result<Handshake, String> {
+    let peer = match timeout(Duration::from_secs(10), stream.next()).await {
+        Ok(Some(Ok(Message::Binary(input)))) => match Frame::decode(&input) {
+            Ok(frame) if frame.frame_type == FrameType::Handshake => {
+                serde_json::from_slice::<Handshake>(&frame.payload).map_err(|e| e.to_string())
+            },
+            Ok(frame) => Err(format!("expect handshake frame, got {:?}", frame.frame_type)),
+            Err(e) => Err(e.to_string()),
+        },
+        Ok(_) => Err("expect handshake frame".to_string()),
+        Err(_) => Err("handshake time out".to_string()),
+    };
+    let result = peer.and_then(|peer| Handshake::local().negotiate(&peer));
+    let reply = match &result {
+        Ok(protocol) => HandshakeResult::from(protocol),
+        Err(message) => HandshakeResult::Rejected { message: message.clone() },
+    };
+    let frame = Frame::new(FrameType::Handshake, "", 0, serde_json::to_vec(&reply).unwrap());
+    stream.send_bytes(frame.encode().unwrap()).await.map_err(|e| e.to_string())?;
+    result
+}
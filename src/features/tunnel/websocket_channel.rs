@@ -1,77 +1,63 @@
 #![allow(dead_code)]
 
-use async_std::{sync::Mutex, channel::{self, Receiver, Sender}};
+use async_std::{sync::Mutex, channel::{self, Receiver, Sender, TrySendError}};
 use lazy_static::lazy_static;
-use std::collections::HashMap;
+use std::collections::{hash_map::Entry, HashMap};
 
 use tide_websockets::WebSocketConnection;
-use crate::common::CommomResult;
+use crate::{common::CommomResult, features::{frame::{Frame, FrameType, FLOW_CONTROL_WINDOW}, handshake::Handshake}};
 
-type ShareKey = String;
-type RequestSender = Sender<Vec<u8>>;
-type RequestReceiver = Receiver<Vec<u8>>;
+type ClientKey = String;
+type RequestId = u64;
+type RequestSender = Sender<Frame>;
+type RequestReceiver = Receiver<Frame>;
 type RequestChannel = (RequestSender, RequestReceiver);
 
+/// 每个进行中的请求独占一个 channel, 同一 client 可以同时有多个请求
+type ProxyKey = (ClientKey, RequestId);
+
+/// 每个 server 同时进行的请求数上限, 与请求 channel 的容量一起限制 tunnel 缓存的数据量
+pub const MAX_PROXY_PER_SERVER: usize = 256;
+
 struct WebSocketChannel {
     ws_conn: WebSocketConnection,
-    proxy: HashMap<ShareKey, RequestChannel>
-}
-
-pub enum WSChannelSendType {
-    Byte(Vec<u8>, Vec<u8>),
-    String(String, String)
+    protocol: Handshake,
+    proxy: HashMap<ProxyKey, RequestChannel>
 }
 
 impl WebSocketChannel {
-    pub fn proxy_receive(&self, client_key: &str) -> Option<&Receiver<Vec<u8>>> {
-        match self.proxy.get(client_key) {
-            None => None,
-            Some(proxy) => Some(&proxy.1)
-        }
+    pub fn proxy_receive(&self, client_key: &str, request_id: RequestId) -> Option<&RequestReceiver> {
+        self.proxy.get(&(client_key.to_string(), request_id)).map(|proxy| &proxy.1)
     }
 
-    pub async fn proxy_send(&self, client_key: &str, data: Vec<u8>) -> CommomResult<()> {
-
-        if let Some(proxy) = self.proxy.get(client_key) {
-            proxy.0.send(data).await?;
-        } else {
-            eprintln!("send msg failed {:?}", client_key);
-        }
-        Ok(())
+    pub fn proxy_sender(&self, client_key: &str, request_id: RequestId) -> Option<&RequestSender> {
+        self.proxy.get(&(client_key.to_string(), request_id)).map(|proxy| &proxy.0)
     }
 
-    pub async fn proxy_add(&mut self, client_key: &str) {
-        if !self.proxy.contains_key(client_key) {
-            eprintln!("open true");
-            self.proxy.insert(client_key.to_string(), channel::unbounded());
-        } else {
-            eprintln!("open failed");
+    /// server 遵守发送窗口时 channel 不会写满
+    pub async fn proxy_add(&mut self, client_key: &str, request_id: RequestId) -> bool {
+        if self.proxy.len() >= MAX_PROXY_PER_SERVER {
+            eprintln!("too many requests, drop request {} of {:?}", request_id, client_key);
+            return false;
         }
-    }
-
-    pub async fn proxy_del(&mut self, client_key: &str) {
-        if self.proxy.contains_key(client_key) {
-            self.proxy.remove(client_key);
+        match self.proxy.entry((client_key.to_string(), request_id)) {
+            Entry::Vacant(entry) => {
+                entry.insert(channel::bounded(FLOW_CONTROL_WINDOW as usize));
+                true
+            },
+            Entry::Occupied(_) => {
+                eprintln!("request {} of {:?} already opened", request_id, client_key);
+                false
+            },
         }
     }
 
-    pub async fn proxy_status(&self, client_key: &str) -> bool {
-        self.proxy.contains_key(client_key)
+    pub async fn proxy_del(&mut self, client_key: &str, request_id: RequestId) {
+        self.proxy.remove(&(client_key.to_string(), request_id));
     }
 
-    pub async fn websocket_send(&self, send_type: WSChannelSendType) {
-        match send_type {
-            WSChannelSendType::Byte(client_key, data) => {
-                let mut msg = vec![client_key.len() as u8];
-                msg.extend(client_key);
-                msg.extend(data);
-                self.ws_conn.send_bytes(msg).await.unwrap()
-            },
-            WSChannelSendType::String(client_key, s) => {
-                let msg = format!("{}:{}{}", client_key.len(), client_key, s);
-                self.ws_conn.send_string(msg).await.unwrap()
-            },
-        }
+    pub async fn proxy_status(&self, client_key: &str, request_id: RequestId) -> bool {
+        self.proxy.contains_key(&(client_key.to_string(), request_id))
     }
 }
 
@@ -88,8 +74,8 @@ impl WebSocketChannelPool {
 }
 
 impl WebSocketChannelPool {
-    pub fn add(&mut self, key: &str, conn: WebSocketConnection) {
-        self.inner.insert(key.to_string(), WebSocketChannel{ws_conn: conn, proxy: HashMap::new()});
+    pub fn add(&mut self, key: &str, conn: WebSocketConnection, protocol: Handshake) {
+        self.inner.insert(key.to_string(), WebSocketChannel{ws_conn: conn, protocol, proxy: HashMap::new()});
     }
 
     pub fn get(&self, key: &str) -> Option<&WebSocketChannel> {
@@ -104,41 +90,39 @@ impl WebSocketChannelPool {
         self.inner.remove(key);
     }
 
-    pub fn proxy_receiver(&self, server_key: &str, client_key: &str) -> Option<&Receiver<Vec<u8>>> {
+    pub fn proxy_receiver(&self, server_key: &str, client_key: &str, request_id: RequestId) -> Option<&RequestReceiver> {
         match self.get(server_key) {
-            Some(ws_channel) => ws_channel.proxy_receive(client_key),
+            Some(ws_channel) => ws_channel.proxy_receive(client_key, request_id),
             None => None,
         }
     }
 
-    pub async fn proxy_send(&mut self, server_key: &str, client_key: &str, data: Vec<u8>) -> CommomResult<()> {
+    pub fn proxy_sender(&self, server_key: &str, client_key: &str, request_id: RequestId) -> Option<&RequestSender> {
         match self.get(server_key) {
-            Some(ws_channel) => ws_channel.proxy_send(client_key, data).await,
-            None => Ok(()),
+            Some(ws_channel) => ws_channel.proxy_sender(client_key, request_id),
+            None => None,
         }
     }
 
-    pub async fn proxy_open(&mut self, server_key: &str, client_key: &str) {
+    pub async fn proxy_open(&mut self, server_key: &str, client_key: &str, request_id: RequestId) -> bool {
         match self.get_mut(server_key) {
-            Some(ws_channel) => {
-                println!("oh ....");
-                ws_channel.proxy_add(client_key).await;
-            },
+            Some(ws_channel) => ws_channel.proxy_add(client_key, request_id).await,
             None => {
                 eprintln!("open proxy failed");
+                false
             },
         }
     }
 
-    pub async fn proxy_close(&mut self, server_key: &str, client_key: &str) {
-        if self.proxy_status(server_key, client_key).await {
-            self.get_mut(server_key).unwrap().proxy_del(client_key);
+    pub async fn proxy_close(&mut self, server_key: &str, client_key: &str, request_id: RequestId) {
+        if self.proxy_status(server_key, client_key, request_id).await {
+            self.get_mut(server_key).unwrap().proxy_del(client_key, request_id).await;
         }
     }
 
-    pub async fn proxy_status(&self, server_key: &str, client_key: &str) -> bool {
+    pub async fn proxy_status(&self, server_key: &str, client_key: &str, request_id: RequestId) -> bool {
         match self.get(server_key) {
-            Some(ws_channel) => ws_channel.proxy_status(client_key).await,
+            Some(ws_channel) => ws_channel.proxy_status(client_key, request_id).await,
             None => false
         }
     }
@@ -148,48 +132,69 @@ lazy_static! {
     static ref WS_CHANNEL: Mutex<Box<WebSocketChannelPool>> = Mutex::new(Box::new(WebSocketChannelPool::new()));
 }
 
-pub async fn add(server_key: &str, conn: WebSocketConnection) {
-    WS_CHANNEL.lock().await.add(server_key, conn);
+pub async fn add(server_key: &str, conn: WebSocketConnection, protocol: Handshake) {
+    WS_CHANNEL.lock().await.add(server_key, conn, protocol);
 }
 
 pub async fn get(server_key: &str) -> Option<WebSocketConnection> {
-    match WS_CHANNEL.lock().await.get(server_key) {
-        Some(ws_chann) => Some(ws_chann.ws_conn.clone()),
-        None => None,
-    }
+    WS_CHANNEL.lock().await.get(server_key).map(|ws_chann| ws_chann.ws_conn.clone())
+}
+
+pub async fn protocol(server_key: &str) -> Option<Handshake> {
+    WS_CHANNEL.lock().await.get(server_key).map(|ws_chann| ws_chann.protocol.clone())
 }
 
 pub async fn del(server_key: &str) {
     WS_CHANNEL.lock().await.del(server_key);
 }
 
-pub async fn proxy_open(server_key: &str, client_key: &str) {
-    WS_CHANNEL.lock().await.proxy_open(server_key, client_key).await;
+pub async fn proxy_open(server_key: &str, client_key: &str, request_id: RequestId) -> bool {
+    WS_CHANNEL.lock().await.proxy_open(server_key, client_key, request_id).await
 }
 
-pub async fn proxy_close(server_key: &str, client_key: &str) {
-    WS_CHANNEL.lock().await.proxy_close(server_key, client_key).await;
+pub async fn proxy_close(server_key: &str, client_key: &str, request_id: RequestId) {
+    WS_CHANNEL.lock().await.proxy_close(server_key, client_key, request_id).await;
 }
 
-pub async fn proxy_receive<'a, 'b>(server_key: &'a str, client_key: &'a str) -> Option<Receiver<Vec<u8>>> {
-    match WS_CHANNEL.lock().await.proxy_receiver(server_key, client_key) {
-        None => None,
-        Some(r) => Some(r.clone())
-    }
+pub async fn proxy_receive(server_key: &str, client_key: &str, request_id: RequestId) -> Option<RequestReceiver> {
+    WS_CHANNEL.lock().await.proxy_receiver(server_key, client_key, request_id).cloned()
 }
 
-pub async fn proxy_send(server_key: &str, client_key: &str, message: Vec<u8>) -> CommomResult<()> {
-    WS_CHANNEL.lock().await.proxy_send(server_key, client_key, message).await
+/// 转发 server 的 frame 到对应请求的 channel, 不等待, 避免一个读取缓慢的 client 阻塞同一 server 的其他请求;
+/// channel 已满说明 server 未遵守发送窗口, 返回错误
+pub async fn proxy_send(server_key: &str, client_key: &str, frame: Frame) -> CommomResult<()> {
+    let request_id = frame.request_id;
+    let sender = WS_CHANNEL.lock().await.proxy_sender(server_key, client_key, request_id).cloned();
+    match sender.map(|sender| sender.try_send(frame)) {
+        Some(Ok(())) => {},
+        Some(Err(TrySendError::Full(_))) => return Err(format!("request {request_id} buffer overflow").into()),
+        Some(Err(TrySendError::Closed(_))) | None => {
+            eprintln!("drop frame of request {} for {:?}, request closed", request_id, client_key);
+        },
+    }
+    Ok(())
 }
 
-pub async fn websocket_send_bytes(server_key: &str, client_key: &str, message: Vec<u8>) {
-    WS_CHANNEL.lock().await
-        .get(server_key).unwrap()
-        .websocket_send(WSChannelSendType::Byte(client_key.as_bytes().to_vec(), message)).await;
+pub async fn websocket_send(server_key: &str, frame: Frame) -> CommomResult<()> {
+    match get(server_key).await {
+        Some(ws_conn) => {
+            ws_conn.send_bytes(frame.encode()?).await?;
+            Ok(())
+        },
+        None => Err(format!("share key {server_key} off line").into()),
+    }
 }
 
-pub async fn websocket_send_text(server_key: &str, client_key: &str, message: String) {
-    WS_CHANNEL.lock().await
-        .get(server_key).unwrap()
-        .websocket_send(WSChannelSendType::String(client_key.to_string(), message)).await;
+/// 取消进行中的请求: 立即释放请求的 channel, 并通知 server 停止处理; 请求已结束时返回 false
+pub async fn cancel(server_key: &str, client_key: &str, request_id: RequestId) -> CommomResult<bool> {
+    let opened = {
+        let mut pool = WS_CHANNEL.lock().await;
+        let opened = pool.proxy_status(server_key, client_key, request_id).await;
+        pool.proxy_close(server_key, client_key, request_id).await;
+        opened
+    };
+    if opened {
+        websocket_send(server_key, Frame::new(FrameType::Cancel, client_key, request_id, vec![]).end_of_stream()).await?;
+    }
+    Ok(opened)
 }
@@ -0,0 +1,107 @@
+use std::{ffi::CString, io, os::unix::ffi::OsStrExt, path::Path, ptr};
+
+use super::commands::Xattr;
+
+/// 下载时只恢复 user 命名空间的属性; security、trusted、system 由本机的策略决定, 不能来自 server
+const RESTORABLE_PREFIX: &str = "user.";
+
+pub fn is_restorable(name: &str) -> bool {
+    name.starts_with(RESTORABLE_PREFIX)
+}
+
+fn c_string(bytes: &[u8]) -> io::Result<CString> {
+    CString::new(bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+}
+
+/// 先以空缓冲区查询长度再读取; 两次调用之间属性变大时重试
+fn read_with_buffer(read: impl Fn(*mut u8, usize) -> isize) -> io::Result<Vec<u8>> {
+    loop {
+        let size = read(ptr::null_mut(), 0);
+        if size < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut buffer = vec![0u8; size as usize];
+        let size = read(buffer.as_mut_ptr(), buffer.len());
+        if size < 0 {
+            let e = io::Error::last_os_error();
+            if e.raw_os_error() == Some(libc::ERANGE) {
+                continue ;
+            }
+            return Err(e);
+        }
+        buffer.truncate(size as usize);
+        return Ok(buffer);
+    }
+}
+
+/// 读取路径(跟随符号链接)的所有扩展属性; 文件系统不支持时返回空, 名称不是 UTF-8 或读取期间被删除的属性跳过
+pub fn read_xattrs(path: &Path) -> io::Result<Vec<Xattr>> {
+    let c_path = c_string(path.as_os_str().as_bytes())?;
+    let names = match read_with_buffer(|buffer, size| unsafe {
+        libc::listxattr(c_path.as_ptr(), buffer as *mut libc::c_char, size)
+    }) {
+        Ok(names) => names,
+        Err(e) if e.raw_os_error() == Some(libc::ENOTSUP) => return Ok(vec![]),
+        Err(e) => return Err(e),
+    };
+    let mut xattrs = vec![];
+    for name in names.split(|b| *b == 0).filter(|name| !name.is_empty()) {
+        let Ok(utf8_name) = std::str::from_utf8(name) else {
+            continue ;
+        };
+        let c_name = c_string(name)?;
+        match read_with_buffer(|buffer, size| unsafe {
+            libc::getxattr(c_path.as_ptr(), c_name.as_ptr(), buffer as *mut libc::c_void, size)
+        }) {
+            Ok(value) => xattrs.push(Xattr { name: utf8_name.to_string(), value }),
+            Err(e) if e.raw_os_error() == Some(libc::ENODATA) => {},
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(xattrs)
+}
+
+/// 设置扩展属性, 已存在时覆盖
+pub fn write_xattr(path: &Path, xattr: &Xattr) -> io::Result<()> {
+    let c_path = c_string(path.as_os_str().as_bytes())?;
+    let c_name = c_string(xattr.name.as_bytes())?;
+    let result = unsafe {
+        libc::setxattr(c_path.as_ptr(), c_name.as_ptr(), xattr.value.as_ptr() as *const libc::c_void, xattr.value.len(), 0)
+    };
+    if result < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test_xattr {
+    use std::fs;
+
+    use crate::{common::test_util::TempDir, features::commands::Xattr};
+
+    use super::{is_restorable, read_xattrs, write_xattr};
+
+    #[test]
+    fn test_xattrs() {
+        let dir = TempDir::new("xattr");
+        let file = dir.join("a");
+        fs::write(&file, "x").unwrap();
+        let xattr = Xattr { name: "user.ft.comment".to_string(), value: b"hello".to_vec() };
+        // note: 部分文件系统(例如 tmpfs 的旧内核)不支持 user 命名空间
+        if write_xattr(&file, &xattr).is_ok() {
+            assert!(read_xattrs(&file).unwrap().contains(&xattr));
+        }
+        assert!(read_xattrs(&file.with_extension("missing")).is_err());
+    }
+
+    #[test]
+    fn test_is_restorable() {
+        assert!(is_restorable("user.ft.comment"));
+        assert!(!is_restorable("security.selinux"));
+        assert!(!is_restorable("security.capability"));
+        assert!(!is_restorable("trusted.overlay.opaque"));
+        assert!(!is_restorable("system.posix_acl_access"));
+        assert!(!is_restorable("username"));
+    }
+}
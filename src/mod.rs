@@ -1,2 +1,6 @@
+// note: 文件系统相关功能直接使用 Linux 的系统调用, 不支持其他平台
+#[cfg(not(target_os = "linux"))]
+compile_error!("file-tunnel only supports linux");
+
 pub mod common;
 pub mod features;